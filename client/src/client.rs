@@ -0,0 +1,75 @@
+use crate::{relay::ConnectionPool, status::ClientStatus};
+use parking_lot::Mutex;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::oneshot::Sender,
+    task::{JoinError, JoinHandle},
+    time,
+};
+
+/// The running relay and local socks5 server
+pub struct Client {
+    relay: JoinHandle<()>,
+    relay_shutdown: Sender<()>,
+    socks5: JoinHandle<()>,
+    socks5_shutdown: Sender<()>,
+    local_addr: SocketAddr,
+    connection_pool: Arc<Mutex<ConnectionPool>>,
+}
+
+impl Client {
+    pub fn new(
+        relay: JoinHandle<()>,
+        relay_shutdown: Sender<()>,
+        socks5: JoinHandle<()>,
+        socks5_shutdown: Sender<()>,
+        local_addr: SocketAddr,
+        connection_pool: Arc<Mutex<ConnectionPool>>,
+    ) -> Self {
+        Self {
+            relay,
+            relay_shutdown,
+            socks5,
+            socks5_shutdown,
+            local_addr,
+            connection_pool,
+        }
+    }
+
+    /// The bound address of the local socks5 server
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// A snapshot of the connections to the server, including their RTT and the number of requests being relayed over each
+    pub fn status(&self) -> ClientStatus {
+        self.connection_pool.lock().status()
+    }
+
+    /// Resolves when either the relay or the local socks5 server stops by itself
+    pub async fn wait(&mut self) -> Result<(), JoinError> {
+        tokio::select! {
+            res = &mut self.relay => res,
+            res = &mut self.socks5 => res,
+        }
+    }
+
+    /// Stops the local socks5 server, then dissociates all UDP sessions and closes the connections to the server. Both are aborted if the teardown does not finish within `timeout`, in which case `false` is returned
+    pub async fn shutdown(mut self, timeout: Duration) -> bool {
+        let teardown = async {
+            let _ = self.socks5_shutdown.send(());
+            let _ = (&mut self.socks5).await;
+
+            let _ = self.relay_shutdown.send(());
+            let _ = (&mut self.relay).await;
+        };
+
+        if time::timeout(timeout, teardown).await.is_ok() {
+            true
+        } else {
+            self.socks5.abort();
+            self.relay.abort();
+            false
+        }
+    }
+}
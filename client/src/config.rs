@@ -1,41 +1,68 @@
 use crate::{
     certificate,
-    relay::{ServerAddr, UdpMode},
-    socks5::Authentication as Socks5Authentication,
+    relay::{Address as RelayAddress, FailoverPolicy, InboundHeartbeat, ServerAddr, UdpMode},
+    socks5::{
+        Authentication as Socks5Authentication, Credential as Socks5Credential, DestinationKey,
+    },
 };
 use getopts::{Fail, Options};
 use log::{LevelFilter, ParseLevelError};
 use quinn::{
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
-    ClientConfig, IdleTimeout, VarInt,
+    ClientConfig, EndpointConfig, IdleTimeout, VarInt,
 };
+use quinn_proto::RandomConnectionIdGenerator;
 use rustls::{version::TLS13, Certificate, ClientConfig as RustlsClientConfig, RootCertStore};
 use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as JsonError;
 use std::{
+    collections::HashMap,
     env::ArgsOs,
     fmt::Display,
     fs::File,
     io::Error as IoError,
     net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr},
-    num::ParseIntError,
+    num::{NonZeroUsize, ParseIntError},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
+use tuic_protocol::Address;
 use webpki::Error as WebpkiError;
 
 pub struct Config {
     pub client_config: ClientConfig,
-    pub server_addr: ServerAddr,
+    pub endpoint_config: EndpointConfig,
+    pub server_addrs: Vec<ServerAddr>,
+    pub failover_policy: FailoverPolicy,
     pub token_digest: [u8; 32],
     pub udp_mode: UdpMode,
     pub heartbeat_interval: u64,
+    pub heartbeat_jitter: u64,
+    pub inbound_heartbeat: InboundHeartbeat,
     pub ipv6_endpoint: bool,
     pub reduce_rtt: bool,
+    pub optimistic_connect: bool,
+    pub fail_fast_threshold: Option<Duration>,
+    pub cache_server_address: bool,
+    pub server_address_refresh_interval: Option<Duration>,
+    pub authentication_padding: Option<usize>,
+    pub self_test: Option<RelayAddress>,
+    pub self_test_timeout: Duration,
     pub local_addr: SocketAddr,
+    pub local_worker_threads: Option<NonZeroUsize>,
+    pub local_disable_udp: bool,
+    pub local_keep_half_closed_associations: bool,
+    pub local_disable_tcp_nodelay: bool,
+    pub local_max_connections_per_destination: Option<usize>,
+    pub local_destination_limit_key: DestinationKey,
+    pub local_circuit_breaker_threshold: Option<usize>,
+    pub local_circuit_breaker_cooldown: Duration,
     pub socks5_authentication: Socks5Authentication,
     pub max_udp_packet_size: usize,
+    pub tcp_chunk_size: usize,
+    pub shutdown_timeout: Duration,
     pub log_level: LevelFilter,
 }
 
@@ -100,6 +127,24 @@ impl Config {
             config
         };
 
+        let endpoint_config = {
+            let cid_len = raw.relay.connection_id_length;
+
+            if cid_len > 20 {
+                return Err(ConfigError::InvalidConnectionIdLength);
+            }
+
+            let mut cid_generator = RandomConnectionIdGenerator::new(cid_len);
+
+            if let Some(lifetime) = raw.relay.connection_id_lifetime {
+                cid_generator.set_lifetime(Duration::from_millis(lifetime));
+            }
+
+            let mut config = EndpointConfig::default();
+            config.cid_generator(move || Box::new(cid_generator));
+            config
+        };
+
         let server_addr = {
             let name = raw.relay.server.unwrap();
             let port = raw.relay.port.unwrap();
@@ -117,37 +162,133 @@ impl Config {
             }
         };
 
+        let mut server_addrs = vec![server_addr];
+
+        for addr in raw.relay.fallback_servers {
+            server_addrs.push(match addr.parse()? {
+                RelayAddress::DomainAddress(hostname, server_port) => ServerAddr::HostnameAddr {
+                    hostname,
+                    server_port,
+                },
+                RelayAddress::SocketAddress(server_addr) => ServerAddr::SocketAddr {
+                    server_addr,
+                    server_name: server_addr.ip().to_string(),
+                },
+            });
+        }
+
+        let failover_policy = raw.relay.failover_policy;
+
         let token_digest = *blake3::hash(&raw.relay.token.unwrap().into_bytes()).as_bytes();
         let udp_mode = raw.relay.udp_mode;
         let heartbeat_interval = raw.relay.heartbeat_interval;
+        let heartbeat_jitter = raw.relay.heartbeat_jitter;
+        let inbound_heartbeat = raw.relay.inbound_heartbeat;
         let ipv6_endpoint = raw.relay.ipv6_endpoint;
         let reduce_rtt = raw.relay.reduce_rtt;
+        let optimistic_connect = raw.relay.optimistic_connect;
+        let fail_fast_threshold = raw.relay.fail_fast_threshold.map(Duration::from_millis);
+        let cache_server_address = raw.relay.cache_server_address;
+        let server_address_refresh_interval = raw
+            .relay
+            .server_address_refresh_interval
+            .map(Duration::from_millis);
+        let authentication_padding = raw.relay.authentication_padding;
+        let self_test = raw.relay.self_test.map(|addr| addr.parse()).transpose()?;
+        let self_test_timeout = Duration::from_millis(raw.relay.self_test_timeout);
+
+        let local_addr = match raw.local.addr {
+            Some(addr) => addr,
+            None => SocketAddr::from((raw.local.ip, raw.local.port.unwrap())),
+        };
+        let local_worker_threads = raw.local.worker_threads;
+        let local_disable_udp = raw.local.disable_udp;
+        let local_keep_half_closed_associations = raw.local.keep_half_closed_associations;
+        let local_disable_tcp_nodelay = raw.local.disable_tcp_nodelay;
+        let local_max_connections_per_destination = raw.local.max_connections_per_destination;
+        let local_destination_limit_key = raw.local.destination_limit_key;
+        let local_circuit_breaker_threshold =
+            raw.local.circuit_breaker_threshold.map(NonZeroUsize::get);
+        let local_circuit_breaker_cooldown =
+            Duration::from_millis(raw.local.circuit_breaker_cooldown);
+
+        let socks5_authentication = {
+            let mut credentials = HashMap::new();
+
+            match (raw.local.username, raw.local.password) {
+                (None, None) => {}
+                (Some(username), Some(password)) => {
+                    credentials.insert(
+                        username.into_bytes(),
+                        Socks5Credential {
+                            password: password.into_bytes(),
+                            token_digest: None,
+                        },
+                    );
+                }
+                _ => return Err(ConfigError::LocalAuthentication),
+            }
 
-        let local_addr = SocketAddr::from((raw.local.ip, raw.local.port.unwrap()));
+            for user in raw.local.users {
+                credentials.insert(
+                    user.username.into_bytes(),
+                    Socks5Credential {
+                        password: user.password.into_bytes(),
+                        token_digest: Some(*blake3::hash(&user.token.into_bytes()).as_bytes()),
+                    },
+                );
+            }
 
-        let socks5_authentication = match (raw.local.username, raw.local.password) {
-            (None, None) => Socks5Authentication::None,
-            (Some(username), Some(password)) => Socks5Authentication::Password {
-                username: username.into_bytes(),
-                password: password.into_bytes(),
-            },
-            _ => return Err(ConfigError::LocalAuthentication),
+            if credentials.is_empty() {
+                Socks5Authentication::None
+            } else {
+                Socks5Authentication::Password(credentials)
+            }
         };
 
         let max_udp_packet_size = raw.max_udp_packet_size;
+
+        let tcp_chunk_size = if raw.tcp_chunk_size > 0 {
+            raw.tcp_chunk_size
+        } else {
+            return Err(ConfigError::InvalidTcpChunkSize);
+        };
+
+        let shutdown_timeout = Duration::from_millis(raw.shutdown_timeout);
         let log_level = raw.log_level;
 
         Ok(Self {
             client_config,
-            server_addr,
+            endpoint_config,
+            server_addrs,
+            failover_policy,
             token_digest,
             udp_mode,
             heartbeat_interval,
+            heartbeat_jitter,
+            inbound_heartbeat,
             ipv6_endpoint,
             reduce_rtt,
+            optimistic_connect,
+            fail_fast_threshold,
+            cache_server_address,
+            server_address_refresh_interval,
+            authentication_padding,
+            self_test,
+            self_test_timeout,
             local_addr,
+            local_worker_threads,
+            local_disable_udp,
+            local_keep_half_closed_associations,
+            local_disable_tcp_nodelay,
+            local_max_connections_per_destination,
+            local_destination_limit_key,
+            local_circuit_breaker_threshold,
+            local_circuit_breaker_cooldown,
             socks5_authentication,
             max_udp_packet_size,
+            tcp_chunk_size,
+            shutdown_timeout,
             log_level,
         })
     }
@@ -162,6 +303,12 @@ struct RawConfig {
     #[serde(default = "default::max_udp_packet_size")]
     max_udp_packet_size: usize,
 
+    #[serde(default = "default::tcp_chunk_size")]
+    tcp_chunk_size: usize,
+
+    #[serde(default = "default::shutdown_timeout")]
+    shutdown_timeout: u64,
+
     #[serde(default = "default::log_level")]
     log_level: LevelFilter,
 }
@@ -193,6 +340,15 @@ struct RawRelayConfig {
     #[serde(default = "default::heartbeat_interval")]
     heartbeat_interval: u64,
 
+    #[serde(default = "default::heartbeat_jitter")]
+    heartbeat_jitter: u64,
+
+    #[serde(
+        default = "default::inbound_heartbeat",
+        deserialize_with = "deserialize_from_str"
+    )]
+    inbound_heartbeat: InboundHeartbeat,
+
     #[serde(default = "default::alpn")]
     alpn: Vec<String>,
 
@@ -204,18 +360,92 @@ struct RawRelayConfig {
 
     #[serde(default = "default::reduce_rtt")]
     reduce_rtt: bool,
+
+    #[serde(default = "default::optimistic_connect")]
+    optimistic_connect: bool,
+
+    #[serde(default = "default::fail_fast_threshold")]
+    fail_fast_threshold: Option<u64>,
+
+    #[serde(default = "default::cache_server_address")]
+    cache_server_address: bool,
+
+    #[serde(default = "default::server_address_refresh_interval")]
+    server_address_refresh_interval: Option<u64>,
+
+    #[serde(default = "default::authentication_padding")]
+    authentication_padding: Option<usize>,
+
+    #[serde(default = "default::connection_id_length")]
+    connection_id_length: usize,
+
+    #[serde(default = "default::connection_id_lifetime")]
+    connection_id_lifetime: Option<u64>,
+
+    self_test: Option<String>,
+
+    #[serde(default = "default::self_test_timeout")]
+    self_test_timeout: u64,
+
+    #[serde(default = "default::fallback_servers")]
+    fallback_servers: Vec<String>,
+
+    #[serde(
+        default = "default::failover_policy",
+        deserialize_with = "deserialize_from_str"
+    )]
+    failover_policy: FailoverPolicy,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct RawLocalConfig {
     port: Option<u16>,
+    addr: Option<SocketAddr>,
 
     #[serde(default = "default::local_ip")]
     ip: IpAddr,
 
     username: Option<String>,
     password: Option<String>,
+
+    #[serde(default = "default::local_users")]
+    users: Vec<RawLocalUser>,
+
+    #[serde(default = "default::local_worker_threads")]
+    worker_threads: Option<NonZeroUsize>,
+
+    #[serde(default = "default::local_disable_udp")]
+    disable_udp: bool,
+
+    #[serde(default = "default::local_keep_half_closed_associations")]
+    keep_half_closed_associations: bool,
+
+    #[serde(default = "default::local_disable_tcp_nodelay")]
+    disable_tcp_nodelay: bool,
+
+    #[serde(default = "default::local_max_connections_per_destination")]
+    max_connections_per_destination: Option<usize>,
+
+    #[serde(
+        default = "default::local_destination_limit_key",
+        deserialize_with = "deserialize_from_str"
+    )]
+    destination_limit_key: DestinationKey,
+
+    #[serde(default = "default::local_circuit_breaker_threshold")]
+    circuit_breaker_threshold: Option<NonZeroUsize>,
+
+    #[serde(default = "default::local_circuit_breaker_cooldown")]
+    circuit_breaker_cooldown: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLocalUser {
+    username: String,
+    password: String,
+    token: String,
 }
 
 impl Default for RawConfig {
@@ -224,6 +454,8 @@ impl Default for RawConfig {
             relay: RawRelayConfig::default(),
             local: RawLocalConfig::default(),
             max_udp_packet_size: default::max_udp_packet_size(),
+            tcp_chunk_size: default::tcp_chunk_size(),
+            shutdown_timeout: default::shutdown_timeout(),
             log_level: default::log_level(),
         }
     }
@@ -241,10 +473,23 @@ impl Default for RawRelayConfig {
             congestion_controller: default::congestion_controller(),
             max_idle_time: default::max_idle_time(),
             heartbeat_interval: default::heartbeat_interval(),
+            heartbeat_jitter: default::heartbeat_jitter(),
+            inbound_heartbeat: default::inbound_heartbeat(),
             alpn: default::alpn(),
             disable_sni: default::disable_sni(),
             ipv6_endpoint: default::ipv6_endpoint(),
             reduce_rtt: default::reduce_rtt(),
+            optimistic_connect: default::optimistic_connect(),
+            fail_fast_threshold: default::fail_fast_threshold(),
+            cache_server_address: default::cache_server_address(),
+            server_address_refresh_interval: default::server_address_refresh_interval(),
+            authentication_padding: default::authentication_padding(),
+            connection_id_length: default::connection_id_length(),
+            connection_id_lifetime: default::connection_id_lifetime(),
+            self_test: None,
+            self_test_timeout: default::self_test_timeout(),
+            fallback_servers: default::fallback_servers(),
+            failover_policy: default::failover_policy(),
         }
     }
 }
@@ -253,9 +498,19 @@ impl Default for RawLocalConfig {
     fn default() -> Self {
         Self {
             port: None,
+            addr: None,
             ip: default::local_ip(),
             username: None,
             password: None,
+            users: default::local_users(),
+            worker_threads: default::local_worker_threads(),
+            disable_udp: default::local_disable_udp(),
+            keep_half_closed_associations: default::local_keep_half_closed_associations(),
+            disable_tcp_nodelay: default::local_disable_tcp_nodelay(),
+            max_connections_per_destination: default::local_max_connections_per_destination(),
+            destination_limit_key: default::local_destination_limit_key(),
+            circuit_breaker_threshold: default::local_circuit_breaker_threshold(),
+            circuit_breaker_cooldown: default::local_circuit_breaker_cooldown(),
         }
     }
 }
@@ -294,6 +549,20 @@ impl RawConfig {
             "SERVER_IP",
         );
 
+        opts.optmulti(
+            "",
+            "fallback-server",
+            "Add a server to fail over to when the connection to the previous one cannot be established, in the form of 'HOST:PORT'. The server must accept the same certificate and token. This option can be used multiple times to add multiple servers, which are tried after the server set in option 'server' in the given order",
+            "FALLBACK_SERVER",
+        );
+
+        opts.optopt(
+            "",
+            "failover-policy",
+            r#"Set the order in which servers are tried when connecting. Available: "ordered", "round-robin". "ordered" always prefers the earliest server, "round-robin" starts from the server after the one last connected to. Servers that failed recently are tried last in both cases. When none can be connected, the next round over them starts after a delay that doubles from 500 ms up to 30 s. Default: "ordered""#,
+            "FAILOVER_POLICY",
+        );
+
         opts.optopt(
             "",
             "certificate",
@@ -329,6 +598,20 @@ impl RawConfig {
             "HEARTBEAT_INTERVAL",
         );
 
+        opts.optopt(
+            "",
+            "heartbeat-jitter",
+            "Set the maximum random deviation applied to each heartbeat interval, in milliseconds, so that clients sharing the same interval do not send heartbeats in sync. Default: 0",
+            "HEARTBEAT_JITTER",
+        );
+
+        opts.optopt(
+            "",
+            "inbound-heartbeat",
+            r#"Set how heartbeats sent by the server are handled. Available: "ignore", "respond", "reset-timer". "respond" replies with a heartbeat, "reset-timer" postpones the next heartbeat of the client, since the connection has just been active. Default: "ignore""#,
+            "INBOUND_HEARTBEAT",
+        );
+
         opts.optopt(
             "",
             "alpn",
@@ -350,6 +633,67 @@ impl RawConfig {
 
         opts.optflag("", "reduce-rtt", "Enable 0-RTT QUIC handshake");
 
+        opts.optflag(
+            "",
+            "optimistic-connect",
+            "Relay TCP data before the server confirms the connection to the target. A failed connection then closes the local connection instead of replying with an error",
+        );
+
+        opts.optopt(
+            "",
+            "fail-fast-threshold",
+            "Set the maximum time to wait for the connection to the server, in milliseconds. Once a connection attempt exceeds it, requests are refused immediately while the attempt keeps running, until the connection is established. If not set, requests will wait until the connection is established",
+            "FAIL_FAST_THRESHOLD",
+        );
+
+        opts.optflag(
+            "",
+            "cache-server-address",
+            "Resolve the hostname of the server once and reuse the resolved addresses for all connections. The hostname is resolved again after 3 failed connection attempts in a row",
+        );
+
+        opts.optopt(
+            "",
+            "server-address-refresh-interval",
+            "Set the time after which the cached addresses of the server are resolved again for `--cache-server-address`, in milliseconds. If not set, they are kept until connecting to them fails",
+            "SERVER_ADDRESS_REFRESH_INTERVAL",
+        );
+
+        opts.optopt(
+            "",
+            "authentication-padding",
+            "Pad the stream carrying the authentication with random bytes to this total length, in bytes, so that it does not reveal the fixed size of the command. The server only reads the command and discards the padding, so it needs no support for this. If not set, the stream carries only the command",
+            "AUTHENTICATION_PADDING",
+        );
+
+        opts.optopt(
+            "",
+            "connection-id-length",
+            "Set the length of the connection IDs issued by the client, in bytes (0 - 20). Default: 8",
+            "CONNECTION_ID_LENGTH",
+        );
+
+        opts.optopt(
+            "",
+            "connection-id-lifetime",
+            "Set the lifetime of the connection IDs issued by the client, in milliseconds. Connection IDs are retired and replaced by new ones after it. If not set, connection IDs will not be rotated",
+            "CONNECTION_ID_LIFETIME",
+        );
+
+        opts.optopt(
+            "",
+            "self-test",
+            "Relay a TCP connection to the given target through the server at startup, in the form of 'HOST:PORT', and exit if it fails. The local socks5 server will only be started after the self-test passes",
+            "SELF_TEST_TARGET",
+        );
+
+        opts.optopt(
+            "",
+            "self-test-timeout",
+            "Set the timeout of the startup self-test, including establishing the connection to the server, in milliseconds. Default: 5000",
+            "SELF_TEST_TIMEOUT",
+        );
+
         opts.optopt(
             "",
             "local-port",
@@ -364,6 +708,13 @@ impl RawConfig {
             "LOCAL_IP",
         );
 
+        opts.optopt(
+            "",
+            "local-addr",
+            r#"Set the full listening address for the local socks5 server, e.g. "127.0.0.1:1080" or "[::1]:1080". Takes precedence over `--local-ip` and `--local-port`"#,
+            "LOCAL_ADDR",
+        );
+
         opts.optopt(
             "",
             "local-username",
@@ -378,13 +729,87 @@ impl RawConfig {
             "LOCAL_PASSWORD",
         );
 
+        opts.optmulti(
+            "",
+            "local-user",
+            "Add a user for the local socks5 server authentication whose connections are relayed with its own TUIC token, in the form of 'USERNAME:PASSWORD:TOKEN'. This option can be used multiple times to add multiple users",
+            "LOCAL_USER",
+        );
+
+        opts.optopt(
+            "",
+            "local-worker-threads",
+            "Run the local socks5 server on a dedicated runtime with the given number of worker threads, so that accepting local connections is isolated from the relay. If not set, the local socks5 server shares the runtime with the relay",
+            "LOCAL_WORKER_THREADS",
+        );
+
+        opts.optflag(
+            "",
+            "local-disable-udp",
+            "Disable UDP relaying on the local socks5 server. UDP ASSOCIATE requests are replied with `CommandNotSupported` without contacting the relay",
+        );
+
+        opts.optflag(
+            "",
+            "local-keep-half-closed-associations",
+            "Keep a UDP association running after the application half-closes its control TCP connection. The association is then torn down when the control connection is fully closed, which is detected with TCP keepalive and can take more than a minute. If not set, the association is torn down as soon as the application stops writing to the control connection",
+        );
+
+        opts.optflag(
+            "",
+            "local-disable-tcp-nodelay",
+            "Keep Nagle's algorithm enabled on the TCP connections accepted by the local socks5 server, which batches small writes at the cost of latency. If not set, `TCP_NODELAY` is set on them",
+        );
+
+        opts.optopt(
+            "",
+            "local-max-connections-per-destination",
+            "Set the maximum number of concurrent TCP connections the client relays to the same destination. Excess connect requests wait locally until a connection to the destination closes. If not set, connections will not be limited",
+            "LOCAL_MAX_CONNECTIONS_PER_DESTINATION",
+        );
+
+        opts.optopt(
+            "",
+            "local-destination-limit-key",
+            r#"Set how destinations are grouped for `--local-max-connections-per-destination`. Available: "host", "host_port". Default: "host_port""#,
+            "LOCAL_DESTINATION_LIMIT_KEY",
+        );
+
+        opts.optopt(
+            "",
+            "local-circuit-breaker-threshold",
+            "Fail connect requests to a destination locally after this many consecutive failures to connect to it, until the cool-down passes. After the cool-down, one request is relayed to test whether the destination recovered. If not set, every connect request is relayed",
+            "LOCAL_CIRCUIT_BREAKER_THRESHOLD",
+        );
+
+        opts.optopt(
+            "",
+            "local-circuit-breaker-cooldown",
+            "Set how long connect requests to a failing destination are failed locally for `--local-circuit-breaker-threshold`, in milliseconds. Default: 30000",
+            "LOCAL_CIRCUIT_BREAKER_COOLDOWN",
+        );
+
         opts.optopt(
             "",
             "max-udp-packet-size",
-            "Set the maximum UDP packet size, in bytes. Excess bytes may be discarded. Default: 1536",
+            "Set the maximum UDP packet size, including the SOCKS5 UDP header, in bytes. Larger packets are dropped. Default: 1536",
             "MAX_UDP_PACKET_SIZE",
         );
 
+        opts.optopt(
+            "",
+            "tcp-chunk-size",
+            "Set the maximum size of each chunk when relaying TCP streams, in bytes. Larger application writes are split and sent as the stream becomes writable. Default: 8192",
+            "TCP_CHUNK_SIZE",
+        );
+
+        opts.optopt(
+            "",
+            "shutdown-timeout",
+            "Set the maximum time to wait on Ctrl-C for the client to stop the local socks5 server, dissociate UDP sessions and close connections to the server, in milliseconds. Default: 3000",
+            "SHUTDOWN_TIMEOUT",
+        );
+
         opts.optopt(
             "",
             "log-level",
@@ -413,6 +838,7 @@ impl RawConfig {
         let server_port = matches.opt_str("server-port").map(|port| port.parse());
         let token = matches.opt_str("token");
         let local_port = matches.opt_str("local-port").map(|port| port.parse());
+        let local_addr = matches.opt_str("local-addr").map(|addr| addr.parse());
 
         let mut raw = if let Some(path) = matches.opt_str("config") {
             let mut raw = RawConfig::from_file(path)?;
@@ -436,12 +862,12 @@ impl RawConfig {
                     .ok_or(ConfigError::MissingOption("token"))?,
             );
 
-            raw.local.port = Some(
-                local_port
-                    .transpose()?
-                    .or(raw.local.port)
-                    .ok_or(ConfigError::MissingOption("local port"))?,
-            );
+            raw.local.addr = local_addr.transpose()?.or(raw.local.addr);
+            raw.local.port = local_port.transpose()?.or(raw.local.port);
+
+            if raw.local.addr.is_none() && raw.local.port.is_none() {
+                return Err(ConfigError::MissingOption("local port"));
+            }
 
             raw
         } else {
@@ -452,9 +878,15 @@ impl RawConfig {
                 ..Default::default()
             };
 
-            let local = RawLocalConfig {
-                port: Some(local_port.ok_or(ConfigError::MissingOption("local port"))??),
-                ..Default::default()
+            let local = match local_addr {
+                Some(addr) => RawLocalConfig {
+                    addr: Some(addr?),
+                    ..Default::default()
+                },
+                None => RawLocalConfig {
+                    port: Some(local_port.ok_or(ConfigError::MissingOption("local port"))??),
+                    ..Default::default()
+                },
             };
 
             RawConfig {
@@ -468,6 +900,16 @@ impl RawConfig {
             raw.relay.ip = Some(ip.parse()?);
         };
 
+        let fallback_servers = matches.opt_strs("fallback-server");
+
+        if !fallback_servers.is_empty() {
+            raw.relay.fallback_servers = fallback_servers;
+        }
+
+        if let Some(policy) = matches.opt_str("failover-policy") {
+            raw.relay.failover_policy = policy.parse()?;
+        };
+
         raw.relay.certificate = matches.opt_str("certificate").or(raw.relay.certificate);
 
         if let Some(mode) = matches.opt_str("udp-mode") {
@@ -486,6 +928,14 @@ impl RawConfig {
             raw.relay.heartbeat_interval = interval.parse()?;
         };
 
+        if let Some(jitter) = matches.opt_str("heartbeat-jitter") {
+            raw.relay.heartbeat_jitter = jitter.parse()?;
+        };
+
+        if let Some(policy) = matches.opt_str("inbound-heartbeat") {
+            raw.relay.inbound_heartbeat = policy.parse()?;
+        };
+
         let alpn = matches.opt_strs("alpn");
 
         if !alpn.is_empty() {
@@ -495,6 +945,35 @@ impl RawConfig {
         raw.relay.disable_sni |= matches.opt_present("disable-sni");
         raw.relay.ipv6_endpoint |= matches.opt_present("ipv6-endpoint");
         raw.relay.reduce_rtt |= matches.opt_present("reduce-rtt");
+        raw.relay.optimistic_connect |= matches.opt_present("optimistic-connect");
+
+        if let Some(threshold) = matches.opt_str("fail-fast-threshold") {
+            raw.relay.fail_fast_threshold = Some(threshold.parse()?);
+        };
+
+        raw.relay.cache_server_address |= matches.opt_present("cache-server-address");
+
+        if let Some(interval) = matches.opt_str("server-address-refresh-interval") {
+            raw.relay.server_address_refresh_interval = Some(interval.parse()?);
+        };
+
+        if let Some(len) = matches.opt_str("authentication-padding") {
+            raw.relay.authentication_padding = Some(len.parse()?);
+        };
+
+        if let Some(len) = matches.opt_str("connection-id-length") {
+            raw.relay.connection_id_length = len.parse()?;
+        };
+
+        if let Some(lifetime) = matches.opt_str("connection-id-lifetime") {
+            raw.relay.connection_id_lifetime = Some(lifetime.parse()?);
+        };
+
+        raw.relay.self_test = matches.opt_str("self-test").or(raw.relay.self_test);
+
+        if let Some(timeout) = matches.opt_str("self-test-timeout") {
+            raw.relay.self_test_timeout = timeout.parse()?;
+        };
 
         if let Some(local_ip) = matches.opt_str("local-ip") {
             raw.local.ip = local_ip.parse()?;
@@ -503,10 +982,47 @@ impl RawConfig {
         raw.local.username = matches.opt_str("local-username").or(raw.local.username);
         raw.local.password = matches.opt_str("local-password").or(raw.local.password);
 
+        for user in matches.opt_strs("local-user") {
+            raw.local.users.push(user.parse()?);
+        }
+
+        if let Some(threads) = matches.opt_str("local-worker-threads") {
+            raw.local.worker_threads = Some(threads.parse()?);
+        };
+
+        raw.local.disable_udp |= matches.opt_present("local-disable-udp");
+        raw.local.keep_half_closed_associations |=
+            matches.opt_present("local-keep-half-closed-associations");
+        raw.local.disable_tcp_nodelay |= matches.opt_present("local-disable-tcp-nodelay");
+
+        if let Some(max) = matches.opt_str("local-max-connections-per-destination") {
+            raw.local.max_connections_per_destination = Some(max.parse()?);
+        };
+
+        if let Some(key) = matches.opt_str("local-destination-limit-key") {
+            raw.local.destination_limit_key = key.parse()?;
+        };
+
+        if let Some(threshold) = matches.opt_str("local-circuit-breaker-threshold") {
+            raw.local.circuit_breaker_threshold = Some(threshold.parse()?);
+        };
+
+        if let Some(cooldown) = matches.opt_str("local-circuit-breaker-cooldown") {
+            raw.local.circuit_breaker_cooldown = cooldown.parse()?;
+        };
+
         if let Some(max_udp_packet_size) = matches.opt_str("max-udp-packet-size") {
             raw.max_udp_packet_size = max_udp_packet_size.parse()?;
         };
 
+        if let Some(tcp_chunk_size) = matches.opt_str("tcp-chunk-size") {
+            raw.tcp_chunk_size = tcp_chunk_size.parse()?;
+        };
+
+        if let Some(timeout) = matches.opt_str("shutdown-timeout") {
+            raw.shutdown_timeout = timeout.parse()?;
+        };
+
         if let Some(log_level) = matches.opt_str("log-level") {
             raw.log_level = log_level.parse()?;
         };
@@ -557,6 +1073,83 @@ impl FromStr for UdpMode {
     }
 }
 
+impl FromStr for FailoverPolicy {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("ordered") {
+            Ok(Self::Ordered)
+        } else if s.eq_ignore_ascii_case("round-robin") {
+            Ok(Self::RoundRobin)
+        } else {
+            Err(ConfigError::InvalidFailoverPolicy)
+        }
+    }
+}
+
+impl FromStr for InboundHeartbeat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("ignore") {
+            Ok(Self::Ignore)
+        } else if s.eq_ignore_ascii_case("respond") {
+            Ok(Self::Respond)
+        } else if s.eq_ignore_ascii_case("reset-timer") {
+            Ok(Self::ResetTimer)
+        } else {
+            Err(ConfigError::InvalidInboundHeartbeat)
+        }
+    }
+}
+
+impl FromStr for DestinationKey {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("host") {
+            Ok(Self::Host)
+        } else if s.eq_ignore_ascii_case("host_port") || s.eq_ignore_ascii_case("hostport") {
+            Ok(Self::HostPort)
+        } else {
+            Err(ConfigError::InvalidDestinationLimitKey)
+        }
+    }
+}
+
+impl FromStr for RelayAddress {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self::SocketAddress(addr));
+        }
+
+        let (hostname, port) = s.rsplit_once(':').ok_or(ConfigError::InvalidAddress)?;
+
+        if hostname.is_empty() || hostname.len() > Address::MAX_DOMAIN_LEN {
+            return Err(ConfigError::InvalidAddress);
+        }
+
+        Ok(Self::DomainAddress(hostname.to_owned(), port.parse()?))
+    }
+}
+
+impl FromStr for RawLocalUser {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, rest) = s.split_once(':').ok_or(ConfigError::InvalidLocalUser)?;
+        let (password, token) = rest.rsplit_once(':').ok_or(ConfigError::InvalidLocalUser)?;
+
+        Ok(Self {
+            username: username.to_owned(),
+            password: password.to_owned(),
+            token: token.to_owned(),
+        })
+    }
+}
+
 fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: FromStr,
@@ -586,6 +1179,10 @@ mod default {
         10000
     }
 
+    pub(super) const fn heartbeat_jitter() -> u64 {
+        0
+    }
+
     pub(super) const fn alpn() -> Vec<String> {
         Vec::new()
     }
@@ -602,14 +1199,102 @@ mod default {
         false
     }
 
+    pub(super) const fn optimistic_connect() -> bool {
+        false
+    }
+
+    pub(super) const fn fail_fast_threshold() -> Option<u64> {
+        None
+    }
+
+    pub(super) const fn cache_server_address() -> bool {
+        false
+    }
+
+    pub(super) const fn server_address_refresh_interval() -> Option<u64> {
+        None
+    }
+
+    pub(super) const fn authentication_padding() -> Option<usize> {
+        None
+    }
+
+    pub(super) const fn connection_id_length() -> usize {
+        8
+    }
+
+    pub(super) const fn connection_id_lifetime() -> Option<u64> {
+        None
+    }
+
+    pub(super) const fn self_test_timeout() -> u64 {
+        5000
+    }
+
+    pub(super) const fn fallback_servers() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub(super) const fn failover_policy() -> FailoverPolicy {
+        FailoverPolicy::Ordered
+    }
+
+    pub(super) const fn inbound_heartbeat() -> InboundHeartbeat {
+        InboundHeartbeat::Ignore
+    }
+
     pub(super) const fn local_ip() -> IpAddr {
         IpAddr::V4(Ipv4Addr::LOCALHOST)
     }
 
+    pub(super) const fn local_users() -> Vec<RawLocalUser> {
+        Vec::new()
+    }
+
+    pub(super) const fn local_worker_threads() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn local_disable_udp() -> bool {
+        false
+    }
+
+    pub(super) const fn local_keep_half_closed_associations() -> bool {
+        false
+    }
+
+    pub(super) const fn local_disable_tcp_nodelay() -> bool {
+        false
+    }
+
+    pub(super) const fn local_max_connections_per_destination() -> Option<usize> {
+        None
+    }
+
+    pub(super) const fn local_destination_limit_key() -> DestinationKey {
+        DestinationKey::HostPort
+    }
+
+    pub(super) const fn local_circuit_breaker_threshold() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn local_circuit_breaker_cooldown() -> u64 {
+        30000
+    }
+
     pub(super) const fn max_udp_packet_size() -> usize {
         1536
     }
 
+    pub(super) const fn tcp_chunk_size() -> usize {
+        8192
+    }
+
+    pub(super) const fn shutdown_timeout() -> u64 {
+        3000
+    }
+
     pub(super) const fn log_level() -> LevelFilter {
         LevelFilter::Info
     }
@@ -639,12 +1324,26 @@ pub enum ConfigError {
     InvalidCongestionController,
     #[error("Invalid udp relay mode")]
     InvalidUdpRelayMode,
+    #[error("Invalid destination limit key")]
+    InvalidDestinationLimitKey,
+    #[error("Invalid failover policy")]
+    InvalidFailoverPolicy,
+    #[error("Invalid inbound heartbeat handling")]
+    InvalidInboundHeartbeat,
     #[error("Failed to load the certificate: {0}")]
     Certificate(#[from] WebpkiError),
     #[error("Could not load platform certs: {0}")]
     NativeCertificate(#[source] IoError),
+    #[error("TCP chunk size must be greater than 0")]
+    InvalidTcpChunkSize,
     #[error("Username and password must be set together for the local socks5 server")]
     LocalAuthentication,
+    #[error("Invalid address, expected 'HOST:PORT'")]
+    InvalidAddress,
+    #[error("Invalid local user, expected 'USERNAME:PASSWORD:TOKEN'")]
+    InvalidLocalUser,
+    #[error("Connection ID length must be at most 20")]
+    InvalidConnectionIdLength,
     #[error(transparent)]
     ParseLogLevel(#[from] ParseLevelError),
 }
@@ -1,14 +1,26 @@
 use crate::{
+    client::Client,
     config::{Config, ConfigError},
-    relay::Relay,
-    socks5::Socks5,
+    relay::{ConnectError, Relay, RelayConfig, Request as RelayRequest},
+    socks5::{Socks5, Socks5Config},
+};
+use std::{env, future, io::Error as IoError, num::NonZeroUsize, process, thread};
+use tokio::{
+    runtime::{Builder, Handle},
+    signal,
+    sync::oneshot,
+    time,
 };
-use std::{env, process};
 
 mod certificate;
+mod client;
 mod config;
 mod relay;
 mod socks5;
+mod status;
+
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() {
@@ -35,44 +47,199 @@ async fn main() {
 
     let (relay, req_tx) = match Relay::init(
         config.client_config,
-        config.server_addr,
-        config.token_digest,
-        config.udp_mode,
-        config.heartbeat_interval,
-        config.ipv6_endpoint,
-        config.reduce_rtt,
+        config.endpoint_config,
+        RelayConfig {
+            server_addrs: config.server_addrs,
+            failover_policy: config.failover_policy,
+            token_digest: config.token_digest,
+            udp_mode: config.udp_mode,
+            heartbeat_interval: config.heartbeat_interval,
+            heartbeat_jitter: config.heartbeat_jitter,
+            inbound_heartbeat: config.inbound_heartbeat,
+            ipv6_endpoint: config.ipv6_endpoint,
+            reduce_rtt: config.reduce_rtt,
+            fail_fast_threshold: config.fail_fast_threshold,
+            cache_server_address: config.cache_server_address,
+            server_address_refresh_interval: config.server_address_refresh_interval,
+            authentication_padding: config.authentication_padding,
+        },
     ) {
-        Ok((relay, tx)) => (tokio::spawn(relay.run()), tx),
+        Ok((relay, tx)) => (relay, tx),
         Err(err) => {
             eprintln!("{err}");
             return;
         }
     };
 
-    let socks5 = match Socks5::init(
-        config.local_addr,
-        config.socks5_authentication,
-        config.max_udp_packet_size,
+    let connection_pool = relay.connection_pool();
+
+    let (relay_shutdown_tx, relay_shutdown_rx) = oneshot::channel();
+    let relay = tokio::spawn(relay.run(async move {
+        let _ = relay_shutdown_rx.await;
+    }));
+
+    if let Some(target) = config.self_test {
+        let target_str = target.to_string();
+        let (req, resp_rx) = RelayRequest::new_connect(target, None, false);
+        let _ = req_tx.send(req).await;
+
+        match time::timeout(config.self_test_timeout, resp_rx).await {
+            Ok(Ok(Ok(_))) => log::info!("[relay] self-test passed. Target: {target_str}"),
+            Ok(Ok(Err(ConnectError::Refused))) => {
+                eprintln!("Self-test failed: the server could not connect to {target_str}");
+                return;
+            }
+            Ok(Ok(Err(ConnectError::Relay(Some(reason))))) => {
+                eprintln!("Self-test failed: the server closed the connection: {reason}");
+                return;
+            }
+            Ok(Ok(Err(ConnectError::Relay(None)))) | Ok(Err(_)) => {
+                eprintln!("Self-test failed: the server is unavailable");
+                return;
+            }
+            Err(_) => {
+                eprintln!("Self-test failed: timed out");
+                return;
+            }
+        }
+    }
+
+    let socks5_runtime = match config.local_worker_threads {
+        Some(threads) => match spawn_runtime(threads) {
+            Ok(handle) => handle,
+            Err(err) => {
+                eprintln!("{err}");
+                return;
+            }
+        },
+        None => Handle::current(),
+    };
+
+    let socks5 = Socks5::init(
+        Socks5Config {
+            local_addr: config.local_addr,
+            authentication: config.socks5_authentication,
+            disable_udp: config.local_disable_udp,
+            keep_half_closed_associations: config.local_keep_half_closed_associations,
+            disable_tcp_nodelay: config.local_disable_tcp_nodelay,
+            max_connections_per_destination: config.local_max_connections_per_destination,
+            destination_limit_key: config.local_destination_limit_key,
+            circuit_breaker_threshold: config.local_circuit_breaker_threshold,
+            circuit_breaker_cooldown: config.local_circuit_breaker_cooldown,
+            max_udp_packet_size: config.max_udp_packet_size,
+            tcp_chunk_size: config.tcp_chunk_size,
+            optimistic_connect: config.optimistic_connect,
+        },
         req_tx,
-    )
-    .await
-    {
-        Ok(socks5) => tokio::spawn(socks5.run()),
+    );
+
+    let (socks5_shutdown_tx, socks5_shutdown_rx) = oneshot::channel();
+
+    let (socks5, local_addr) = match socks5_runtime.spawn(socks5).await {
+        Ok(Ok(socks5)) => {
+            let local_addr = socks5.local_addr();
+
+            let socks5 = socks5_runtime.spawn(socks5.run(async move {
+                let _ = socks5_shutdown_rx.await;
+            }));
+
+            (socks5, local_addr)
+        }
+        Ok(Err(err)) => {
+            eprintln!("{err}");
+            return;
+        }
         Err(err) => {
             eprintln!("{err}");
             return;
         }
     };
 
-    let res = tokio::select! {
-        res = relay => res,
-        res = socks5 => res,
+    let mut client = Client::new(
+        relay,
+        relay_shutdown_tx,
+        socks5,
+        socks5_shutdown_tx,
+        local_addr,
+        connection_pool,
+    );
+
+    log::info!("[socks5] started. Listening: {}", client.local_addr());
+
+    let mut dump_signal = match DumpSignal::new() {
+        Ok(signal) => signal,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
     };
 
-    match res {
-        Ok(()) => {}
-        Err(err) => eprintln!("{err}"),
+    loop {
+        tokio::select! {
+            res = client.wait() => {
+                match res {
+                    Ok(()) => {}
+                    Err(err) => eprintln!("{err}"),
+                }
+
+                process::exit(1);
+            }
+            () = dump_signal.recv() => {
+                match serde_json::to_string(&client.status()) {
+                    Ok(status) => log::info!("Status: {status}"),
+                    Err(err) => log::error!("Failed to serialize the client status: {err}"),
+                }
+            }
+            _ = signal::ctrl_c() => break,
+        }
     }
 
-    process::exit(1);
+    if !client.shutdown(config.shutdown_timeout).await {
+        log::warn!("shutdown timed out, remaining tasks aborted");
+        process::exit(1);
+    }
+}
+
+fn spawn_runtime(worker_threads: NonZeroUsize) -> Result<Handle, IoError> {
+    let rt = Builder::new_multi_thread()
+        .worker_threads(worker_threads.get())
+        .thread_name("tuic-socks5")
+        .enable_all()
+        .build()?;
+
+    let handle = rt.handle().clone();
+    thread::spawn(move || rt.block_on(future::pending::<()>()));
+
+    Ok(handle)
+}
+
+#[cfg(unix)]
+struct DumpSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl DumpSignal {
+    fn new() -> Result<Self, IoError> {
+        use tokio::signal::unix::{self, SignalKind};
+        Ok(Self(unix::signal(SignalKind::user_defined2())?))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct DumpSignal;
+
+#[cfg(not(unix))]
+impl DumpSignal {
+    fn new() -> Result<Self, IoError> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        future::pending::<()>().await;
+    }
 }
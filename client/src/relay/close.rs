@@ -0,0 +1,135 @@
+use super::RelayError;
+use quinn::{ConnectionError, ReadError, ReadExactError, VarInt, WriteError};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::Error as IoError,
+};
+use tuic_protocol::{CloseCode, Error as ProtocolError};
+
+/// The reason the server closed the connection for, decoded from its application close code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    Protocol,
+    AuthenticationFailed,
+    AuthenticationTimeout,
+    BadCommand,
+    Unauthenticated,
+    QuotaExceeded,
+    TooManyMigrations,
+    UnexpectedResponse,
+    AuthenticationInDatagram,
+    TruncatedCommand,
+    LifetimeExpired,
+    Other(VarInt),
+}
+
+impl CloseReason {
+    pub fn from_code(code: VarInt) -> Self {
+        match CloseCode::from_code(code.into_inner()) {
+            Some(CloseCode::Protocol) => Self::Protocol,
+            Some(CloseCode::AuthenticationFailed) => Self::AuthenticationFailed,
+            Some(CloseCode::AuthenticationTimeout) => Self::AuthenticationTimeout,
+            Some(CloseCode::BadCommand) => Self::BadCommand,
+            Some(CloseCode::Unauthenticated) => Self::Unauthenticated,
+            Some(CloseCode::QuotaExceeded) => Self::QuotaExceeded,
+            Some(CloseCode::TooManyMigrations) => Self::TooManyMigrations,
+            Some(CloseCode::UnexpectedResponse) => Self::UnexpectedResponse,
+            Some(CloseCode::AuthenticationInDatagram) => Self::AuthenticationInDatagram,
+            Some(CloseCode::TruncatedCommand) => Self::TruncatedCommand,
+            Some(CloseCode::LifetimeExpired) => Self::LifetimeExpired,
+            None => Self::Other(code),
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Protocol => write!(f, "protocol error"),
+            Self::AuthenticationFailed => write!(f, "the token was rejected"),
+            Self::AuthenticationTimeout => write!(f, "authentication timed out"),
+            Self::BadCommand => write!(f, "bad command"),
+            Self::Unauthenticated => write!(f, "command sent before authentication"),
+            Self::QuotaExceeded => write!(f, "byte quota of the connection exceeded"),
+            Self::TooManyMigrations => write!(f, "too many migrations"),
+            Self::UnexpectedResponse => write!(f, "response command sent to the server"),
+            Self::AuthenticationInDatagram => write!(f, "authentication sent in a datagram"),
+            Self::TruncatedCommand => write!(f, "truncated command"),
+            Self::LifetimeExpired => write!(f, "connection lifetime expired"),
+            Self::Other(code) => write!(f, "close code {code}"),
+        }
+    }
+}
+
+impl RelayError {
+    /// The reason the server gave for closing the connection, if that is what caused the error
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        let err = match self {
+            Self::Connection(err)
+            | Self::WriteStream(WriteError::ConnectionLost(err))
+            | Self::ReadStream(ReadExactError::ReadError(ReadError::ConnectionLost(err))) => err,
+            Self::Protocol(ProtocolError::Io(err)) | Self::Io(err) => lost_connection(err)?,
+            _ => return None,
+        };
+
+        match err {
+            ConnectionError::ApplicationClosed(close) => {
+                Some(CloseReason::from_code(close.error_code))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Stream errors lose their type when they are passed through `AsyncRead` and `AsyncWrite`
+fn lost_connection(err: &IoError) -> Option<&ConnectionError> {
+    let err = err.get_ref()?;
+
+    match (err.downcast_ref(), err.downcast_ref()) {
+        (Some(ReadError::ConnectionLost(err)), _) | (_, Some(WriteError::ConnectionLost(err))) => {
+            Some(err)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CloseReason, RelayError};
+    use bytes::Bytes;
+    use quinn::{ApplicationClose, ConnectionError, ReadError, VarInt, WriteError};
+    use std::io::{Error as IoError, ErrorKind};
+    use tuic_protocol::{CloseCode, Error as ProtocolError};
+
+    fn closed_with(code: CloseCode) -> ConnectionError {
+        ConnectionError::ApplicationClosed(ApplicationClose {
+            error_code: VarInt::from_u32(code.as_u32()),
+            reason: Bytes::new(),
+        })
+    }
+
+    #[test]
+    fn close_reasons_are_found_through_io_errors() {
+        let err = RelayError::Io(IoError::from(ReadError::ConnectionLost(closed_with(
+            CloseCode::Unauthenticated,
+        ))));
+        assert_eq!(err.close_reason(), Some(CloseReason::Unauthenticated));
+
+        let err = RelayError::Protocol(ProtocolError::Io(IoError::from(
+            WriteError::ConnectionLost(closed_with(CloseCode::QuotaExceeded)),
+        )));
+        assert_eq!(err.close_reason(), Some(CloseReason::QuotaExceeded));
+    }
+
+    #[test]
+    fn other_errors_have_no_close_reason() {
+        let reset = RelayError::Io(IoError::from(ReadError::Reset(VarInt::from_u32(0))));
+        assert_eq!(reset.close_reason(), None);
+
+        let io = RelayError::Io(IoError::from(ErrorKind::UnexpectedEof));
+        assert_eq!(io.close_reason(), None);
+
+        let timed_out = RelayError::Connection(ConnectionError::TimedOut);
+        assert_eq!(timed_out.close_reason(), None);
+    }
+}
@@ -1,8 +1,9 @@
 use super::{task, Connection};
-use crate::relay::{Address, RelayError, Request, TaskCount, UdpMode};
+use crate::relay::{Address, InboundHeartbeat, RelayError, Request, TaskCount, UdpMode};
 use bytes::Bytes;
 use quinn::RecvStream;
-use tuic_protocol::Command as TuicCommand;
+use std::{sync::atomic::Ordering, time::Instant};
+use tuic_protocol::{Address as TuicAddress, Command as TuicCommand};
 
 impl Connection {
     pub async fn process_relay_request(
@@ -11,21 +12,64 @@ impl Connection {
         task_count: TaskCount,
     ) -> Result<(), RelayError> {
         match req {
-            Request::Connect { addr, tx } => {
+            Request::Connect {
+                addr,
+                tx,
+                optimistic,
+                ..
+            } => {
                 log::info!("[relay] [task] [connect] [{addr}]");
-                let res = task::connect(self.controller, addr, tx).await;
+                let started = Instant::now();
+                let res = task::connect(
+                    self.controller.clone(),
+                    addr,
+                    tx,
+                    optimistic,
+                    self.is_closed.clone(),
+                )
+                .await;
+
+                // in optimistic mode, the response is read after the streams are handed over
+                if res.is_ok() && !optimistic {
+                    self.record_first_connect(started.elapsed());
+                }
+
                 drop(task_count);
                 res?
             }
             Request::Associate {
                 assoc_id,
+                tx,
                 mut pkt_send_rx,
                 pkt_receive_tx,
+                ..
             } => {
+                if self.udp_refused.load(Ordering::Acquire) {
+                    log::warn!("[relay] [task] [associate] [{assoc_id}] UDP relaying is disabled by the server");
+                    let _ = tx.send(false);
+                    return Ok(());
+                }
+
+                let _ = tx.send(true);
+
                 log::info!("[relay] [task] [associate] [{assoc_id}]");
                 self.udp_sessions.lock().insert(assoc_id, pkt_receive_tx);
 
                 while let Some((pkt, addr)) = pkt_send_rx.recv().await {
+                    let addr = TuicAddress::from(addr);
+
+                    match self.max_udp_payload(&addr) {
+                        Some(max) if pkt.len() <= max => {}
+                        Some(max) => {
+                            log::warn!("[relay] [task] [associate] [{assoc_id}] packet of {} bytes to {addr} exceeds the maximum UDP payload of {max} bytes, dropped", pkt.len());
+                            continue;
+                        }
+                        None => {
+                            log::warn!("[relay] [task] [associate] [{assoc_id}] datagrams are not available on the connection, packet to {addr} dropped");
+                            continue;
+                        }
+                    }
+
                     let conn = self.controller.clone();
 
                     tokio::spawn(async move {
@@ -79,27 +123,160 @@ impl Connection {
                 task::packet_from_server(pkt, self.udp_sessions, assoc_id, Address::from(addr))
                     .await
             }
+            TuicCommand::Dissociate { assoc_id } => {
+                // dropping the session closes the SOCKS5 association, as it was already reported as succeeded
+                if self.udp_sessions.lock().remove(&assoc_id).is_some() {
+                    log::warn!("[relay] [task] [associate] [{assoc_id}] refused by the server, closing the association");
+                    self.udp_refused.store(true, Ordering::Release);
+                } else {
+                    log::debug!("[relay] [task] [dissociate] [{assoc_id}] [acknowledged]");
+                }
+
+                Ok(())
+            }
+            TuicCommand::Heartbeat => {
+                log::debug!("[relay] [connection] [heartbeat] [inbound]");
+
+                match self.inbound_heartbeat {
+                    InboundHeartbeat::Ignore => Ok(()),
+                    InboundHeartbeat::Respond => {
+                        task::heartbeat(self.controller).await?;
+                        log::debug!("[relay] [connection] [heartbeat]");
+                        Ok(())
+                    }
+                    InboundHeartbeat::ResetTimer => {
+                        self.heartbeat_reset.notify_one();
+                        Ok(())
+                    }
+                }
+            }
             _ => Err(RelayError::BadCommand),
         }
     }
 
-    pub async fn process_incoming_datagram(self, datagram: Bytes) -> Result<(), RelayError> {
-        let cmd = TuicCommand::read_from(&mut datagram.as_ref()).await?;
-        let cmd_len = cmd.serialized_len();
-
-        match cmd {
-            TuicCommand::Packet { assoc_id, addr, .. } => {
-                log::debug!("[relay] [task] [associate] [{assoc_id}] [packet-from-native] {addr}");
+    pub async fn process_incoming_datagram(self, mut datagram: Bytes) -> Result<(), RelayError> {
+        // a datagram may carry multiple coalesced packets, each with its own header
+        while !datagram.is_empty() {
+            let cmd = TuicCommand::read_from(&mut datagram.as_ref()).await?;
+            let cmd_len = cmd.serialized_len();
 
-                task::packet_from_server(
-                    datagram.slice(cmd_len..),
-                    self.udp_sessions,
+            match cmd {
+                TuicCommand::Packet {
                     assoc_id,
-                    Address::from(addr),
-                )
-                .await
+                    len,
+                    addr,
+                } => {
+                    let pkt_end = cmd_len + len as usize;
+
+                    if pkt_end > datagram.len() {
+                        return Err(RelayError::BadCommand);
+                    }
+
+                    let pkt = datagram.slice(cmd_len..pkt_end);
+                    datagram = datagram.slice(pkt_end..);
+
+                    log::debug!(
+                        "[relay] [task] [associate] [{assoc_id}] [packet-from-native] {addr}"
+                    );
+
+                    let res = task::packet_from_server(
+                        pkt,
+                        self.udp_sessions.clone(),
+                        assoc_id,
+                        Address::from(addr),
+                    )
+                    .await;
+
+                    match res {
+                        Ok(()) => {}
+                        Err(err) => log::warn!("[relay] [connection] [incoming] {err}"),
+                    }
+                }
+                _ => return Err(RelayError::BadCommand),
             }
-            _ => Err(RelayError::BadCommand),
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connection;
+    use crate::{
+        relay::{Address, InboundHeartbeat, Request, TaskCount, UdpMode},
+        testing,
+    };
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use quinn::{Endpoint, NewConnection};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+    use tokio::{sync::mpsc, time};
+    use tuic_protocol::{Address as TuicAddress, Command};
+
+    #[tokio::test]
+    async fn packets_over_the_maximum_udp_payload_are_dropped() {
+        let (server, mut incoming) = Endpoint::server(
+            testing::server_config(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        )
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        // reports the payload length of each packet the server receives
+        let (payload_tx, mut payload_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let NewConnection { mut datagrams, .. } = incoming.next().await.unwrap().await.unwrap();
+
+            while let Some(Ok(datagram)) = datagrams.next().await {
+                let (_, header_len) = Command::from_bytes(&datagram).unwrap();
+                let _ = payload_tx.send(datagram.len() - header_len);
+            }
+        });
+
+        let mut client = Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        client.set_default_client_config(testing::client_config());
+        let conn = Connection::init(
+            client.connect(server_addr, "localhost").unwrap(),
+            [0; 32],
+            UdpMode::Native,
+            InboundHeartbeat::Ignore,
+            false,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let dst_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 53));
+        let max = conn
+            .max_udp_payload(&TuicAddress::SocketAddress(dst_addr))
+            .unwrap();
+
+        let (req, rx, pkt_send_tx, _pkt_receive_rx) = Request::new_associate(None);
+        tokio::spawn(conn.clone().process_relay_request(req, TaskCount::new()));
+        assert!(rx.await.unwrap());
+
+        for len in [max + 1, max] {
+            pkt_send_tx
+                .send((Bytes::from(vec![0; len]), Address::SocketAddress(dst_addr)))
+                .await
+                .unwrap();
+        }
+
+        let received = time::timeout(Duration::from_secs(5), payload_rx.recv())
+            .await
+            .expect("the packet at the maximum was not sent");
+        assert_eq!(received, Some(max));
+
+        assert!(
+            time::timeout(Duration::from_millis(200), payload_rx.recv())
+                .await
+                .is_err(),
+            "a packet over the maximum was sent"
+        );
     }
 }
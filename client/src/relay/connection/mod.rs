@@ -1,11 +1,13 @@
-use super::{Address, RelayError, TaskCount, UdpMode};
+use super::{Address, CloseReason, InboundHeartbeat, RelayError, TaskCount, UdpMode};
+use crate::status::{ConnectionStatus, HandshakeTiming, ZeroRtt};
 use bytes::Bytes;
 use futures_util::StreamExt;
 use parking_lot::Mutex;
 use quinn::{
     Connecting, Connection as QuinnConnection, ConnectionError, Datagrams, IncomingUniStreams,
-    NewConnection,
+    NewConnection, SendStream, VarInt, WriteError,
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::{
     collections::HashMap,
     future::Future,
@@ -15,10 +17,13 @@ use std::{
         Arc,
     },
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::{sync::mpsc::Sender, time};
-use tuic_protocol::Command;
+use tokio::{
+    sync::{mpsc::Sender, Notify},
+    time,
+};
+use tuic_protocol::{Address as TuicAddress, Command};
 
 mod dispatch;
 mod task;
@@ -29,6 +34,10 @@ pub struct Connection {
     udp_mode: UdpMode,
     udp_sessions: Arc<UdpSessionMap>,
     is_closed: IsClosed,
+    udp_refused: Arc<AtomicBool>,
+    inbound_heartbeat: InboundHeartbeat,
+    heartbeat_reset: Arc<Notify>,
+    timing: Arc<Mutex<HandshakeTiming>>,
 }
 
 pub type UdpSessionMap = Mutex<HashMap<u32, Sender<(Bytes, Address)>>>;
@@ -38,8 +47,17 @@ impl Connection {
         conn: Connecting,
         token_digest: [u8; 32],
         udp_mode: UdpMode,
+        inbound_heartbeat: InboundHeartbeat,
         reduce_rtt: bool,
+        auth_padding: Option<usize>,
+        resolve_time: Option<Duration>,
     ) -> Result<Self, RelayError> {
+        let timing = Arc::new(Mutex::new(HandshakeTiming {
+            resolve_ms: resolve_time.map(millis),
+            ..HandshakeTiming::default()
+        }));
+        let started = Instant::now();
+
         let NewConnection {
             connection,
             uni_streams,
@@ -47,11 +65,45 @@ impl Connection {
             ..
         } = if reduce_rtt {
             match conn.into_0rtt() {
-                Ok((conn, _)) => conn,
-                Err(conn) => conn.await?,
+                Ok((conn, zero_rtt_accepted)) => {
+                    let timing = timing.clone();
+
+                    tokio::spawn(async move {
+                        let accepted = zero_rtt_accepted.await;
+
+                        let outcome = if accepted {
+                            ZeroRtt::Accepted
+                        } else {
+                            ZeroRtt::Rejected
+                        };
+
+                        let mut timing = timing.lock();
+                        timing.handshake_ms = Some(millis(started.elapsed()));
+                        timing.zero_rtt = Some(outcome);
+                        drop(timing);
+
+                        if accepted {
+                            log::debug!("[relay] [connection] [0-rtt] accepted");
+                        } else {
+                            // the server may have disabled 0-RTT or not recognized the session ticket
+                            log::info!("[relay] [connection] [0-rtt] rejected by the server or the handshake failed, streams opened before the handshake completed are lost");
+                        }
+                    });
+
+                    conn
+                }
+                Err(conn) => {
+                    log::info!("[relay] [connection] [0-rtt] unavailable: no valid session ticket cached for the server, falling back to 1-RTT");
+                    timing.lock().zero_rtt = Some(ZeroRtt::Unavailable);
+                    let conn = conn.await?;
+                    timing.lock().handshake_ms = Some(millis(started.elapsed()));
+                    conn
+                }
             }
         } else {
-            conn.await?
+            let conn = conn.await?;
+            timing.lock().handshake_ms = Some(millis(started.elapsed()));
+            conn
         };
 
         let udp_sessions = Arc::new(Mutex::new(HashMap::new()));
@@ -62,14 +114,26 @@ impl Connection {
             udp_mode,
             udp_sessions,
             is_closed,
+            udp_refused: Arc::new(AtomicBool::new(false)),
+            inbound_heartbeat,
+            heartbeat_reset: Arc::new(Notify::new()),
+            timing,
         };
 
-        tokio::spawn(Self::authenticate(conn.clone(), token_digest));
+        // the server expects the authentication on the first unidirectional stream, so it is opened before any other
+        let auth_stream = conn.controller.open_uni().await?;
+        tokio::spawn(Self::authenticate(
+            conn.clone(),
+            auth_stream,
+            token_digest,
+            auth_padding,
+        ));
 
-        match udp_mode {
-            UdpMode::Native => tokio::spawn(Self::listen_datagrams(conn.clone(), datagrams)),
-            UdpMode::Quic => tokio::spawn(Self::listen_uni_streams(conn.clone(), uni_streams)),
-        };
+        tokio::spawn(Self::listen_uni_streams(conn.clone(), uni_streams));
+
+        if let UdpMode::Native = udp_mode {
+            tokio::spawn(Self::listen_datagrams(conn.clone(), datagrams));
+        }
 
         Ok(conn)
     }
@@ -78,25 +142,87 @@ impl Connection {
         self.is_closed.check()
     }
 
-    pub fn start_heartbeat(&self, task_count: TaskCount, heartbeat_interval: u64) {
-        async fn heartbeat(conn: &QuinnConnection) -> Result<(), RelayError> {
-            let mut stream = conn.open_uni().await?;
-            let heartbeat = Command::new_heartbeat();
-            heartbeat.write_to(&mut stream).await?;
-            Ok(())
+    pub fn status(&self, in_flight_requests: usize) -> ConnectionStatus {
+        ConnectionStatus {
+            remote_addr: self.controller.remote_address(),
+            rtt_ms: self.controller.rtt().as_millis() as u64,
+            in_flight_requests,
+            udp_sessions: self.udp_sessions.lock().len(),
+            timing: *self.timing.lock(),
         }
+    }
+
+    /// Records the round trip of the first `Connect`, logging the timing of the connection on its first use
+    fn record_first_connect(&self, rtt: Duration) {
+        let mut timing = self.timing.lock();
 
+        if timing.first_connect_ms.is_none() {
+            timing.first_connect_ms = Some(millis(rtt));
+            log::debug!("[relay] [connection] [timing] {timing}");
+        }
+    }
+
+    /// The largest UDP payload that can currently be relayed to `addr` in a single packet, or `None` if the server does not accept datagrams in native mode
+    ///
+    /// In native mode, this follows the datagram size allowed by the current path MTU. In QUIC mode, packets are only limited by the length field of the header
+    pub fn max_udp_payload(&self, addr: &TuicAddress) -> Option<usize> {
+        match self.udp_mode {
+            UdpMode::Native => self
+                .controller
+                .max_datagram_size()
+                .map(|size| size.saturating_sub(Command::packet_header_len(addr)))
+                .map(|size| size.min(u16::MAX as usize)),
+            UdpMode::Quic => Some(u16::MAX as usize),
+        }
+    }
+
+    /// Dissociates all UDP sessions, then closes the connection
+    pub async fn close(&self) {
+        let assoc_ids = self
+            .udp_sessions
+            .lock()
+            .drain()
+            .map(|(assoc_id, _)| assoc_id)
+            .collect::<Vec<_>>();
+
+        for assoc_id in assoc_ids {
+            log::info!("[relay] [task] [dissociate] [{assoc_id}]");
+
+            match task::dissociate(self.controller.clone(), assoc_id).await {
+                Ok(()) => {}
+                Err(err) => log::warn!("[relay] [task] [dissociate] [{assoc_id}] {err}"),
+            }
+        }
+
+        self.controller
+            .close(VarInt::from_u32(0), b"client shutting down");
+        self.is_closed.set_closed();
+    }
+
+    pub fn start_heartbeat(
+        &self,
+        task_count: TaskCount,
+        heartbeat_interval: u64,
+        heartbeat_jitter: u64,
+    ) {
         let conn = self.controller.clone();
         let is_closed = self.is_closed.clone();
-        let mut interval = time::interval(Duration::from_millis(heartbeat_interval));
+        let heartbeat_reset = self.heartbeat_reset.clone();
+        let mut rng = StdRng::from_entropy();
 
         tokio::spawn(async move {
-            while tokio::select! {
-                () = is_closed.clone() => false,
-                _ = interval.tick() => true,
-            } {
+            loop {
+                tokio::select! {
+                    () = is_closed.clone() => break,
+                    () = heartbeat_reset.notified() => continue,
+                    () = time::sleep(Duration::from_millis(rng.gen_range(
+                        heartbeat_interval.saturating_sub(heartbeat_jitter)
+                            ..=heartbeat_interval.saturating_add(heartbeat_jitter),
+                    ))) => {}
+                }
+
                 if !task_count.is_zero() {
-                    match heartbeat(&conn).await {
+                    match task::heartbeat(conn.clone()).await {
                         Ok(()) => log::debug!("[relay] [connection] [heartbeat]"),
                         Err(err) => log::error!("[relay] [connection] [heartbeat] {err}"),
                     }
@@ -105,19 +231,40 @@ impl Connection {
         });
     }
 
-    async fn authenticate(self, token_digest: [u8; 32]) {
+    async fn authenticate(
+        self,
+        stream: SendStream,
+        token_digest: [u8; 32],
+        padding: Option<usize>,
+    ) {
         async fn send_authenticate(
-            conn: QuinnConnection,
+            mut stream: SendStream,
             token_digest: [u8; 32],
+            padding: Option<usize>,
         ) -> Result<(), RelayError> {
-            let mut stream = conn.open_uni().await?;
             let cmd = Command::new_authenticate(token_digest);
             cmd.write_to(&mut stream).await?;
+
+            let padding = authentication_padding(&cmd, padding);
+
+            if !padding.is_empty() {
+                // the server stops the stream once it has read the command
+                match stream.write_all(&padding).await {
+                    Ok(()) | Err(WriteError::Stopped(_)) => {}
+                    Err(err) => return Err(RelayError::from(err)),
+                }
+            }
+
             Ok(())
         }
 
-        match send_authenticate(self.controller, token_digest).await {
-            Ok(()) => log::debug!("[relay] [connection] [authentication]"),
+        let started = Instant::now();
+
+        match send_authenticate(stream, token_digest, padding).await {
+            Ok(()) => {
+                self.timing.lock().authentication_ms = Some(millis(started.elapsed()));
+                log::debug!("[relay] [connection] [authentication]");
+            }
             Err(err) => {
                 self.is_closed.set_closed();
                 log::error!("[relay] [connection] [authentication] {err}");
@@ -148,15 +295,27 @@ impl Connection {
         }
 
         let is_closed = self.is_closed.clone();
+        let udp_sessions = self.udp_sessions.clone();
 
         match listen(self, uni_streams).await {
             Ok(())
             | Err(RelayError::Connection(ConnectionError::LocallyClosed))
             | Err(RelayError::Connection(ConnectionError::TimedOut)) => {}
+            Err(err) if err.close_reason() == Some(CloseReason::LifetimeExpired) => {
+                log::info!(
+                    "[relay] [connection] lifetime expired, reconnecting on the next request"
+                )
+            }
             Err(err) => log::error!("[relay] [connection] {err}"),
         }
 
         is_closed.set_closed();
+
+        let closed = close_udp_sessions(&udp_sessions);
+
+        if closed > 0 {
+            log::info!("[relay] [connection] {closed} UDP associations closed with the connection");
+        }
     }
 
     async fn listen_datagrams(self, datagrams: Datagrams) {
@@ -184,6 +343,8 @@ impl Connection {
             Ok(())
             | Err(RelayError::Connection(ConnectionError::LocallyClosed))
             | Err(RelayError::Connection(ConnectionError::TimedOut)) => {}
+            // already reported by `listen_uni_streams`
+            Err(err) if err.close_reason() == Some(CloseReason::LifetimeExpired) => {}
             Err(err) => log::error!("[relay] [connection] {err}"),
         }
 
@@ -191,6 +352,23 @@ impl Connection {
     }
 }
 
+/// Random bytes that bring the stream carrying `cmd` to `padding` bytes in total. The server reads only the command and stops the stream, so it discards them without having to know about padding
+fn authentication_padding(cmd: &Command, padding: Option<usize>) -> Vec<u8> {
+    let len = padding.map_or(0, |len| len.saturating_sub(cmd.serialized_len()));
+    let mut padding = vec![0; len];
+    StdRng::from_entropy().fill(&mut padding[..]);
+    padding
+}
+
+/// Associations are bound to sockets of the server on the connection they were opened on, so they can not move to a new connection. Dropping their packet channels closes the SOCKS5 associations, so applications can associate again
+fn close_udp_sessions(udp_sessions: &UdpSessionMap) -> usize {
+    udp_sessions.lock().drain().count()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
 #[derive(Clone)]
 struct IsClosed(Arc<IsClosedInner>);
 
@@ -232,3 +410,43 @@ impl Future for IsClosed {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{authentication_padding, close_udp_sessions};
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+    use tuic_protocol::Command;
+
+    #[tokio::test]
+    async fn padded_authentication_parses_like_unpadded() {
+        let cmd = Command::new_authenticate([7; 32]);
+        let unpadded = cmd.to_bytes();
+
+        for padding in [None, Some(0), Some(16), Some(34), Some(35), Some(1200)] {
+            let mut stream = cmd.to_bytes();
+            stream.extend(authentication_padding(&cmd, padding));
+            assert_eq!(stream.len(), padding.unwrap_or(0).max(unpadded.len()));
+
+            let (parsed, consumed) = Command::from_bytes(&stream).unwrap();
+            assert_eq!(parsed.to_bytes(), unpadded);
+            assert_eq!(consumed, unpadded.len());
+
+            let mut reader = stream.as_slice();
+            let parsed = Command::read_from(&mut reader).await.unwrap();
+            assert_eq!(parsed.to_bytes(), unpadded);
+            assert_eq!(reader.len(), stream.len() - unpadded.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_the_connection_closes_its_associations() {
+        let (pkt_receive_tx, mut pkt_receive_rx) = mpsc::channel(1);
+        let udp_sessions = Mutex::new(HashMap::from([(1, pkt_receive_tx)]));
+
+        assert_eq!(close_udp_sessions(&udp_sessions), 1);
+        assert!(pkt_receive_rx.recv().await.is_none());
+        assert_eq!(close_udp_sessions(&udp_sessions), 0);
+    }
+}
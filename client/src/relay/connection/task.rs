@@ -1,5 +1,5 @@
-use super::UdpSessionMap;
-use crate::relay::{Address, RelayError};
+use super::{IsClosed, UdpSessionMap};
+use crate::relay::{Address, ConnectError, RelayError};
 use bytes::{Bytes, BytesMut};
 use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
 use std::sync::Arc;
@@ -9,11 +9,14 @@ use tuic_protocol::{Address as TuicAddress, Command as TuicCommand};
 pub async fn connect(
     conn: QuinnConnection,
     addr: Address,
-    tx: Sender<Option<(SendStream, RecvStream)>>,
+    tx: Sender<Result<(SendStream, RecvStream), ConnectError>>,
+    optimistic: bool,
+    is_closed: IsClosed,
 ) -> Result<(), RelayError> {
     async fn get_streams(
         conn: QuinnConnection,
         addr: Address,
+        optimistic: bool,
     ) -> Result<Option<(SendStream, RecvStream)>, RelayError> {
         let (mut send, mut recv) = conn.open_bi().await?;
 
@@ -22,6 +25,11 @@ pub async fn connect(
 
         cmd.write_to(&mut send).await?;
 
+        // the caller reads the `Response` itself while already sending data
+        if optimistic {
+            return Ok(Some((send, recv)));
+        }
+
         let resp = TuicCommand::read_from(&mut recv).await?;
 
         if let TuicCommand::Response(true) = resp {
@@ -31,13 +39,24 @@ pub async fn connect(
         }
     }
 
-    match get_streams(conn, addr).await {
-        Ok(res) => {
-            let _ = tx.send(res);
+    match get_streams(conn, addr, optimistic).await {
+        Ok(Some(streams)) => {
+            let _ = tx.send(Ok(streams));
+            Ok(())
+        }
+        Ok(None) => {
+            let _ = tx.send(Err(ConnectError::Refused));
             Ok(())
         }
         Err(err) => {
-            let _ = tx.send(None);
+            let reason = err.close_reason();
+
+            // the request may be retried, which must not be dispatched to this connection again
+            if reason.is_some() {
+                is_closed.set_closed();
+            }
+
+            let _ = tx.send(Err(ConnectError::Relay(reason)));
             Err(err)
         }
     }
@@ -47,11 +66,10 @@ pub async fn packet_to_uni_stream(
     conn: QuinnConnection,
     assoc_id: u32,
     pkt: Bytes,
-    addr: Address,
+    addr: TuicAddress,
 ) -> Result<(), RelayError> {
     let mut stream = conn.open_uni().await?;
 
-    let addr = TuicAddress::from(addr);
     let cmd = TuicCommand::new_packet(assoc_id, pkt.len() as u16, addr);
 
     cmd.write_to(&mut stream).await?;
@@ -64,9 +82,8 @@ pub async fn packet_to_datagram(
     conn: QuinnConnection,
     assoc_id: u32,
     pkt: Bytes,
-    addr: Address,
+    addr: TuicAddress,
 ) -> Result<(), RelayError> {
-    let addr = TuicAddress::from(addr);
     let cmd = TuicCommand::new_packet(assoc_id, pkt.len() as u16, addr);
 
     let mut buf = BytesMut::with_capacity(cmd.serialized_len());
@@ -96,10 +113,19 @@ pub async fn packet_from_server(
     Ok(())
 }
 
+pub async fn heartbeat(conn: QuinnConnection) -> Result<(), RelayError> {
+    let mut stream = conn.open_uni().await?;
+    let cmd = TuicCommand::new_heartbeat();
+    cmd.write_to(&mut stream).await?;
+
+    Ok(())
+}
+
 pub async fn dissociate(conn: QuinnConnection, assoc_id: u32) -> Result<(), RelayError> {
     let mut stream = conn.open_uni().await?;
     let cmd = TuicCommand::new_dissociate(assoc_id);
     cmd.write_to(&mut stream).await?;
+    stream.finish().await?;
 
     Ok(())
 }
@@ -1,49 +1,96 @@
 use self::connection::Connection;
+use crate::status::ClientStatus;
+use parking_lot::Mutex;
 use quinn::{
     ClientConfig, ConnectionError, Endpoint, EndpointConfig, ReadExactError, SendDatagramError,
     WriteError,
 };
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
+    collections::HashMap,
     fmt::{Display, Formatter, Result as FmtResult},
+    future::{self, Future},
     io::Error as IoError,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 use tokio::{
     net,
     sync::mpsc::{self, Receiver, Sender},
+    time,
 };
 use tuic_protocol::Error as ProtocolError;
 
-pub use self::{address::Address, request::Request};
+pub use self::{
+    address::Address,
+    close::CloseReason,
+    request::{read_connect_response, ConnectError, Request},
+};
 
 mod address;
+mod close;
 mod connection;
 mod request;
 
 pub struct Relay {
-    req_rx: Receiver<Request>,
+    req_rx: Mutex<Receiver<Request>>,
     endpoint: Endpoint,
-    server_addr: ServerAddr,
+    pool: Arc<Mutex<ConnectionPool>>,
+    server_addrs: Vec<ServerAddr>,
+    server_health: Mutex<ServerHealth>,
+    server_addr_cache: Option<Mutex<ServerAddrCache>>,
     token_digest: [u8; 32],
     udp_mode: UdpMode,
     heartbeat_interval: u64,
+    heartbeat_jitter: u64,
+    inbound_heartbeat: InboundHeartbeat,
     reduce_rtt: bool,
+    authentication_padding: Option<usize>,
+    fail_fast_threshold: Option<Duration>,
+}
+
+/// How the relay connects to the server and relays requests
+pub struct RelayConfig {
+    pub server_addrs: Vec<ServerAddr>,
+    pub failover_policy: FailoverPolicy,
+    pub token_digest: [u8; 32],
+    pub udp_mode: UdpMode,
+    pub heartbeat_interval: u64,
+    pub heartbeat_jitter: u64,
+    pub inbound_heartbeat: InboundHeartbeat,
+    pub ipv6_endpoint: bool,
+    pub reduce_rtt: bool,
+    pub fail_fast_threshold: Option<Duration>,
+    pub cache_server_address: bool,
+    pub server_address_refresh_interval: Option<Duration>,
+    pub authentication_padding: Option<usize>,
 }
 
 impl Relay {
     pub fn init(
         config: ClientConfig,
-        server_addr: ServerAddr,
-        token_digest: [u8; 32],
-        udp_mode: UdpMode,
-        heartbeat_interval: u64,
-        ipv6_endpoint: bool,
-        reduce_rtt: bool,
+        endpoint_config: EndpointConfig,
+        relay_config: RelayConfig,
     ) -> Result<(Self, Sender<Request>), IoError> {
-        let mut endpoint = if ipv6_endpoint {
+        let RelayConfig {
+            server_addrs,
+            failover_policy,
+            token_digest,
+            udp_mode,
+            heartbeat_interval,
+            heartbeat_jitter,
+            inbound_heartbeat,
+            ipv6_endpoint,
+            reduce_rtt,
+            fail_fast_threshold,
+            cache_server_address,
+            server_address_refresh_interval,
+            authentication_padding,
+        } = relay_config;
+
+        let socket = if ipv6_endpoint {
             let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
 
             socket.set_only_v6(false)?;
@@ -52,107 +99,469 @@ impl Relay {
                 0,
             ))))?;
 
-            Endpoint::new(EndpointConfig::default(), None, UdpSocket::from(socket))?.0
+            UdpSocket::from(socket)
         } else {
-            Endpoint::client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?
+            UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?
         };
 
+        let mut endpoint = Endpoint::new(endpoint_config, None, socket)?.0;
+
         endpoint.set_default_client_config(config);
 
         let (req_tx, req_rx) = mpsc::channel(1);
 
         let relay = Self {
-            req_rx,
+            req_rx: Mutex::new(req_rx),
             endpoint,
-            server_addr,
+            pool: Arc::new(Mutex::new(ConnectionPool::default())),
+            server_health: Mutex::new(ServerHealth::new(server_addrs.len(), failover_policy)),
+            server_addr_cache: cache_server_address.then(|| {
+                Mutex::new(ServerAddrCache::new(
+                    server_addrs.len(),
+                    server_address_refresh_interval,
+                ))
+            }),
+            server_addrs,
             token_digest,
             udp_mode,
             heartbeat_interval,
+            heartbeat_jitter,
+            inbound_heartbeat,
             reduce_rtt,
+            authentication_padding,
+            fail_fast_threshold,
         };
 
         Ok((relay, req_tx))
     }
 
-    pub async fn run(mut self) {
-        log::info!("[relay] started. Target server: {}", self.server_addr);
+    /// The connections of the relay, shared so their status can be queried while it runs
+    pub fn connection_pool(&self) -> Arc<Mutex<ConnectionPool>> {
+        self.pool.clone()
+    }
 
-        let mut task_count = TaskCount::new();
+    /// Relays requests until `shutdown` resolves, then dissociates all UDP sessions and closes the connections to the server
+    pub async fn run<F>(self, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        log::info!("[relay] started. Target server: {}", self.server_addrs[0]);
 
-        let mut conn = self.establish_connection(task_count.clone()).await;
-        log::debug!("[relay] [connection] [establish]");
+        for server_addr in &self.server_addrs[1..] {
+            log::info!("[relay] fallback server: {server_addr}");
+        }
 
-        while let Some(req) = self.req_rx.recv().await {
-            if conn.is_closed() {
+        tokio::pin!(shutdown);
+
+        self.get_connection(self.token_digest).await;
+
+        while let Some(req) = tokio::select! {
+            req = self.recv_request() => req,
+            () = &mut shutdown => None,
+        } {
+            let token_digest = req.token_digest().unwrap_or(self.token_digest);
+            let (conn, task_count) = self.get_connection(token_digest).await;
+            spawn_relay_request(conn, task_count, req);
+        }
+
+        let conns = self
+            .pool
+            .lock()
+            .conns
+            .drain()
+            .map(|(_, (conn, _))| conn)
+            .collect::<Vec<_>>();
+
+        for conn in conns {
+            conn.close().await;
+        }
+
+        self.endpoint.wait_idle().await;
+        log::info!("[relay] stopped");
+    }
+
+    /// The lock is only held while polling, so requests can be received while a connection is being established
+    async fn recv_request(&self) -> Option<Request> {
+        future::poll_fn(|cx| self.req_rx.lock().poll_recv(cx)).await
+    }
+
+    fn pooled_connection(&self, token_digest: &[u8; 32]) -> Option<(Connection, TaskCount)> {
+        match self.pool.lock().conns.get(token_digest) {
+            Some((conn, task_count)) if !conn.is_closed() => {
+                Some((conn.clone(), task_count.clone()))
+            }
+            Some(_) => {
                 log::debug!("[relay] [connection] [disconnect]");
-                task_count = TaskCount::new();
-                conn = self.establish_connection(task_count.clone()).await;
-                log::debug!("[relay] [connection] [establish]");
+                None
             }
+            None => None,
+        }
+    }
+
+    async fn get_connection(&self, token_digest: [u8; 32]) -> (Connection, TaskCount) {
+        if let Some(conn) = self.pooled_connection(&token_digest) {
+            return conn;
+        }
+
+        let task_count = TaskCount::new();
+        self.pool.lock().reconnecting = true;
+
+        let establish = self.establish_connection(token_digest, task_count.clone());
+
+        let conn = match self.fail_fast_threshold {
+            Some(threshold) => self.establish_failing_fast(establish, threshold).await,
+            None => establish.await,
+        };
+
+        log::debug!("[relay] [connection] [establish]");
+
+        let mut pool = self.pool.lock();
+        pool.reconnecting = false;
+        pool.conns
+            .insert(token_digest, (conn.clone(), task_count.clone()));
+        drop(pool);
+
+        (conn, task_count)
+    }
 
-            let conn_cloned = conn.clone();
-            let task_count_cloned = task_count.clone();
+    /// Keeps establishing the connection however long it takes, but once that exceeds `threshold`, requests that would wait for it are refused as they arrive
+    async fn establish_failing_fast(
+        &self,
+        establish: impl Future<Output = Connection>,
+        threshold: Duration,
+    ) -> Connection {
+        tokio::pin!(establish);
 
-            tokio::spawn(async move {
-                match conn_cloned
-                    .process_relay_request(req, task_count_cloned)
+        let outage = time::sleep(threshold);
+        tokio::pin!(outage);
+
+        let mut is_unavailable = false;
+        let mut is_receiving = true;
+
+        loop {
+            tokio::select! {
+                conn = &mut establish => return conn,
+                () = &mut outage, if !is_unavailable => {
+                    log::warn!("[relay] [connection] server unavailable for {} ms, refusing requests until it is connected", threshold.as_millis());
+                    is_unavailable = true;
+                }
+                req = self.recv_request(), if is_unavailable && is_receiving => match req {
+                    Some(req) => {
+                        let token_digest = req.token_digest().unwrap_or(self.token_digest);
+
+                        match self.pooled_connection(&token_digest) {
+                            Some((conn, task_count)) => spawn_relay_request(conn, task_count, req),
+                            None => log::warn!("[relay] [connection] server unavailable, request refused"),
+                        }
+                    }
+                    None => is_receiving = false,
+                },
+            }
+        }
+    }
+
+    async fn establish_connection(
+        &self,
+        token_digest: [u8; 32],
+        task_count: TaskCount,
+    ) -> Connection {
+        let mut backoff = FailoverBackoff::new();
+
+        loop {
+            let order = self.server_health.lock().order();
+
+            for idx in order {
+                let server_addr = &self.server_addrs[idx];
+
+                match self
+                    .connect_server(idx, token_digest, task_count.clone())
                     .await
                 {
-                    Ok(()) => (),
-                    Err(err) => {
-                        log::warn!("[relay] [task] {err}");
+                    Some(conn) => {
+                        if self.server_addrs.len() > 1 {
+                            log::info!("[relay] [connection] connected to {server_addr}");
+                        }
+
+                        self.server_health.lock().mark_connected(idx);
+
+                        if let Some(cache) = &self.server_addr_cache {
+                            cache.lock().mark_connected(idx);
+                        }
+
+                        return conn;
+                    }
+                    None => {
+                        self.server_health.lock().mark_failed(idx);
+
+                        if let Some(cache) = &self.server_addr_cache {
+                            cache.lock().mark_failed(idx);
+                        }
                     }
                 }
-            });
+            }
+
+            backoff.wait().await;
         }
     }
 
-    async fn establish_connection(&self, task_count: TaskCount) -> Connection {
-        let (mut addrs, server_name) = match &self.server_addr {
-            ServerAddr::HostnameAddr { hostname, .. } => (Vec::new(), hostname),
-            ServerAddr::SocketAddr {
-                server_addr,
-                server_name,
-            } => (vec![*server_addr], server_name),
-        };
+    async fn connect_server(
+        &self,
+        idx: usize,
+        token_digest: [u8; 32],
+        task_count: TaskCount,
+    ) -> Option<Connection> {
+        let server_addr = &self.server_addrs[idx];
 
-        loop {
-            if let ServerAddr::HostnameAddr {
+        let resolve_started = Instant::now();
+        let mut resolve_time = None;
+
+        let (addrs, server_name) = match server_addr {
+            ServerAddr::HostnameAddr {
                 hostname,
                 server_port,
-            } = &self.server_addr
+            } => match self
+                .server_addr_cache
+                .as_ref()
+                .and_then(|cache| cache.lock().get(idx))
             {
-                match net::lookup_host((hostname.as_str(), *server_port)).await {
-                    Ok(resolved) => addrs = resolved.collect(),
+                Some(addrs) => (addrs, hostname),
+                None => match net::lookup_host((hostname.as_str(), *server_port)).await {
+                    Ok(resolved) => {
+                        resolve_time = Some(resolve_started.elapsed());
+                        let addrs = resolved.collect::<Vec<_>>();
+                        log::debug!("[relay] [connection] [resolve] {server_addr}: {addrs:?}");
+
+                        if let Some(cache) = &self.server_addr_cache {
+                            cache.lock().insert(idx, addrs.clone());
+                        }
+
+                        (addrs, hostname)
+                    }
                     Err(err) => {
-                        log::error!("[relay] [connection] {err}");
-                        continue;
+                        self.connection_error(err);
+                        return None;
                     }
-                }
-            }
+                },
+            },
+            ServerAddr::SocketAddr {
+                server_addr,
+                server_name,
+            } => (vec![*server_addr], server_name),
+        };
 
-            for addr in &addrs {
-                match self.endpoint.connect(*addr, server_name) {
-                    Ok(conn) => {
-                        match Connection::init(
-                            conn,
-                            self.token_digest,
-                            self.udp_mode,
-                            self.reduce_rtt,
-                        )
-                        .await
-                        {
-                            Ok(conn) => {
-                                conn.start_heartbeat(task_count, self.heartbeat_interval);
-                                return conn;
-                            }
-                            Err(err) => log::error!("[relay] [connection] {err}"),
+        for addr in addrs {
+            match self.endpoint.connect(addr, server_name) {
+                Ok(conn) => {
+                    match Connection::init(
+                        conn,
+                        token_digest,
+                        self.udp_mode,
+                        self.inbound_heartbeat,
+                        self.reduce_rtt,
+                        self.authentication_padding,
+                        resolve_time,
+                    )
+                    .await
+                    {
+                        Ok(conn) => {
+                            conn.start_heartbeat(
+                                task_count,
+                                self.heartbeat_interval,
+                                self.heartbeat_jitter,
+                            );
+                            return Some(conn);
                         }
+                        Err(err) => self.connection_error(err),
                     }
-                    Err(err) => log::error!("[relay] [connection] {err}"),
                 }
+                Err(err) => self.connection_error(err),
             }
         }
+
+        None
+    }
+
+    fn connection_error(&self, err: impl Display) {
+        log::error!("[relay] [connection] {err}");
+        self.pool.lock().last_error = Some(err.to_string());
+    }
+}
+
+fn spawn_relay_request(conn: Connection, task_count: TaskCount, req: Request) {
+    tokio::spawn(async move {
+        match conn.process_relay_request(req, task_count).await {
+            Ok(()) => (),
+            Err(err) => log::warn!("[relay] [task] {err}"),
+        }
+    });
+}
+
+/// The connections to the server, keyed by the token digest they are authenticated with
+#[derive(Default)]
+pub struct ConnectionPool {
+    conns: HashMap<[u8; 32], (Connection, TaskCount)>,
+    reconnecting: bool,
+    last_error: Option<String>,
+}
+
+impl ConnectionPool {
+    pub fn status(&self) -> ClientStatus {
+        ClientStatus {
+            connections: self
+                .conns
+                .values()
+                .filter(|(conn, _)| !conn.is_closed())
+                .map(|(conn, task_count)| conn.status(task_count.in_flight()))
+                .collect(),
+            reconnecting: self.reconnecting,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+/// How heartbeats sent by the server are handled
+#[derive(Clone, Copy)]
+pub enum InboundHeartbeat {
+    Ignore,
+    Respond,
+    ResetTimer,
+}
+
+#[derive(Clone, Copy)]
+pub enum FailoverPolicy {
+    Ordered,
+    RoundRobin,
+}
+
+/// Tracks which servers failed to connect recently, so they are tried after the others
+struct ServerHealth {
+    policy: FailoverPolicy,
+    failures: Vec<Option<Instant>>,
+    next: usize,
+}
+
+impl ServerHealth {
+    const RECOVERY_TIME: Duration = Duration::from_secs(30);
+
+    fn new(count: usize, policy: FailoverPolicy) -> Self {
+        Self {
+            policy,
+            failures: vec![None; count],
+            next: 0,
+        }
+    }
+
+    /// Returns the indices of the servers in the order they should be tried
+    fn order(&self) -> Vec<usize> {
+        let count = self.failures.len();
+
+        let start = match self.policy {
+            FailoverPolicy::Ordered => 0,
+            FailoverPolicy::RoundRobin => self.next,
+        };
+
+        let (mut order, failed): (Vec<_>, Vec<_>) = (0..count)
+            .map(|idx| (start + idx) % count)
+            .partition(|idx| {
+                self.failures[*idx].is_none_or(|since| since.elapsed() >= Self::RECOVERY_TIME)
+            });
+
+        order.extend(failed);
+        order
+    }
+
+    fn mark_failed(&mut self, idx: usize) {
+        self.failures[idx] = Some(Instant::now());
+    }
+
+    fn mark_connected(&mut self, idx: usize) {
+        self.failures[idx] = None;
+        self.next = (idx + 1) % self.failures.len();
+    }
+}
+
+/// Spaces out the rounds over all servers while none of them can be connected, doubling the delay after each round up to a maximum
+struct FailoverBackoff {
+    delay: Duration,
+}
+
+impl FailoverBackoff {
+    const INITIAL_DELAY: Duration = Duration::from_millis(500);
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    fn new() -> Self {
+        Self {
+            delay: Self::INITIAL_DELAY,
+        }
+    }
+
+    async fn wait(&mut self) {
+        log::warn!(
+            "[relay] [connection] no server could be connected, retrying in {} ms",
+            self.delay.as_millis()
+        );
+
+        time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(Self::MAX_DELAY);
+    }
+}
+
+/// The resolved addresses of the servers given by hostname, reused across connections instead of resolving the hostname again for each one
+struct ServerAddrCache {
+    refresh_interval: Option<Duration>,
+    entries: Vec<Option<CachedServerAddrs>>,
+}
+
+struct CachedServerAddrs {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    failures: usize,
+}
+
+impl ServerAddrCache {
+    const MAX_FAILURES: usize = 3;
+
+    fn new(count: usize, refresh_interval: Option<Duration>) -> Self {
+        Self {
+            refresh_interval,
+            entries: (0..count).map(|_| None).collect(),
+        }
+    }
+
+    /// Returns the cached addresses of the server, unless they have to be resolved again
+    fn get(&self, idx: usize) -> Option<Vec<SocketAddr>> {
+        self.entries[idx]
+            .as_ref()
+            .filter(|entry| {
+                self.refresh_interval
+                    .is_none_or(|interval| entry.resolved_at.elapsed() < interval)
+            })
+            .map(|entry| entry.addrs.clone())
+    }
+
+    fn insert(&mut self, idx: usize, addrs: Vec<SocketAddr>) {
+        self.entries[idx] = Some(CachedServerAddrs {
+            addrs,
+            resolved_at: Instant::now(),
+            failures: 0,
+        });
+    }
+
+    /// Drops the cached addresses once connecting to them failed too many times in a row, in case the server has moved
+    fn mark_failed(&mut self, idx: usize) {
+        if let Some(entry) = &mut self.entries[idx] {
+            entry.failures += 1;
+
+            if entry.failures >= Self::MAX_FAILURES {
+                self.entries[idx] = None;
+            }
+        }
+    }
+
+    fn mark_connected(&mut self, idx: usize) {
+        if let Some(entry) = &mut self.entries[idx] {
+            entry.failures = 0;
+        }
     }
 }
 
@@ -197,7 +606,12 @@ impl TaskCount {
     }
 
     pub fn is_zero(&self) -> bool {
-        Arc::strong_count(&self.0) <= 2
+        self.in_flight() == 0
+    }
+
+    /// The number of relay tasks holding the count, besides the connection pool and the heartbeat
+    fn in_flight(&self) -> usize {
+        Arc::strong_count(&self.0).saturating_sub(2)
     }
 }
 
@@ -220,3 +634,172 @@ pub enum RelayError {
     #[error("bad command")]
     BadCommand,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Address, FailoverBackoff, FailoverPolicy, InboundHeartbeat, Relay, RelayConfig, Request,
+        ServerAddr, ServerHealth, UdpMode,
+    };
+    use crate::testing;
+    use futures_util::StreamExt;
+    use quinn::{ClientConfig, Endpoint, EndpointConfig};
+    use rustls::RootCertStore;
+    use std::{
+        future,
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio::time;
+
+    const THRESHOLD: Duration = Duration::from_millis(100);
+
+    fn relay_config(server_addr: SocketAddr) -> RelayConfig {
+        RelayConfig {
+            server_addrs: vec![ServerAddr::SocketAddr {
+                server_addr,
+                server_name: "localhost".to_owned(),
+            }],
+            failover_policy: FailoverPolicy::Ordered,
+            token_digest: [0; 32],
+            udp_mode: UdpMode::Native,
+            heartbeat_interval: 10000,
+            heartbeat_jitter: 0,
+            inbound_heartbeat: InboundHeartbeat::Ignore,
+            ipv6_endpoint: false,
+            reduce_rtt: false,
+            fail_fast_threshold: None,
+            cache_server_address: false,
+            server_address_refresh_interval: None,
+            authentication_padding: None,
+        }
+    }
+
+    #[test]
+    fn failed_servers_are_tried_last() {
+        let mut health = ServerHealth::new(3, FailoverPolicy::Ordered);
+        assert_eq!(health.order(), [0, 1, 2]);
+
+        health.mark_failed(0);
+        assert_eq!(health.order(), [1, 2, 0]);
+
+        health.mark_connected(0);
+        assert_eq!(health.order(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_starts_after_the_last_connected_server() {
+        let mut health = ServerHealth::new(3, FailoverPolicy::RoundRobin);
+        assert_eq!(health.order(), [0, 1, 2]);
+
+        health.mark_connected(0);
+        assert_eq!(health.order(), [1, 2, 0]);
+
+        // the next server failed recently, so it is moved to the end
+        health.mark_failed(2);
+        health.mark_connected(1);
+        assert_eq!(health.order(), [0, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failover_rounds_back_off_up_to_the_cap() {
+        let mut backoff = FailoverBackoff::new();
+        let mut delays = Vec::new();
+
+        for _ in 0..9 {
+            let started = time::Instant::now();
+            backoff.wait().await;
+            delays.push(started.elapsed().as_millis());
+        }
+
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_fail_fast_while_the_connection_is_still_attempted() {
+        // a server that never answers, so the handshake is still in progress when the threshold elapses
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let (relay, req_tx) = Relay::init(
+            ClientConfig::with_root_certificates(RootCertStore::empty()),
+            EndpointConfig::default(),
+            RelayConfig {
+                fail_fast_threshold: Some(THRESHOLD),
+                ..relay_config(server.local_addr().unwrap())
+            },
+        )
+        .unwrap();
+
+        let pool = relay.connection_pool();
+        tokio::spawn(relay.run(future::pending()));
+
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let started = Instant::now();
+
+        for _ in 0..3 {
+            let (req, resp_rx) = Request::new_connect(Address::SocketAddress(addr), None, false);
+            req_tx.send(req).await.unwrap();
+            assert!(resp_rx.await.is_err());
+        }
+
+        assert!(started.elapsed() >= THRESHOLD);
+        assert!(started.elapsed() < THRESHOLD * 10);
+        assert!(pool.lock().status().reconnecting);
+    }
+
+    #[tokio::test]
+    async fn requests_during_a_cold_start_share_one_connection() {
+        let (server, mut incoming) = Endpoint::server(
+            testing::server_config(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        )
+        .unwrap();
+
+        let established = Arc::new(AtomicUsize::new(0));
+        let server_established = established.clone();
+
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+
+            while let Some(conn) = incoming.next().await {
+                server_established.fetch_add(1, Ordering::Relaxed);
+                conns.push(conn.await.unwrap());
+            }
+        });
+
+        let (relay, req_tx) = Relay::init(
+            testing::client_config(),
+            EndpointConfig::default(),
+            relay_config(server.local_addr().unwrap()),
+        )
+        .unwrap();
+
+        // all requests are queued before the relay starts, so none of them finds a pooled connection
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let resp_rxs = (0..8)
+            .map(|_| {
+                let (req, resp_rx) = Request::new_connect(Address::SocketAddress(addr), None, true);
+                let req_tx = req_tx.clone();
+                tokio::spawn(async move { req_tx.send(req).await.unwrap() });
+                resp_rx
+            })
+            .collect::<Vec<_>>();
+
+        tokio::spawn(relay.run(future::pending()));
+
+        for resp_rx in resp_rxs {
+            assert!(resp_rx.await.unwrap().is_ok());
+        }
+
+        // leaves time for any other establishment to reach the server
+        time::sleep(THRESHOLD).await;
+        assert_eq!(established.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use super::Address;
+use super::{Address, CloseReason};
 use bytes::Bytes;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
@@ -8,9 +8,12 @@ use tokio::sync::{
     mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender},
     oneshot::{self, Receiver as OneshotReceiver, Sender as OneshotSender},
 };
+use tuic_protocol::Command as TuicCommand;
 
-type ConnectResponseSender = OneshotSender<Option<(SendStream, RecvStream)>>;
-type ConnectResponseReceiver = OneshotReceiver<Option<(SendStream, RecvStream)>>;
+type ConnectResponseSender = OneshotSender<Result<(SendStream, RecvStream), ConnectError>>;
+type ConnectResponseReceiver = OneshotReceiver<Result<(SendStream, RecvStream), ConnectError>>;
+type AssociateResponseSender = OneshotSender<bool>;
+type AssociateResponseReceiver = OneshotReceiver<bool>;
 type AssociateSendPacketSender = MpscSender<(Bytes, Address)>;
 type AssociateSendPacketReceiver = MpscReceiver<(Bytes, Address)>;
 type AssociateRecvPacketSender = MpscSender<(Bytes, Address)>;
@@ -20,35 +23,87 @@ pub enum Request {
     Connect {
         addr: Address,
         tx: ConnectResponseSender,
+        token_digest: Option<[u8; 32]>,
+        optimistic: bool,
     },
     Associate {
         assoc_id: u32,
+        tx: AssociateResponseSender,
         pkt_send_rx: AssociateSendPacketReceiver,
         pkt_receive_tx: AssociateRecvPacketSender,
+        token_digest: Option<[u8; 32]>,
     },
 }
 
+/// Why a `Connect` request was not relayed
+pub enum ConnectError {
+    /// The server could not connect to the target
+    Refused,
+    /// The request failed on the connection to the server, with the reason if the server closed it
+    Relay(Option<CloseReason>),
+}
+
 impl Request {
-    pub fn new_connect(addr: Address) -> (Self, ConnectResponseReceiver) {
+    pub fn new_connect(
+        addr: Address,
+        token_digest: Option<[u8; 32]>,
+        optimistic: bool,
+    ) -> (Self, ConnectResponseReceiver) {
         let (tx, rx) = oneshot::channel();
-        (Request::Connect { addr, tx }, rx)
+
+        (
+            Request::Connect {
+                addr,
+                tx,
+                token_digest,
+                optimistic,
+            },
+            rx,
+        )
     }
 
-    pub fn new_associate() -> (Self, AssociateSendPacketSender, AssociateRecvPacketReceiver) {
+    pub fn new_associate(
+        token_digest: Option<[u8; 32]>,
+    ) -> (
+        Self,
+        AssociateResponseReceiver,
+        AssociateSendPacketSender,
+        AssociateRecvPacketReceiver,
+    ) {
         let assoc_id = get_random_u32();
+        let (tx, rx) = oneshot::channel();
         let (pkt_send_tx, pkt_send_rx) = mpsc::channel(1);
         let (pkt_receive_tx, pkt_receive_rx) = mpsc::channel(1);
 
         (
             Self::Associate {
                 assoc_id,
+                tx,
                 pkt_send_rx,
                 pkt_receive_tx,
+                token_digest,
             },
+            rx,
             pkt_send_tx,
             pkt_receive_rx,
         )
     }
+
+    pub fn token_digest(&self) -> Option<[u8; 32]> {
+        match self {
+            Self::Connect { token_digest, .. } | Self::Associate { token_digest, .. } => {
+                *token_digest
+            }
+        }
+    }
+}
+
+/// Reads the server's `Response` to an optimistically relayed `Connect`, returning whether the target is connected
+pub async fn read_connect_response(recv: &mut RecvStream) -> bool {
+    matches!(
+        TuicCommand::read_from(recv).await,
+        Ok(TuicCommand::Response(true))
+    )
 }
 
 static RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| Mutex::new(StdRng::from_entropy()));
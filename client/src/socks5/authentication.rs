@@ -1,18 +1,21 @@
 use super::protocol::HandshakeMethod;
+use std::collections::HashMap;
 
 pub enum Authentication {
     None,
-    Password {
-        username: Vec<u8>,
-        password: Vec<u8>,
-    },
+    Password(HashMap<Vec<u8>, Credential>),
+}
+
+pub struct Credential {
+    pub password: Vec<u8>,
+    pub token_digest: Option<[u8; 32]>,
 }
 
 impl Authentication {
     pub fn as_handshake_method(&self) -> HandshakeMethod {
         match self {
             Authentication::None => HandshakeMethod::None,
-            Authentication::Password { .. } => HandshakeMethod::Password,
+            Authentication::Password(_) => HandshakeMethod::Password,
         }
     }
 }
@@ -0,0 +1,214 @@
+use super::{destination::DestinationKey, protocol::Address};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Fails connect requests to a destination locally after consecutive failures, until a request relayed after the cool-down succeeds
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    destinations: Mutex<HashMap<String, CircuitState>>,
+}
+
+enum CircuitState {
+    Closed {
+        failures: usize,
+        last_failure: Instant,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            destinations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a guard to record the result of connecting to the destination, or `None` if the request should fail locally
+    pub fn try_pass(self: &Arc<Self>, addr: &Address) -> Option<CircuitGuard> {
+        let key = DestinationKey::HostPort.of(addr);
+        let mut dsts = self.destinations.lock();
+
+        let is_probe = match dsts.get(&key) {
+            Some(CircuitState::Open { until }) if *until <= Instant::now() => true,
+            Some(CircuitState::Open { .. }) | Some(CircuitState::HalfOpen) => return None,
+            Some(CircuitState::Closed { .. }) | None => false,
+        };
+
+        if is_probe {
+            log::info!("[socks5] [circuit-breaker] [{key}] half-open, testing the destination");
+            dsts.insert(key.clone(), CircuitState::HalfOpen);
+        }
+
+        Some(CircuitGuard {
+            breaker: self.clone(),
+            key,
+            is_probe,
+            is_finished: false,
+        })
+    }
+
+    fn finish(&self, key: &str, is_probe: bool, is_connected: Option<bool>) {
+        let mut dsts = self.destinations.lock();
+        let now = Instant::now();
+
+        match is_connected {
+            Some(true) => {
+                if is_probe {
+                    log::info!(
+                        "[socks5] [circuit-breaker] [{key}] closed, the destination recovered"
+                    );
+                }
+
+                dsts.remove(key);
+            }
+            Some(false) => {
+                let failures = match dsts.get(key) {
+                    Some(CircuitState::Closed {
+                        failures,
+                        last_failure,
+                    }) if now.duration_since(*last_failure) < self.cooldown => failures + 1,
+                    Some(CircuitState::Open { .. }) => return,
+                    Some(CircuitState::HalfOpen) if !is_probe => return,
+                    Some(CircuitState::HalfOpen) => self.threshold,
+                    Some(CircuitState::Closed { .. }) | None => 1,
+                };
+
+                if failures >= self.threshold {
+                    if is_probe {
+                        log::warn!("[socks5] [circuit-breaker] [{key}] open again for {:?}, the destination is still failing", self.cooldown);
+                    } else {
+                        log::warn!("[socks5] [circuit-breaker] [{key}] open for {:?} after {failures} consecutive failures", self.cooldown);
+                    }
+
+                    dsts.insert(
+                        key.to_owned(),
+                        CircuitState::Open {
+                            until: now + self.cooldown,
+                        },
+                    );
+                } else {
+                    dsts.insert(
+                        key.to_owned(),
+                        CircuitState::Closed {
+                            failures,
+                            last_failure: now,
+                        },
+                    );
+                }
+            }
+            // the probe ended without reaching the destination, so the next request tests it instead
+            None if is_probe => {
+                dsts.insert(key.to_owned(), CircuitState::Open { until: now });
+            }
+            None => {}
+        }
+    }
+}
+
+pub struct CircuitGuard {
+    breaker: Arc<CircuitBreaker>,
+    key: String,
+    is_probe: bool,
+    is_finished: bool,
+}
+
+impl CircuitGuard {
+    /// Records whether the destination was connected. Dropping the guard without finishing it records nothing
+    pub fn finish(mut self, is_connected: bool) {
+        self.breaker
+            .finish(&self.key, self.is_probe, Some(is_connected));
+        self.is_finished = true;
+    }
+}
+
+impl Drop for CircuitGuard {
+    fn drop(&mut self) {
+        if !self.is_finished {
+            self.breaker.finish(&self.key, self.is_probe, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CircuitBreaker;
+    use crate::socks5::protocol::Address;
+    use std::{sync::Arc, thread, time::Duration};
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn addr() -> Address {
+        Address::HostnameAddress("example.com".to_owned(), 443)
+    }
+
+    fn fail(breaker: &Arc<CircuitBreaker>) {
+        breaker.try_pass(&addr()).unwrap().finish(false);
+    }
+
+    #[test]
+    fn consecutive_failures_open_the_circuit() {
+        let breaker = Arc::new(CircuitBreaker::new(2, COOLDOWN));
+
+        fail(&breaker);
+        assert!(breaker.try_pass(&addr()).is_some());
+        fail(&breaker);
+        assert!(breaker.try_pass(&addr()).is_none());
+
+        // other destinations are not affected
+        assert!(breaker
+            .try_pass(&Address::HostnameAddress("example.com".to_owned(), 80))
+            .is_some());
+    }
+
+    #[test]
+    fn a_success_resets_the_failures() {
+        let breaker = Arc::new(CircuitBreaker::new(2, COOLDOWN));
+
+        fail(&breaker);
+        breaker.try_pass(&addr()).unwrap().finish(true);
+        fail(&breaker);
+        assert!(breaker.try_pass(&addr()).is_some());
+    }
+
+    #[test]
+    fn one_probe_is_let_through_after_the_cooldown() {
+        let breaker = Arc::new(CircuitBreaker::new(1, COOLDOWN));
+
+        fail(&breaker);
+        assert!(breaker.try_pass(&addr()).is_none());
+        thread::sleep(COOLDOWN);
+
+        let probe = breaker.try_pass(&addr()).unwrap();
+        assert!(breaker.try_pass(&addr()).is_none());
+
+        // a failing probe opens the circuit again, a successful one closes it
+        probe.finish(false);
+        assert!(breaker.try_pass(&addr()).is_none());
+        thread::sleep(COOLDOWN);
+
+        breaker.try_pass(&addr()).unwrap().finish(true);
+        assert!(breaker.try_pass(&addr()).is_some());
+        assert!(breaker.try_pass(&addr()).is_some());
+    }
+
+    #[test]
+    fn an_unfinished_probe_lets_the_next_request_probe() {
+        let breaker = Arc::new(CircuitBreaker::new(1, COOLDOWN));
+
+        fail(&breaker);
+        thread::sleep(COOLDOWN);
+
+        drop(breaker.try_pass(&addr()).unwrap());
+        assert!(breaker.try_pass(&addr()).is_some());
+    }
+}
@@ -7,36 +7,68 @@ use crate::{
     },
 };
 use bytes::{Bytes, BytesMut};
+use socket2::{SockRef, TcpKeepalive};
 use std::{
+    io::ErrorKind,
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
     io::AsyncReadExt,
     net::{TcpStream, UdpSocket},
     sync::mpsc::{Receiver, Sender},
+    time,
 };
 
+const HALF_CLOSED_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
 impl Connection {
     pub async fn handle_associate(
         mut self,
         ctrl_addr: SocketAddr,
         max_udp_pkt_size: usize,
+        keep_half_closed: bool,
     ) -> Result<(), Socks5Error> {
         match create_udp_socket(self.local_addr.ip()).await {
             Ok((socket, socket_addr)) => {
                 let socket = Arc::new(socket);
 
+                let (relay_req, relay_resp_rx, pkt_send_tx, pkt_receive_rx) =
+                    RelayRequest::new_associate(self.token_digest);
+                let _ = self.req_tx.send(relay_req).await;
+
+                match relay_resp_rx.await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let resp = Response::new(
+                            Reply::CommandNotSupported,
+                            Address::SocketAddress(self.local_addr),
+                        );
+
+                        resp.write_to(&mut self.stream).await?;
+
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        let resp = Response::new(
+                            Reply::GeneralFailure,
+                            Address::SocketAddress(self.local_addr),
+                        );
+
+                        resp.write_to(&mut self.stream).await?;
+
+                        return Err(Socks5Error::RelayConnectivity);
+                    }
+                }
+
                 let resp = Response::new(Reply::Succeeded, Address::SocketAddress(socket_addr));
                 resp.write_to(&mut self.stream).await?;
 
-                let (relay_req, pkt_send_tx, pkt_receive_rx) = RelayRequest::new_associate();
-                let _ = self.req_tx.send(relay_req).await;
-
                 let res = tokio::select! {
                     res = listen_packet_to_relay(socket.clone(), ctrl_addr, max_udp_pkt_size, pkt_send_tx) => res,
                     res = listen_packet_from_relay(socket, ctrl_addr, pkt_receive_rx) => res,
-                    () = listen_control_stream(self.stream) => Ok(()),
+                    () = listen_control_stream(self.stream, ctrl_addr, keep_half_closed) => Ok(()),
                 };
 
                 match res {
@@ -74,8 +106,15 @@ async fn listen_packet_to_relay(
     pkt_send_tx: Sender<(Bytes, RelayAddress)>,
 ) -> Result<(), Socks5Error> {
     loop {
-        let mut buf = vec![0; max_udp_pkt_size];
+        // one extra byte tells oversized packets apart from the ones that fit exactly
+        let mut buf = vec![0; max_udp_pkt_size + 1];
         let (len, addr) = socket.recv_from(&mut buf).await?;
+
+        if len > max_udp_pkt_size {
+            log::warn!("[socks5] [{ctrl_addr}] [associate] [packet-to] packet exceeds the maximum UDP packet size of {max_udp_pkt_size} bytes, dropped");
+            continue;
+        }
+
         buf.truncate(len);
         let pkt = Bytes::from(buf);
 
@@ -91,8 +130,14 @@ async fn listen_packet_to_relay(
     }
 
     loop {
-        let mut buf = vec![0; max_udp_pkt_size];
+        let mut buf = vec![0; max_udp_pkt_size + 1];
         let len = socket.recv(&mut buf).await?;
+
+        if len > max_udp_pkt_size {
+            log::warn!("[socks5] [{ctrl_addr}] [associate] [packet-to] packet exceeds the maximum UDP packet size of {max_udp_pkt_size} bytes, dropped");
+            continue;
+        }
+
         buf.truncate(len);
         let pkt = Bytes::from(buf);
 
@@ -120,8 +165,38 @@ async fn listen_packet_from_relay(
     Err(Socks5Error::RelayConnectivity)
 }
 
-async fn listen_control_stream(mut stream: TcpStream) {
-    while stream.read_u8().await.is_ok() {}
+async fn listen_control_stream(
+    mut stream: TcpStream,
+    ctrl_addr: SocketAddr,
+    keep_half_closed: bool,
+) {
+    loop {
+        match stream.read_u8().await {
+            Ok(_) => {}
+            Err(err) if keep_half_closed && err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(_) => return,
+        }
+    }
+
+    log::debug!("[socks5] [{ctrl_addr}] [associate] control connection half-closed");
+
+    // after the application stopped writing, a full close can only be noticed through a reset in reply to a keepalive probe
+    let keepalive = TcpKeepalive::new().with_time(HALF_CLOSED_KEEPALIVE_INTERVAL);
+
+    if let Err(err) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+        log::warn!("[socks5] [{ctrl_addr}] [associate] {err}");
+        return;
+    }
+
+    let mut interval = time::interval(HALF_CLOSED_KEEPALIVE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if !matches!(stream.take_error(), Ok(None)) {
+            return;
+        }
+    }
 }
 
 async fn process_packet_to_relay(pkt: Bytes) -> Result<(Bytes, RelayAddress), Socks5Error> {
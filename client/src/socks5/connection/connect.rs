@@ -1,44 +1,126 @@
 use super::Connection;
 use crate::{
-    relay::{Address as RelayAddress, Request as RelayRequest},
+    relay::{self, Address as RelayAddress, CloseReason, ConnectError, Request as RelayRequest},
     socks5::{
+        circuit::CircuitGuard,
         protocol::{Address, Reply, Response},
         Socks5Error,
     },
 };
-use tokio::io;
+use quinn::{RecvStream, SendStream, VarInt};
+use std::net::SocketAddr;
+use tokio::io::{self, BufReader};
 
 impl Connection {
-    pub async fn handle_connect(mut self, addr: Address) -> Result<(), Socks5Error> {
-        let addr = RelayAddress::from(addr);
-        let (relay_req, relay_resp_rx) = RelayRequest::new_connect(addr);
+    pub async fn handle_connect(
+        mut self,
+        src_addr: SocketAddr,
+        addr: Address,
+        tcp_chunk_size: usize,
+        optimistic: bool,
+        mut circuit_guard: Option<CircuitGuard>,
+    ) -> Result<(), Socks5Error> {
+        let mut relay_resp = self.request_connect(&addr, optimistic).await;
 
-        let _ = self.req_tx.send(relay_req).await;
-        let relay_resp = relay_resp_rx
-            .await
-            .map_err(|_| Socks5Error::RelayConnectivity)?;
-
-        if let Some((mut remote_send, mut remote_recv)) = relay_resp {
-            let resp = Response::new(Reply::Succeeded, Address::SocketAddress(self.local_addr));
-            resp.write_to(&mut self.stream).await?;
-
-            let (mut local_recv, mut local_send) = self.stream.split();
-            let remote_to_local = io::copy(&mut remote_recv, &mut local_send);
-            let local_to_remote = io::copy(&mut local_recv, &mut remote_send);
-
-            tokio::select! {
-                _ = remote_to_local => {},
-                _ = local_to_remote => {},
-            };
-        } else {
-            let resp = Response::new(
-                Reply::NetworkUnreachable,
-                Address::SocketAddress(self.local_addr),
-            );
-
-            resp.write_to(&mut self.stream).await?;
+        // the server closed the connection at the end of its lifetime, so the request never reached the target
+        if let Some(Err(ConnectError::Relay(Some(CloseReason::LifetimeExpired)))) = relay_resp {
+            log::debug!("[socks5] [{src_addr}] [connect] [{addr}] connection lifetime expired, retrying on a new connection");
+            relay_resp = self.request_connect(&addr, optimistic).await;
+        }
+
+        let relay_resp = match relay_resp {
+            Some(resp) => resp,
+            None => {
+                let resp = Response::new(
+                    Reply::GeneralFailure,
+                    Address::SocketAddress(self.local_addr),
+                );
+
+                resp.write_to(&mut self.stream).await?;
+                return Err(Socks5Error::RelayConnectivity);
+            }
+        };
+
+        match relay_resp {
+            Ok((mut remote_send, mut remote_recv)) => {
+                if !optimistic {
+                    if let Some(guard) = circuit_guard.take() {
+                        guard.finish(true);
+                    }
+                }
+
+                let resp = Response::new(Reply::Succeeded, Address::SocketAddress(self.local_addr));
+                resp.write_to(&mut self.stream).await?;
+
+                let (local_recv, mut local_send) = self.stream.split();
+                let mut local_recv = BufReader::with_capacity(tcp_chunk_size, local_recv);
+
+                // in optimistic mode, local data is already being relayed while the `Response` is pending
+                let remote_to_local = async {
+                    if optimistic {
+                        let is_connected = relay::read_connect_response(&mut remote_recv).await;
+
+                        if let Some(guard) = circuit_guard {
+                            guard.finish(is_connected);
+                        }
+
+                        if !is_connected {
+                            return false;
+                        }
+                    }
+
+                    let mut remote_recv =
+                        BufReader::with_capacity(tcp_chunk_size, &mut remote_recv);
+                    let _ = io::copy_buf(&mut remote_recv, &mut local_send).await;
+                    true
+                };
+
+                let local_to_remote = io::copy_buf(&mut local_recv, &mut remote_send);
+
+                let connected = tokio::select! {
+                    connected = remote_to_local => connected,
+                    _ = local_to_remote => true,
+                };
+
+                if !connected {
+                    let _ = remote_send.reset(VarInt::from_u32(0));
+                    return Err(Socks5Error::ConnectFailed);
+                }
+            }
+            Err(err) => {
+                let reply = match err {
+                    ConnectError::Refused | ConnectError::Relay(None) => Reply::NetworkUnreachable,
+                    ConnectError::Relay(Some(reason)) => {
+                        log::warn!("[socks5] [{src_addr}] [connect] [{addr}] the server closed the connection: {reason}");
+                        Reply::from(reason)
+                    }
+                };
+
+                let resp = Response::new(reply, Address::SocketAddress(self.local_addr));
+
+                if let Some(guard) = circuit_guard {
+                    guard.finish(false);
+                }
+
+                resp.write_to(&mut self.stream).await?;
+            }
         }
 
         Ok(())
     }
+
+    async fn request_connect(
+        &self,
+        addr: &Address,
+        optimistic: bool,
+    ) -> Option<Result<(SendStream, RecvStream), ConnectError>> {
+        let (relay_req, relay_resp_rx) = RelayRequest::new_connect(
+            RelayAddress::from(addr.clone()),
+            self.token_digest,
+            optimistic,
+        );
+
+        let _ = self.req_tx.send(relay_req).await;
+        relay_resp_rx.await.ok()
+    }
 }
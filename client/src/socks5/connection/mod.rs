@@ -1,4 +1,6 @@
 use super::{
+    circuit::CircuitBreaker,
+    destination::DestinationLimiter,
     protocol::{
         handshake::password::{Request as PasswordAuthRequest, Response as PasswordAuthResponse},
         Address, Command, Error as ProtocolError, HandshakeMethod, HandshakeRequest,
@@ -13,10 +15,24 @@ use tokio::{net::TcpStream, sync::mpsc::Sender};
 mod associate;
 mod connect;
 
+/// The settings and state shared by the local connections
+pub struct ConnectionConfig {
+    pub local_addr: SocketAddr,
+    pub authentication: Authentication,
+    pub disable_udp: bool,
+    pub keep_half_closed_associations: bool,
+    pub destination_limiter: Option<Arc<DestinationLimiter>>,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub max_udp_packet_size: usize,
+    pub tcp_chunk_size: usize,
+    pub optimistic_connect: bool,
+}
+
 pub struct Connection {
     stream: TcpStream,
     local_addr: SocketAddr,
-    auth: Arc<Authentication>,
+    config: Arc<ConnectionConfig>,
+    token_digest: Option<[u8; 32]>,
     req_tx: Sender<RelayRequest>,
 }
 
@@ -24,34 +40,96 @@ impl Connection {
     pub async fn handle(
         conn: TcpStream,
         src_addr: SocketAddr,
-        local_addr: SocketAddr,
-        auth: Arc<Authentication>,
-        max_udp_pkt_size: usize,
+        config: Arc<ConnectionConfig>,
         req_tx: Sender<RelayRequest>,
     ) -> Result<(), Socks5Error> {
         log::debug!("[socks5] [{src_addr}] [establish]");
 
         let mut conn = Self {
             stream: conn,
-            local_addr,
-            auth,
+            local_addr: config.local_addr,
+            config: config.clone(),
+            token_digest: None,
             req_tx,
         };
 
-        conn.handshake().await?;
+        conn.handshake(src_addr).await?;
         log::debug!("[socks5] [{src_addr}] [handshake]");
 
         match Request::read_from(&mut conn.stream).await {
             Ok(req) => match req.command {
+                Command::Connect if !req.address.is_connectable() => {
+                    log::warn!(
+                        "[socks5] [{src_addr}] [connect] invalid target: {}",
+                        req.address
+                    );
+
+                    let resp = Response::new(
+                        Reply::ConnectionNotAllowed,
+                        Address::SocketAddress(conn.local_addr),
+                    );
+                    resp.write_to(&mut conn.stream).await?;
+                }
                 Command::Connect => {
                     log::info!("[socks5] [{src_addr}] [connect] [{}]", req.address);
-                    conn.handle_connect(req.address).await?
+
+                    let circuit_guard = match &config.circuit_breaker {
+                        Some(breaker) => breaker.try_pass(&req.address).map(Some),
+                        None => Some(None),
+                    };
+
+                    match circuit_guard {
+                        Some(circuit_guard) => {
+                            let _guard = match &config.destination_limiter {
+                                Some(limiter) => match limiter.try_acquire(&req.address) {
+                                    Ok(guard) => Some(guard),
+                                    Err(pending) => {
+                                        log::debug!("[socks5] [{src_addr}] [connect] [{}] waiting for a free slot to the destination", req.address);
+                                        Some(pending.acquire().await)
+                                    }
+                                },
+                                None => None,
+                            };
+
+                            conn.handle_connect(
+                                src_addr,
+                                req.address,
+                                config.tcp_chunk_size,
+                                config.optimistic_connect,
+                                circuit_guard,
+                            )
+                            .await?;
+                        }
+                        None => {
+                            log::warn!("[socks5] [{src_addr}] [connect] [{}] the destination is failing, request failed locally", req.address);
+
+                            let resp = Response::new(
+                                Reply::HostUnreachable,
+                                Address::SocketAddress(conn.local_addr),
+                            );
+                            resp.write_to(&mut conn.stream).await?;
+                        }
+                    }
+                }
+                Command::Associate if config.disable_udp => {
+                    log::warn!("[socks5] [{src_addr}] [associate] UDP relaying is disabled");
+
+                    let resp = Response::new(
+                        Reply::CommandNotSupported,
+                        Address::SocketAddress(conn.local_addr),
+                    );
+                    resp.write_to(&mut conn.stream).await?;
                 }
                 Command::Associate => {
                     let req_addr = req.address.to_string();
                     log::info!("[socks5] [{src_addr}] [associate] [{req_addr}]");
 
-                    conn.handle_associate(src_addr, max_udp_pkt_size).await?;
+                    conn.handle_associate(
+                        src_addr,
+                        config.max_udp_packet_size,
+                        config.keep_half_closed_associations,
+                    )
+                    .await?;
 
                     log::info!("[socks5] [{src_addr}] [dissociate] [{req_addr}]");
                 }
@@ -86,20 +164,40 @@ impl Connection {
         Ok(())
     }
 
-    async fn handshake(&mut self) -> Result<(), Socks5Error> {
-        let method = self.auth.as_handshake_method();
+    async fn handshake(&mut self, src_addr: SocketAddr) -> Result<(), Socks5Error> {
+        let method = self.config.authentication.as_handshake_method();
         let req = HandshakeRequest::read_from(&mut self.stream).await?;
 
-        if req.methods.contains(&method) {
+        let selected = if req.methods.contains(&method) {
+            method
+        } else {
+            HandshakeMethod::Unacceptable
+        };
+
+        log::debug!(
+            "[socks5] [{src_addr}] [handshake] offered: [{}], selected: {selected}",
+            req.methods
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        if selected != HandshakeMethod::Unacceptable {
             let resp = HandshakeResponse::new(method);
             resp.write_to(&mut self.stream).await?;
 
-            match self.auth.as_ref() {
+            match &self.config.authentication {
                 Authentication::None => {}
-                Authentication::Password { username, password } => {
+                Authentication::Password(credentials) => {
                     let req = PasswordAuthRequest::read_from(&mut self.stream).await?;
 
-                    if (&req.username, &req.password) == (username, password) {
+                    if let Some(cred) = credentials
+                        .get(&req.username)
+                        .filter(|cred| cred.password == req.password)
+                    {
+                        self.token_digest = cred.token_digest;
+
                         let resp = PasswordAuthResponse::new(true);
                         resp.write_to(&mut self.stream).await?;
                     } else {
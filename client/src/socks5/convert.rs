@@ -1,5 +1,5 @@
-use super::protocol::Address;
-use crate::relay::Address as RelayAddress;
+use super::protocol::{Address, Reply};
+use crate::relay::{Address as RelayAddress, CloseReason};
 
 impl From<RelayAddress> for Address {
     fn from(address: RelayAddress) -> Self {
@@ -18,3 +18,111 @@ impl From<Address> for RelayAddress {
         }
     }
 }
+
+/// The reply to a `Connect` that failed because the server closed the connection
+impl From<CloseReason> for Reply {
+    fn from(reason: CloseReason) -> Self {
+        match reason {
+            CloseReason::AuthenticationFailed
+            | CloseReason::AuthenticationTimeout
+            | CloseReason::Unauthenticated
+            | CloseReason::TooManyMigrations => Self::ConnectionNotAllowed,
+            CloseReason::Protocol
+            | CloseReason::BadCommand
+            | CloseReason::QuotaExceeded
+            | CloseReason::UnexpectedResponse
+            | CloseReason::AuthenticationInDatagram
+            | CloseReason::TruncatedCommand => Self::GeneralFailure,
+            CloseReason::LifetimeExpired | CloseReason::Other(_) => Self::NetworkUnreachable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reply;
+    use crate::relay::CloseReason;
+    use quinn::VarInt;
+    use tuic_protocol::CloseCode;
+
+    #[test]
+    fn close_codes_are_replied_to_by_reason() {
+        let cases = [
+            (
+                CloseCode::Protocol,
+                CloseReason::Protocol,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::AuthenticationFailed,
+                CloseReason::AuthenticationFailed,
+                Reply::ConnectionNotAllowed,
+            ),
+            (
+                CloseCode::AuthenticationTimeout,
+                CloseReason::AuthenticationTimeout,
+                Reply::ConnectionNotAllowed,
+            ),
+            (
+                CloseCode::BadCommand,
+                CloseReason::BadCommand,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::Unauthenticated,
+                CloseReason::Unauthenticated,
+                Reply::ConnectionNotAllowed,
+            ),
+            (
+                CloseCode::QuotaExceeded,
+                CloseReason::QuotaExceeded,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::TooManyMigrations,
+                CloseReason::TooManyMigrations,
+                Reply::ConnectionNotAllowed,
+            ),
+            (
+                CloseCode::UnexpectedResponse,
+                CloseReason::UnexpectedResponse,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::AuthenticationInDatagram,
+                CloseReason::AuthenticationInDatagram,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::TruncatedCommand,
+                CloseReason::TruncatedCommand,
+                Reply::GeneralFailure,
+            ),
+            (
+                CloseCode::LifetimeExpired,
+                CloseReason::LifetimeExpired,
+                Reply::NetworkUnreachable,
+            ),
+        ];
+
+        for (code, reason, reply) in cases {
+            let decoded = CloseReason::from_code(VarInt::from_u32(code.as_u32()));
+            assert_eq!(decoded, reason);
+            assert_eq!(Reply::from(decoded).as_u8(), reply.as_u8(), "{reason}");
+        }
+    }
+
+    #[test]
+    fn unknown_close_codes_are_replied_to_as_unreachable() {
+        for code in [0, 0xffffffef, 0xfffffffb] {
+            let code = VarInt::from_u32(code);
+            let decoded = CloseReason::from_code(code);
+
+            assert_eq!(decoded, CloseReason::Other(code));
+            assert_eq!(
+                Reply::from(decoded).as_u8(),
+                Reply::NetworkUnreachable.as_u8()
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use super::protocol::Address;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone, Copy)]
+pub enum DestinationKey {
+    Host,
+    HostPort,
+}
+
+impl DestinationKey {
+    pub(super) fn of(self, addr: &Address) -> String {
+        match (self, addr) {
+            (Self::Host, Address::HostnameAddress(hostname, _)) => hostname.to_ascii_lowercase(),
+            (Self::Host, Address::SocketAddress(addr)) => addr.ip().to_string(),
+            (Self::HostPort, Address::HostnameAddress(hostname, port)) => {
+                format!("{}:{port}", hostname.to_ascii_lowercase())
+            }
+            (Self::HostPort, Address::SocketAddress(addr)) => addr.to_string(),
+        }
+    }
+}
+
+pub struct DestinationLimiter {
+    max_connections: usize,
+    key: DestinationKey,
+    destinations: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl DestinationLimiter {
+    pub fn new(max_conns: usize, key: DestinationKey) -> Self {
+        Self {
+            max_connections: max_conns,
+            key,
+            destinations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a guard if the destination has a free slot, otherwise the semaphore to wait on
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        addr: &Address,
+    ) -> Result<DestinationGuard, PendingDestination> {
+        let key = self.key.of(addr);
+
+        let semaphore = self
+            .destinations
+            .lock()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)))
+            .clone();
+
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(DestinationGuard {
+                limiter: self.clone(),
+                key,
+                permit: Some(permit),
+            }),
+            Err(_) => Err(PendingDestination {
+                limiter: self.clone(),
+                key,
+                semaphore: Some(semaphore),
+            }),
+        }
+    }
+
+    fn release(&self, key: &str) {
+        let mut dsts = self.destinations.lock();
+
+        // only the map still holds the semaphore, so nothing is connected or waiting
+        if dsts
+            .get(key)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            dsts.remove(key);
+        }
+    }
+}
+
+pub struct PendingDestination {
+    limiter: Arc<DestinationLimiter>,
+    key: String,
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl PendingDestination {
+    pub async fn acquire(self) -> DestinationGuard {
+        // the semaphore is never closed
+        let semaphore = self.semaphore.clone().unwrap();
+        let permit = semaphore.acquire_owned().await.unwrap();
+
+        DestinationGuard {
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            permit: Some(permit),
+        }
+    }
+}
+
+impl Drop for PendingDestination {
+    fn drop(&mut self) {
+        drop(self.semaphore.take());
+        self.limiter.release(&self.key);
+    }
+}
+
+pub struct DestinationGuard {
+    limiter: Arc<DestinationLimiter>,
+    key: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for DestinationGuard {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        self.limiter.release(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DestinationKey, DestinationLimiter};
+    use crate::socks5::protocol::Address;
+    use std::{net::SocketAddr, sync::Arc};
+
+    #[test]
+    fn keys_ignore_the_case_of_hostnames() {
+        let addr = Address::HostnameAddress("Example.COM".to_owned(), 443);
+        let ip = Address::SocketAddress(SocketAddr::from(([192, 0, 2, 1], 80)));
+
+        assert_eq!(DestinationKey::Host.of(&addr), "example.com");
+        assert_eq!(DestinationKey::HostPort.of(&addr), "example.com:443");
+        assert_eq!(DestinationKey::Host.of(&ip), "192.0.2.1");
+        assert_eq!(DestinationKey::HostPort.of(&ip), "192.0.2.1:80");
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_wait_for_a_slot() {
+        let limiter = Arc::new(DestinationLimiter::new(1, DestinationKey::Host));
+        let addr = || Address::HostnameAddress("example.com".to_owned(), 443);
+
+        let guard = limiter.try_acquire(&addr()).ok().unwrap();
+        let pending = limiter.try_acquire(&addr()).err().unwrap();
+
+        let waiter = tokio::spawn(pending.acquire());
+        drop(guard);
+        let guard = waiter.await.unwrap();
+
+        assert!(limiter.try_acquire(&addr()).is_err());
+        drop(guard);
+        assert!(limiter.destinations.lock().is_empty());
+    }
+}
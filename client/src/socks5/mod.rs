@@ -1,75 +1,131 @@
-use self::{connection::Connection, protocol::Error as ProtocolError};
+use self::{
+    circuit::CircuitBreaker,
+    connection::{Connection, ConnectionConfig},
+    destination::DestinationLimiter,
+    protocol::Error as ProtocolError,
+};
 use crate::relay::Request as RelayRequest;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use std::{
+    future::Future,
     io::Error as IoError,
     net::{SocketAddr, TcpListener as StdTcpListener},
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{net::TcpListener, sync::mpsc::Sender};
 
-pub use self::authentication::Authentication;
+pub use self::{
+    authentication::{Authentication, Credential},
+    destination::DestinationKey,
+};
 
 mod authentication;
+mod circuit;
 mod connection;
 mod convert;
+mod destination;
 mod protocol;
 
 pub struct Socks5 {
     listener: TcpListener,
     local_addr: SocketAddr,
-    authentication: Arc<Authentication>,
-    max_udp_packet_size: usize,
+    tcp_nodelay: bool,
+    connection_config: Arc<ConnectionConfig>,
     req_tx: Sender<RelayRequest>,
 }
 
+/// How the local server accepts and handles connections
+pub struct Socks5Config {
+    pub local_addr: SocketAddr,
+    pub authentication: Authentication,
+    pub disable_udp: bool,
+    pub keep_half_closed_associations: bool,
+    pub disable_tcp_nodelay: bool,
+    pub max_connections_per_destination: Option<usize>,
+    pub destination_limit_key: DestinationKey,
+    pub circuit_breaker_threshold: Option<usize>,
+    pub circuit_breaker_cooldown: Duration,
+    pub max_udp_packet_size: usize,
+    pub tcp_chunk_size: usize,
+    pub optimistic_connect: bool,
+}
+
 impl Socks5 {
     pub async fn init(
-        local_addr: SocketAddr,
-        auth: Authentication,
-        max_udp_pkt_size: usize,
+        config: Socks5Config,
         req_tx: Sender<RelayRequest>,
     ) -> Result<Self, Socks5Error> {
-        let listener = if local_addr.is_ipv4() {
-            TcpListener::bind(local_addr).await?
-        } else {
-            let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-            socket.set_only_v6(false)?;
-            socket.bind(&SockAddr::from(local_addr))?;
-            socket.listen(128)?;
-            TcpListener::from_std(StdTcpListener::from(socket))?
-        };
+        let listener = bind(config.local_addr)
+            .await
+            .map_err(|err| Socks5Error::Bind(config.local_addr, err))?;
+
+        let local_addr = listener.local_addr()?;
 
-        let auth = Arc::new(auth);
+        let connection_config = ConnectionConfig {
+            local_addr,
+            authentication: config.authentication,
+            disable_udp: config.disable_udp,
+            keep_half_closed_associations: config.keep_half_closed_associations,
+            destination_limiter: config
+                .max_connections_per_destination
+                .map(|max| Arc::new(DestinationLimiter::new(max, config.destination_limit_key))),
+            circuit_breaker: config.circuit_breaker_threshold.map(|threshold| {
+                Arc::new(CircuitBreaker::new(
+                    threshold,
+                    config.circuit_breaker_cooldown,
+                ))
+            }),
+            max_udp_packet_size: config.max_udp_packet_size,
+            tcp_chunk_size: config.tcp_chunk_size,
+            optimistic_connect: config.optimistic_connect,
+        };
 
         Ok(Self {
             listener,
             local_addr,
-            authentication: auth,
-            max_udp_packet_size: max_udp_pkt_size,
+            tcp_nodelay: !config.disable_tcp_nodelay,
+            connection_config: Arc::new(connection_config),
             req_tx,
         })
     }
 
-    pub async fn run(self) {
-        log::info!("[socks5] started. Listening: {}", self.local_addr);
+    /// The address the listener is bound to, with the port chosen by the OS if port 0 was requested
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Accepts local connections until `shutdown` resolves. Established connections are not affected
+    pub async fn run<F>(self, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        tokio::pin!(shutdown);
+
+        while let Ok((conn, src_addr)) = tokio::select! {
+            res = self.listener.accept() => res,
+            () = &mut shutdown => {
+                log::info!("[socks5] stopped");
+                return;
+            }
+        } {
+            if self.tcp_nodelay {
+                if let Err(err) = conn.set_nodelay(true) {
+                    log::warn!("[socks5] [{src_addr}] {err}");
+                }
+            }
 
-        while let Ok((conn, src_addr)) = self.listener.accept().await {
-            let auth = self.authentication.clone();
+            // the relay has no way to mark urgent data, so it is relayed in-band instead of being left out of the stream by the kernel
+            if let Err(err) = SockRef::from(&conn).set_out_of_band_inline(true) {
+                log::warn!("[socks5] [{src_addr}] {err}");
+            }
+
+            let config = self.connection_config.clone();
             let req_tx = self.req_tx.clone();
 
             tokio::spawn(async move {
-                match Connection::handle(
-                    conn,
-                    src_addr,
-                    self.local_addr,
-                    auth,
-                    self.max_udp_packet_size,
-                    req_tx,
-                )
-                .await
-                {
+                match Connection::handle(conn, src_addr, config, req_tx).await {
                     Ok(()) => {}
                     Err(err) => log::warn!("[socks5] [{src_addr}] {err}"),
                 }
@@ -78,14 +134,31 @@ impl Socks5 {
     }
 }
 
+async fn bind(addr: SocketAddr) -> Result<TcpListener, IoError> {
+    if addr.is_ipv4() {
+        TcpListener::bind(addr).await
+    } else {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SockAddr::from(addr))?;
+        socket.listen(128)?;
+        socket.set_nonblocking(true)?;
+        TcpListener::from_std(StdTcpListener::from(socket))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Socks5Error {
     #[error(transparent)]
     Protocol(#[from] ProtocolError),
     #[error(transparent)]
     Io(#[from] IoError),
+    #[error("failed to bind the local socks5 server to {0}: {1}")]
+    Bind(SocketAddr, #[source] IoError),
     #[error("failed to connect to the relay layer")]
     RelayConnectivity,
+    #[error("the server failed to connect to the target")]
+    ConnectFailed,
     #[error("fragmented UDP packet is not supported")]
     FragmentedUdpPacket,
     #[error("authentication failed")]
@@ -1,4 +1,4 @@
-use super::Error;
+use super::{idna, Error};
 use bytes::BufMut;
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
@@ -29,7 +29,7 @@ impl Address {
                 let mut buf = [0; 6];
                 stream.read_exact(&mut buf).await?;
 
-                let port = unsafe { u16::from_be(*(buf.as_ptr().add(4) as *const u16)) };
+                let port = u16::from_be_bytes([buf[4], buf[5]]);
                 let addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
 
                 Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
@@ -37,20 +37,11 @@ impl Address {
             Self::ATYP_IPV6 => {
                 let mut buf = [0; 18];
                 stream.read_exact(&mut buf).await?;
-                let buf = unsafe { *(buf.as_ptr() as *const [u16; 9]) };
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&buf[..16]);
 
-                let port = buf[8];
-
-                let addr = Ipv6Addr::new(
-                    u16::from_be(buf[0]),
-                    u16::from_be(buf[1]),
-                    u16::from_be(buf[2]),
-                    u16::from_be(buf[3]),
-                    u16::from_be(buf[4]),
-                    u16::from_be(buf[5]),
-                    u16::from_be(buf[6]),
-                    u16::from_be(buf[7]),
-                );
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
+                let addr = Ipv6Addr::from(octets);
 
                 Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
             }
@@ -60,11 +51,12 @@ impl Address {
                 let mut buf = vec![0; len + 2];
                 stream.read_exact(&mut buf).await?;
 
-                let port = unsafe { u16::from_be(*(buf.as_ptr().add(len) as *const u16)) };
+                let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
 
                 buf.truncate(len);
 
                 let addr = String::from_utf8(buf).map_err(|_| Error::AddressInvalidEncoding)?;
+                let addr = idna::to_ascii(&addr).ok_or(Error::AddressInvalidDomain(addr))?;
 
                 Ok(Self::HostnameAddress(addr, port))
             }
@@ -89,7 +81,7 @@ impl Address {
                 }
             },
             Self::HostnameAddress(addr, port) => {
-                assert!(addr.len() > u8::MAX as usize);
+                assert!(addr.len() <= u8::MAX as usize);
 
                 buf.put_u8(Self::ATYP_HOSTNAME);
                 buf.put_u8(addr.len() as u8);
@@ -108,6 +100,14 @@ impl Address {
             Address::HostnameAddress(addr, _) => 1 + addr.len() + 2,
         }
     }
+
+    /// Whether the address can be the target of a CONNECT. Port 0 and unspecified IP addresses can not
+    pub fn is_connectable(&self) -> bool {
+        match self {
+            Address::SocketAddress(addr) => addr.port() != 0 && !addr.ip().is_unspecified(),
+            Address::HostnameAddress(addr, port) => *port != 0 && !addr.is_empty(),
+        }
+    }
 }
 
 impl Display for Address {
@@ -118,3 +118,54 @@ impl Display for Address {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, Error};
+
+    fn hostname_bytes(hostname: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![Address::ATYP_HOSTNAME, hostname.len() as u8];
+        bytes.extend_from_slice(hostname);
+        bytes.extend_from_slice(&443u16.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn internationalized_hostnames_are_converted_to_ascii() {
+        let bytes = hostname_bytes("bücher.de".as_bytes());
+
+        assert!(
+            Address::read_from(&mut &bytes[..]).await.unwrap()
+                == Address::HostnameAddress("xn--bcher-kva.de".to_owned(), 443)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_hostnames_are_rejected() {
+        let bytes = hostname_bytes(&[0xc3, 0x28]);
+        assert!(matches!(
+            Address::read_from(&mut &bytes[..]).await,
+            Err(Error::AddressInvalidEncoding)
+        ));
+
+        let bytes = hostname_bytes("bü cher.de".as_bytes());
+        assert!(matches!(
+            Address::read_from(&mut &bytes[..]).await,
+            Err(Error::AddressInvalidDomain(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn addresses_round_trip() {
+        for addr in [
+            Address::HostnameAddress("example.com".to_owned(), 443),
+            Address::SocketAddress(([192, 0, 2, 1], 53).into()),
+            Address::SocketAddress((std::net::Ipv6Addr::LOCALHOST, 8080).into()),
+        ] {
+            let mut buf = Vec::new();
+            addr.write_to_buf(&mut buf);
+            assert_eq!(buf.len(), addr.serialized_len());
+            assert!(Address::read_from(&mut &buf[..]).await.unwrap() == addr);
+        }
+    }
+}
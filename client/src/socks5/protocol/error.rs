@@ -11,8 +11,12 @@ pub enum Error {
     UnsupportedPasswordAuthenticationVersion(u8),
     #[error("unsupported command {0:#x}")]
     UnsupportedCommand(u8),
+    #[error("reserved byte must be 0x00, got {0:#x}")]
+    InvalidReservedByte(u8),
     #[error("unsupported address type {0:#x}")]
     UnsupportedAddressType(u8),
     #[error("address domain name must be in UTF-8")]
     AddressInvalidEncoding,
+    #[error("invalid internationalized domain name: {0}")]
+    AddressInvalidDomain(String),
 }
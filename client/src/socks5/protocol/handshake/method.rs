@@ -1,3 +1,5 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
 #[derive(Clone, Copy, PartialEq)]
 #[repr(transparent)]
 pub struct HandshakeMethod(pub u8);
@@ -13,3 +15,15 @@ impl HandshakeMethod {
         self.0
     }
 }
+
+impl Display for HandshakeMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match *self {
+            Self::None => write!(f, "no-auth"),
+            Self::Gssapi => write!(f, "gssapi"),
+            Self::Password => write!(f, "username/password"),
+            Self::Unacceptable => write!(f, "no acceptable methods"),
+            Self(method) => write!(f, "{method:#04x}"),
+        }
+    }
+}
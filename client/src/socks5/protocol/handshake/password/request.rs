@@ -10,7 +10,6 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 /// |  1  |  1   | 1 to 255 |  1   | 1 to 255 |
 /// +-----+------+----------+------+----------+
 /// ```
-
 pub struct Request {
     pub username: Vec<u8>,
     pub password: Vec<u8>,
@@ -12,7 +12,6 @@ use tokio::io::{AsyncWrite, AsyncWriteExt};
 /// |  1  |   1    |
 /// +-----+--------+
 /// ```
-
 #[derive(Clone)]
 pub struct Response(bool);
 
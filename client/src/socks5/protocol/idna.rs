@@ -0,0 +1,177 @@
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+
+const ACE_PREFIX: &str = "xn--";
+const MAX_LABEL_LEN: usize = 63;
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Converts an internationalized domain name to its ASCII form, with non-ASCII labels encoded in Punycode (RFC 3492). Returns `None` if it is not a valid domain name
+///
+/// ASCII domains are returned unchanged. Non-ASCII domains are lowercased, but the full UTS #46 mapping and normalization are not applied
+pub fn to_ascii(domain: &str) -> Option<String> {
+    if domain.is_ascii() {
+        return Some(domain.to_owned());
+    }
+
+    let domain = domain
+        .replace(['\u{3002}', '\u{ff0e}', '\u{ff61}'], ".")
+        .to_lowercase();
+
+    let (domain, root) = match domain.strip_suffix('.') {
+        Some(domain) => (domain, "."),
+        None => (domain.as_str(), ""),
+    };
+
+    let mut labels = Vec::new();
+
+    for label in domain.split('.') {
+        let label = if label.is_ascii() {
+            label.to_owned()
+        } else if label.chars().all(is_valid_char) {
+            format!("{ACE_PREFIX}{}", encode(label)?)
+        } else {
+            return None;
+        };
+
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+
+        labels.push(label);
+    }
+
+    let domain = labels.join(".") + root;
+    (domain.len() <= MAX_DOMAIN_LEN).then_some(domain)
+}
+
+fn is_valid_char(c: char) -> bool {
+    if c.is_ascii() {
+        c.is_ascii_alphanumeric() || c == '-'
+    } else {
+        !c.is_control() && !c.is_whitespace()
+    }
+}
+
+fn encode(input: &str) -> Option<String> {
+    let code_points = input.chars().map(u32::from).collect::<Vec<_>>();
+    let mut output = input.chars().filter(char::is_ascii).collect::<String>();
+
+    let basic_len = output.len() as u32;
+    let mut handled = basic_len;
+
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta = 0u32;
+    let mut bias = INITIAL_BIAS;
+
+    while (handled as usize) < code_points.len() {
+        let m = code_points.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in &code_points {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+
+                loop {
+                    let t = k.saturating_sub(bias).clamp(T_MIN, T_MAX);
+
+                    if q < t {
+                        break;
+                    }
+
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn adapt(delta: u32, num_points: u32, is_first: bool) -> u32 {
+    let mut delta = if is_first { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+fn encode_digit(digit: u32) -> char {
+    if digit < 26 {
+        char::from(b'a' + digit as u8)
+    } else {
+        char::from(b'0' + (digit - 26) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_ascii;
+
+    #[test]
+    fn labels_are_encoded_in_punycode() {
+        assert_eq!(to_ascii("bücher.de").as_deref(), Some("xn--bcher-kva.de"));
+        assert_eq!(to_ascii("München.").as_deref(), Some("xn--mnchen-3ya."));
+        assert_eq!(
+            to_ascii("例え。テスト").as_deref(),
+            Some("xn--r8jz45g.xn--zckzah")
+        );
+
+        // sample strings of RFC 3492
+        assert_eq!(
+            to_ascii("他们为什么不说中文").as_deref(),
+            Some("xn--ihqwcrb4cv8a8dqg056pqjye")
+        );
+        assert_eq!(
+            to_ascii("ليهمابتكلموشعربي؟").as_deref(),
+            Some("xn--egbpdaj6bu4bxfgehfvwxn")
+        );
+    }
+
+    #[test]
+    fn ascii_domains_are_unchanged() {
+        assert_eq!(to_ascii("Example.COM").as_deref(), Some("Example.COM"));
+        assert_eq!(to_ascii("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        assert_eq!(to_ascii("bü cher.de"), None);
+        assert_eq!(to_ascii("bücher..de"), None);
+        assert_eq!(to_ascii(&format!("ü{}.de", "a".repeat(60))), None);
+        assert_eq!(
+            to_ascii(&format!("ü.{}", vec!["a".repeat(63); 4].join("."))),
+            None
+        );
+    }
+}
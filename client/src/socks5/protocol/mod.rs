@@ -2,6 +2,7 @@ mod address;
 mod command;
 mod error;
 pub mod handshake;
+mod idna;
 mod reply;
 mod request;
 mod response;
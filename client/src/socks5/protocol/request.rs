@@ -35,7 +35,65 @@ impl Request {
             None => return Err(Error::UnsupportedCommand(cmd)),
         };
 
+        let rsv = buf[2];
+        if rsv != 0x00 {
+            return Err(Error::InvalidReservedByte(rsv));
+        }
+
         let address = Address::read_from(r).await?;
         Ok(Self { command, address })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, Command, Error, Request};
+    use std::net::SocketAddr;
+
+    const CONNECT: [u8; 10] = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x01, 0xbb];
+
+    async fn read(bytes: &[u8]) -> Result<Request, Error> {
+        Request::read_from(&mut &bytes[..]).await
+    }
+
+    #[tokio::test]
+    async fn valid_requests_are_parsed() {
+        let req = read(&CONNECT).await.unwrap();
+
+        assert!(matches!(req.command, Command::Connect));
+        assert!(req.address == Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 443))));
+    }
+
+    #[tokio::test]
+    async fn non_zero_reserved_bytes_are_rejected() {
+        let mut bytes = CONNECT;
+        bytes[2] = 0x01;
+
+        assert!(matches!(
+            read(&bytes).await,
+            Err(Error::InvalidReservedByte(0x01))
+        ));
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_rejected() {
+        let mut bytes = CONNECT;
+        bytes[0] = 0x04;
+
+        assert!(matches!(
+            read(&bytes).await,
+            Err(Error::UnsupportedSocks5Version(0x04))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_rejected() {
+        let mut bytes = CONNECT;
+        bytes[1] = 0x04;
+
+        assert!(matches!(
+            read(&bytes).await,
+            Err(Error::UnsupportedCommand(0x04))
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Serialize;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+};
+
+/// The connections of the client to the server at one point in time
+#[derive(Debug, Serialize)]
+pub struct ClientStatus {
+    pub connections: Vec<ConnectionStatus>,
+    /// Whether a connection to the server is being established
+    pub reconnecting: bool,
+    /// The most recent error when connecting to the server, if any
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatus {
+    pub remote_addr: SocketAddr,
+    pub rtt_ms: u64,
+    /// The number of relay requests being processed on the connection: connects awaiting the response of the server, and UDP sessions
+    pub in_flight_requests: usize,
+    pub udp_sessions: usize,
+    pub timing: HandshakeTiming,
+}
+
+/// How long each step of establishing a connection took, in milliseconds. Steps that were skipped or have not completed yet are `None`
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct HandshakeTiming {
+    /// Resolving the server address. Skipped for IP addresses and cached addresses
+    pub resolve_ms: Option<f64>,
+    /// The QUIC handshake. With 0-RTT, requests are already sent while it is in progress
+    pub handshake_ms: Option<f64>,
+    /// Sending the `Authenticate` command. The server does not acknowledge it, so this is not a round trip
+    pub authentication_ms: Option<f64>,
+    /// The first `Connect` on the connection, from opening its stream to the response of the server. Not measured with `optimistic_connect`
+    pub first_connect_ms: Option<f64>,
+    /// The outcome of the 0-RTT attempt with `reduce_rtt`. `None` if 0-RTT was not attempted or the handshake is still in progress
+    pub zero_rtt: Option<ZeroRtt>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZeroRtt {
+    Accepted,
+    /// The server rejected the early data, or the handshake failed. Streams opened before the handshake completed are lost
+    Rejected,
+    /// No valid session ticket was cached for the server, so the connection fell back to 1-RTT
+    Unavailable,
+}
+
+impl Display for ZeroRtt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Accepted => write!(f, "accepted"),
+            Self::Rejected => write!(f, "rejected"),
+            Self::Unavailable => write!(f, "unavailable"),
+        }
+    }
+}
+
+impl Display for HandshakeTiming {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let steps = [
+            ("resolve", self.resolve_ms),
+            ("handshake", self.handshake_ms),
+            ("authentication", self.authentication_ms),
+            ("first connect", self.first_connect_ms),
+        ];
+
+        for (i, (step, ms)) in steps.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+
+            match ms {
+                Some(ms) => write!(f, "{step}: {ms:.3} ms")?,
+                None => write!(f, "{step}: -")?,
+            }
+        }
+
+        if let Some(zero_rtt) = self.zero_rtt {
+            write!(f, ", 0-rtt: {zero_rtt}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HandshakeTiming, ZeroRtt};
+
+    #[test]
+    fn zero_rtt_outcome_is_reported() {
+        let timing = HandshakeTiming {
+            handshake_ms: Some(1.5),
+            zero_rtt: Some(ZeroRtt::Unavailable),
+            ..HandshakeTiming::default()
+        };
+
+        assert_eq!(
+            timing.to_string(),
+            "resolve: -, handshake: 1.500 ms, authentication: -, first connect: -, 0-rtt: unavailable"
+        );
+
+        let json = serde_json::to_value(timing).unwrap();
+        assert_eq!(json["zero_rtt"], "unavailable");
+
+        let json = serde_json::to_value(HandshakeTiming::default()).unwrap();
+        assert!(json["zero_rtt"].is_null());
+        assert!(!HandshakeTiming::default().to_string().contains("0-rtt"));
+    }
+}
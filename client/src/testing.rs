@@ -0,0 +1,30 @@
+//! Configures a test server and the relay to connect to it over QUIC on the loopback interface
+
+use crate::certificate;
+use quinn::{ClientConfig, ServerConfig};
+use rustls::{PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+use std::{fs::File, io::BufReader};
+use tuic_protocol::testing;
+
+pub fn server_config() -> ServerConfig {
+    let certs = certificate::load_certificates(testing::CERTIFICATE_PATH).unwrap();
+
+    let mut file = BufReader::new(File::open(testing::PRIVATE_KEY_PATH).unwrap());
+    let priv_key = match rustls_pemfile::read_one(&mut file).unwrap() {
+        Some(Item::PKCS8Key(key)) => PrivateKey(key),
+        _ => panic!("the test private key is not in PKCS #8"),
+    };
+
+    ServerConfig::with_single_cert(certs, priv_key).unwrap()
+}
+
+pub fn client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+
+    for cert in certificate::load_certificates(testing::CA_CERTIFICATE_PATH).unwrap() {
+        roots.add(&cert).unwrap();
+    }
+
+    ClientConfig::with_root_certificates(roots)
+}
@@ -36,6 +36,9 @@ impl Address {
     const TYPE_IPV4: u8 = 0x01;
     const TYPE_IPV6: u8 = 0x02;
 
+    /// The maximum length of a domain name that can be expressed by the length byte
+    pub const MAX_DOMAIN_LEN: usize = u8::MAX as usize;
+
     pub async fn read_from<R>(stream: &mut R) -> Result<Self, Error>
     where
         R: AsyncRead + Unpin,
@@ -46,12 +49,14 @@ impl Address {
             Self::TYPE_DOMAIN => {
                 let len = stream.read_u8().await? as usize;
 
-                let mut buf = vec![0; len + 2];
-                stream.read_exact(&mut buf).await?;
+                if len == 0 {
+                    return Err(Error::AddressInvalidLength(len));
+                }
 
-                let port = unsafe { u16::from_be(*(buf.as_ptr().add(len) as *const u16)) };
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await?;
 
-                buf.truncate(len);
+                let port = stream.read_u16().await?;
 
                 let addr = String::from_utf8(buf).map_err(|_| Error::AddressInvalidEncoding)?;
 
@@ -61,7 +66,7 @@ impl Address {
                 let mut buf = [0; 6];
                 stream.read_exact(&mut buf).await?;
 
-                let port = unsafe { u16::from_be(*(buf.as_ptr().add(4) as *const u16)) };
+                let port = u16::from_be_bytes([buf[4], buf[5]]);
                 let addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
 
                 Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
@@ -69,20 +74,11 @@ impl Address {
             Self::TYPE_IPV6 => {
                 let mut buf = [0; 18];
                 stream.read_exact(&mut buf).await?;
-                let buf = unsafe { *(buf.as_ptr() as *const [u16; 9]) };
-
-                let port = buf[8];
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&buf[..16]);
 
-                let addr = Ipv6Addr::new(
-                    u16::from_be(buf[0]),
-                    u16::from_be(buf[1]),
-                    u16::from_be(buf[2]),
-                    u16::from_be(buf[3]),
-                    u16::from_be(buf[4]),
-                    u16::from_be(buf[5]),
-                    u16::from_be(buf[6]),
-                    u16::from_be(buf[7]),
-                );
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
+                let addr = Ipv6Addr::from(octets);
 
                 Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
             }
@@ -102,7 +98,7 @@ impl Address {
     pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
         match self {
             Self::DomainAddress(addr, port) => {
-                assert!(addr.len() < u8::MAX as usize);
+                assert!(addr.len() <= Self::MAX_DOMAIN_LEN);
 
                 buf.put_u8(Self::TYPE_DOMAIN);
                 buf.put_u8(addr.len() as u8);
@@ -157,3 +153,54 @@ impl Display for Address {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+    use crate::Error;
+
+    fn domain_bytes(len: u8) -> Vec<u8> {
+        let mut bytes = vec![Address::TYPE_DOMAIN, len];
+        bytes.extend(std::iter::repeat_n(b'a', len as usize));
+        bytes.extend_from_slice(&443u16.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn domains_of_the_longest_length_are_read_exactly() {
+        let bytes = domain_bytes(u8::MAX);
+        let mut reader = &bytes[..];
+
+        match Address::read_from(&mut reader).await {
+            Ok(Address::DomainAddress(addr, 443)) => {
+                assert_eq!(addr.len(), Address::MAX_DOMAIN_LEN)
+            }
+            _ => panic!("not read as a domain"),
+        }
+
+        assert!(reader.is_empty());
+
+        let addr = Address::DomainAddress("a".repeat(Address::MAX_DOMAIN_LEN), 443);
+        let mut buf = Vec::new();
+        addr.write_to_buf(&mut buf);
+        assert_eq!(buf, bytes);
+        assert_eq!(addr.serialized_len(), bytes.len());
+    }
+
+    #[tokio::test]
+    async fn empty_domains_are_rejected() {
+        let bytes = domain_bytes(0);
+
+        assert!(matches!(
+            Address::read_from(&mut &bytes[..]).await,
+            Err(Error::AddressInvalidLength(0))
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn domains_longer_than_the_length_byte_are_not_written() {
+        let addr = Address::DomainAddress("a".repeat(Address::MAX_DOMAIN_LEN + 1), 443);
+        addr.write_to_buf(&mut Vec::new());
+    }
+}
@@ -0,0 +1,63 @@
+/// The application error codes the server closes a QUIC connection with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum CloseCode {
+    Protocol = 0xfffffff0,
+    AuthenticationFailed = 0xfffffff1,
+    AuthenticationTimeout = 0xfffffff2,
+    BadCommand = 0xfffffff3,
+    Unauthenticated = 0xfffffff4,
+    QuotaExceeded = 0xfffffff5,
+    TooManyMigrations = 0xfffffff6,
+    UnexpectedResponse = 0xfffffff7,
+    AuthenticationInDatagram = 0xfffffff8,
+    TruncatedCommand = 0xfffffff9,
+    LifetimeExpired = 0xfffffffa,
+}
+
+impl CloseCode {
+    const ALL: [Self; 11] = [
+        Self::Protocol,
+        Self::AuthenticationFailed,
+        Self::AuthenticationTimeout,
+        Self::BadCommand,
+        Self::Unauthenticated,
+        Self::QuotaExceeded,
+        Self::TooManyMigrations,
+        Self::UnexpectedResponse,
+        Self::AuthenticationInDatagram,
+        Self::TruncatedCommand,
+        Self::LifetimeExpired,
+    ];
+
+    pub const fn as_u32(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns `None` for codes not defined by the protocol
+    pub fn from_code(code: u64) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|close| u64::from(close.as_u32()) == code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CloseCode;
+
+    #[test]
+    fn codes_round_trip() {
+        for code in CloseCode::ALL {
+            assert_eq!(CloseCode::from_code(code.as_u32().into()), Some(code));
+        }
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        assert_eq!(CloseCode::from_code(0), None);
+        assert_eq!(CloseCode::from_code(0xffffffef), None);
+        assert_eq!(CloseCode::from_code(0xfffffffb), None);
+        assert_eq!(CloseCode::from_code(u64::from(u32::MAX) + 0xf0), None);
+    }
+}
@@ -1,6 +1,10 @@
-use crate::{Address, Error, TUIC_PROTOCOL_VERSION};
+use crate::{supported_versions, Address, Error, TUIC_PROTOCOL_VERSION};
 use bytes::{BufMut, BytesMut};
-use std::io::Result as IoResult;
+use futures_util::FutureExt;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Command
@@ -82,7 +86,7 @@ impl Command {
         let ver = buf[0];
         let cmd = buf[1];
 
-        if ver != TUIC_PROTOCOL_VERSION {
+        if !supported_versions().contains(&ver) {
             return Err(Error::UnsupportedVersion(ver));
         }
 
@@ -105,8 +109,8 @@ impl Command {
                 let mut buf = [0; 6];
                 r.read_exact(&mut buf).await?;
 
-                let assoc_id = unsafe { u32::from_be(*(buf.as_ptr() as *const u32)) };
-                let len = unsafe { u16::from_be(*(buf.as_ptr().add(4) as *const u16)) };
+                let assoc_id = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                let len = u16::from_be_bytes([buf[4], buf[5]]);
                 let addr = Address::read_from(r).await?;
 
                 Ok(Self::new_packet(assoc_id, len, addr))
@@ -120,6 +124,25 @@ impl Command {
         }
     }
 
+    /// Parses a command from an in-memory buffer, returning it with the number of bytes consumed
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), Error> {
+        let mut reader = bytes;
+
+        // reading from a slice is always ready, running out of bytes fails with `UnexpectedEof`
+        let cmd = Self::read_from(&mut reader)
+            .now_or_never()
+            .ok_or_else(|| IoError::from(ErrorKind::WouldBlock))??;
+
+        Ok((cmd, bytes.len() - reader.len()))
+    }
+
+    /// Serializes the command into an in-memory buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        buf.to_vec()
+    }
+
     pub async fn write_to<W>(&self, w: &mut W) -> IoResult<()>
     where
         W: AsyncWrite + Unpin,
@@ -174,9 +197,33 @@ impl Command {
             Self::Response(_) => 1,
             Self::Authenticate { .. } => 32,
             Self::Connect { addr } => addr.serialized_len(),
-            Self::Packet { addr, .. } => 6 + addr.serialized_len(),
+            Self::Packet { addr, .. } => Self::packet_header_len(addr) - 2,
             Self::Dissociate { .. } => 4,
             Self::Heartbeat => 0,
         }
     }
+
+    /// The length of the header of a `Packet` command carrying a packet to or from `addr`
+    pub fn packet_header_len(addr: &Address) -> usize {
+        8 + addr.serialized_len()
+    }
+}
+
+/// Shows the command type and its fields, except for the authentication token
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Response(true) => write!(f, "Response [succeeded]"),
+            Self::Response(false) => write!(f, "Response [failed]"),
+            Self::Authenticate { .. } => write!(f, "Authenticate"),
+            Self::Connect { addr } => write!(f, "Connect [{addr}]"),
+            Self::Packet {
+                assoc_id,
+                len,
+                addr,
+            } => write!(f, "Packet [{assoc_id}] [{len}] [{addr}]"),
+            Self::Dissociate { assoc_id } => write!(f, "Dissociate [{assoc_id}]"),
+            Self::Heartbeat => write!(f, "Heartbeat"),
+        }
+    }
 }
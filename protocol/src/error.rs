@@ -13,6 +13,8 @@ pub enum Error {
     UnsupportedAddressType(u8),
     #[error("invalid response {0:#x}")]
     InvalidResponse(u8),
+    #[error("invalid address domain name length {0}")]
+    AddressInvalidLength(usize),
     #[error("address domain name must be in UTF-8")]
     AddressInvalidEncoding,
 }
@@ -1,9 +1,21 @@
 //! The TUIC protocol
 
 mod address;
+mod close;
 mod command;
 mod error;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use std::ops::RangeInclusive;
+
+/// The protocol version written in every command sent
 pub const TUIC_PROTOCOL_VERSION: u8 = 0x04;
 
-pub use crate::{address::Address, command::Command, error::Error};
+/// The protocol versions accepted in received commands, which always include `TUIC_PROTOCOL_VERSION`
+pub const fn supported_versions() -> RangeInclusive<u8> {
+    TUIC_PROTOCOL_VERSION..=TUIC_PROTOCOL_VERSION
+}
+
+pub use crate::{address::Address, close::CloseCode, command::Command, error::Error};
@@ -0,0 +1,229 @@
+//! Byte streams of valid and malformed commands, for testing the parsers of commands, and a certificate to connect test clients and servers with
+
+use crate::{Address, Command, Error, TUIC_PROTOCOL_VERSION};
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// A certificate of `localhost` and `127.0.0.1`, issued by `CA_CERTIFICATE_PATH`
+pub const CERTIFICATE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem");
+pub const PRIVATE_KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem");
+pub const CA_CERTIFICATE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+
+/// A command of every type, with every address type, paired with its serialized form
+pub fn valid_commands() -> Vec<(Command, Vec<u8>)> {
+    let addrs = [
+        Address::DomainAddress("example.com".to_owned(), 443),
+        Address::DomainAddress("a".repeat(Address::MAX_DOMAIN_LEN), 80),
+        Address::SocketAddress(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 53))),
+        Address::SocketAddress(SocketAddr::from((Ipv6Addr::LOCALHOST, 8080))),
+    ];
+
+    let mut cmds = vec![
+        Command::new_response(true),
+        Command::new_response(false),
+        Command::new_authenticate([0x5a; 32]),
+        Command::new_dissociate(u32::MAX),
+        Command::new_heartbeat(),
+    ];
+
+    for addr in addrs {
+        cmds.push(Command::new_connect(addr.clone()));
+        cmds.push(Command::new_packet(0x0102_0304, 1200, addr));
+    }
+
+    cmds.into_iter()
+        .map(|cmd| {
+            let bytes = cmd.to_bytes();
+            (cmd, bytes)
+        })
+        .collect()
+}
+
+/// The error a malformed command is expected to be rejected with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpectedError {
+    UnsupportedVersion(u8),
+    UnsupportedCommand(u8),
+    UnsupportedAddressType(u8),
+    InvalidResponse(u8),
+    AddressInvalidLength(usize),
+    AddressInvalidEncoding,
+    /// The stream ends in the middle of the command
+    UnexpectedEof,
+}
+
+impl ExpectedError {
+    pub fn matches(&self, err: &Error) -> bool {
+        match (self, err) {
+            (Self::UnsupportedVersion(exp), Error::UnsupportedVersion(ver)) => exp == ver,
+            (Self::UnsupportedCommand(exp), Error::UnsupportedCommand(cmd)) => exp == cmd,
+            (Self::UnsupportedAddressType(exp), Error::UnsupportedAddressType(ty)) => exp == ty,
+            (Self::InvalidResponse(exp), Error::InvalidResponse(resp)) => exp == resp,
+            (Self::AddressInvalidLength(exp), Error::AddressInvalidLength(len)) => exp == len,
+            (Self::AddressInvalidEncoding, Error::AddressInvalidEncoding) => true,
+            (Self::UnexpectedEof, Error::Io(err)) => err.kind() == ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+}
+
+/// A malformed command, described by `name`
+pub struct MalformedCommand {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub expected: ExpectedError,
+}
+
+impl MalformedCommand {
+    fn new(name: impl Into<String>, bytes: Vec<u8>, expected: ExpectedError) -> Self {
+        Self {
+            name: name.into(),
+            bytes,
+            expected,
+        }
+    }
+}
+
+/// A catalog of malformed commands: unsupported versions and types, invalid fields, and every truncation of each valid command
+pub fn malformed_commands() -> Vec<MalformedCommand> {
+    const VER: u8 = TUIC_PROTOCOL_VERSION;
+
+    let mut cases = vec![
+        MalformedCommand::new(
+            "version 0",
+            vec![0x00, 0x04],
+            ExpectedError::UnsupportedVersion(0x00),
+        ),
+        MalformedCommand::new(
+            "previous version",
+            vec![VER - 1, 0x04],
+            ExpectedError::UnsupportedVersion(VER - 1),
+        ),
+        MalformedCommand::new(
+            "next version",
+            vec![VER + 1, 0x04],
+            ExpectedError::UnsupportedVersion(VER + 1),
+        ),
+        MalformedCommand::new(
+            "unknown type",
+            vec![VER, 0x05],
+            ExpectedError::UnsupportedCommand(0x05),
+        ),
+        MalformedCommand::new(
+            "unknown type 0xfe",
+            vec![VER, 0xfe],
+            ExpectedError::UnsupportedCommand(0xfe),
+        ),
+        MalformedCommand::new(
+            "response byte 0x01",
+            vec![VER, 0xff, 0x01],
+            ExpectedError::InvalidResponse(0x01),
+        ),
+        MalformedCommand::new(
+            "response byte 0xfe",
+            vec![VER, 0xff, 0xfe],
+            ExpectedError::InvalidResponse(0xfe),
+        ),
+        MalformedCommand::new(
+            "unknown address type",
+            vec![VER, 0x01, 0x03, 127, 0, 0, 1, 0, 80],
+            ExpectedError::UnsupportedAddressType(0x03),
+        ),
+        MalformedCommand::new(
+            "empty domain",
+            vec![VER, 0x01, 0x00, 0x00, 0, 80],
+            ExpectedError::AddressInvalidLength(0),
+        ),
+        MalformedCommand::new(
+            "domain not in UTF-8",
+            vec![VER, 0x01, 0x00, 0x02, 0xc3, 0x28, 0, 80],
+            ExpectedError::AddressInvalidEncoding,
+        ),
+    ];
+
+    // the length byte claims more than the stream holds, as for a domain longer than the longest one the length byte can express
+    let mut oversized = vec![VER, 0x01, 0x00, u8::MAX];
+    oversized.extend_from_slice(&[b'a'; 64]);
+    cases.push(MalformedCommand::new(
+        "oversized domain",
+        oversized,
+        ExpectedError::UnexpectedEof,
+    ));
+
+    for (cmd, bytes) in valid_commands() {
+        for len in 0..bytes.len() {
+            cases.push(MalformedCommand::new(
+                format!("{cmd} truncated to {len} bytes"),
+                bytes[..len].to_vec(),
+                ExpectedError::UnexpectedEof,
+            ));
+        }
+    }
+
+    cases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{malformed_commands, valid_commands};
+    use crate::Command;
+
+    #[test]
+    fn valid_commands_round_trip() {
+        for (cmd, bytes) in valid_commands() {
+            let (parsed, len) = Command::from_bytes(&bytes).unwrap();
+            assert_eq!(len, bytes.len(), "{cmd}");
+            assert_eq!(len, cmd.serialized_len(), "{cmd}");
+            assert_eq!(parsed.to_bytes(), bytes, "{cmd}");
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_not_consumed() {
+        for (cmd, mut bytes) in valid_commands() {
+            let len = bytes.len();
+            bytes.extend_from_slice(b"payload");
+
+            let (_, consumed) = Command::from_bytes(&bytes).unwrap();
+            assert_eq!(consumed, len, "{cmd}");
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected_from_bytes() {
+        for case in malformed_commands() {
+            match Command::from_bytes(&case.bytes) {
+                Ok((cmd, _)) => panic!("{}: parsed as {cmd}", case.name),
+                Err(err) => assert!(
+                    case.expected.matches(&err),
+                    "{}: expected {:?}, got {err}",
+                    case.name,
+                    case.expected
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_commands_are_rejected_from_streams() {
+        for case in malformed_commands() {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            tokio::io::AsyncWriteExt::write_all(&mut client, &case.bytes)
+                .await
+                .unwrap();
+            drop(client);
+
+            match Command::read_from(&mut server).await {
+                Ok(cmd) => panic!("{}: parsed as {cmd}", case.name),
+                Err(err) => assert!(
+                    case.expected.matches(&err),
+                    "{}: expected {:?}, got {err}",
+                    case.name,
+                    case.expected
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use parking_lot::Mutex;
+
+/// The source of the buffers used for relaying TCP streams. Each acquired buffer is released after the stream ends
+pub trait BufferProvider: Send + Sync {
+    fn acquire(&self, size: usize) -> Vec<u8>;
+
+    fn release(&self, _buf: Vec<u8>) {}
+}
+
+pub struct HeapBufferProvider;
+
+impl BufferProvider for HeapBufferProvider {
+    fn acquire(&self, size: usize) -> Vec<u8> {
+        vec![0; size]
+    }
+}
+
+/// Keeps up to `capacity` released buffers and hands them out again, with `--buffer-pool-size`
+pub struct PooledBufferProvider {
+    capacity: usize,
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl PooledBufferProvider {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+        }
+    }
+}
+
+impl BufferProvider for PooledBufferProvider {
+    fn acquire(&self, size: usize) -> Vec<u8> {
+        match self.buffers.lock().pop() {
+            Some(mut buf) => {
+                buf.resize(size, 0);
+                buf
+            }
+            None => vec![0; size],
+        }
+    }
+
+    fn release(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock();
+
+        if buffers.len() < self.capacity {
+            buffers.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferProvider, PooledBufferProvider};
+
+    #[test]
+    fn released_buffers_are_reused_up_to_the_capacity() {
+        let provider = PooledBufferProvider::new(1);
+
+        let first = provider.acquire(16);
+        let second = provider.acquire(16);
+        let ptr = first.as_ptr();
+
+        provider.release(first);
+        provider.release(second);
+        assert_eq!(provider.buffers.lock().len(), 1);
+
+        let reused = provider.acquire(16);
+        assert_eq!(reused.as_ptr(), ptr);
+        assert_eq!(reused.len(), 16);
+        assert!(provider.buffers.lock().is_empty());
+    }
+
+    #[test]
+    fn reused_buffers_have_the_requested_size() {
+        let provider = PooledBufferProvider::new(1);
+
+        provider.release(vec![0; 8]);
+        assert_eq!(provider.acquire(32).len(), 32);
+
+        provider.release(vec![0; 64]);
+        assert_eq!(provider.acquire(32).len(), 32);
+    }
+}
@@ -0,0 +1,49 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+/// Spawns `task`, calling `on_panic` if it panics. The panic unwinds only the task, which releases everything the task holds. Returns `None` if the task did not complete
+pub fn spawn_catching_panic<F, P>(task: F, on_panic: P) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+    P: FnOnce() + Send + 'static,
+{
+    tokio::spawn(async move {
+        match tokio::spawn(task).await {
+            Ok(output) => Some(output),
+            Err(err) => {
+                if err.is_panic() {
+                    on_panic();
+                }
+
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spawn_catching_panic;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn only_panics_are_reported() {
+        let panicked = Arc::new(AtomicBool::new(false));
+
+        let flag = panicked.clone();
+        let output = spawn_catching_panic(async { 1 }, move || flag.store(true, Ordering::Relaxed));
+        assert_eq!(output.await.unwrap(), Some(1));
+        assert!(!panicked.load(Ordering::Relaxed));
+
+        let flag = panicked.clone();
+        let output = spawn_catching_panic(async { panic!("task panicked") }, move || {
+            flag.store(true, Ordering::Relaxed)
+        });
+        assert_eq!(output.await.unwrap(), None::<()>);
+        assert!(panicked.load(Ordering::Relaxed));
+    }
+}
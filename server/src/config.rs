@@ -1,31 +1,93 @@
-use crate::certificate;
+use crate::{
+    certificate,
+    connection::{AddressPreference, DestinationKey},
+    ip_network::{IpNetwork, ParseIpNetworkError},
+};
 use getopts::{Fail, Options};
 use log::{LevelFilter, ParseLevelError};
 use quinn::{
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
-    IdleTimeout, ServerConfig, VarInt,
+    EndpointConfig, IdleTimeout, ServerConfig, VarInt,
 };
+use quinn_proto::DEFAULT_SUPPORTED_VERSIONS;
 use rustls::{version::TLS13, Error as RustlsError, ServerConfig as RustlsServerConfig};
 use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as JsonError;
 use std::{
-    env::ArgsOs, fmt::Display, fs::File, io::Error as IoError, num::ParseIntError, str::FromStr,
-    sync::Arc, time::Duration,
+    ffi::OsStr,
+    fmt::Display,
+    fs::File,
+    io::Error as IoError,
+    net::{AddrParseError, IpAddr},
+    num::{NonZeroU64, NonZeroU8, NonZeroUsize, ParseIntError},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 
 pub struct Config {
     pub server_config: ServerConfig,
+    pub endpoint_config: EndpointConfig,
     pub port: u16,
     pub token_digest: [u8; 32],
     pub authentication_timeout: Duration,
     pub max_udp_packet_size: usize,
     pub enable_ipv6: bool,
+    pub udp_association_reap_threshold: Duration,
+    pub max_udp_packets_per_second: Option<u64>,
+    pub max_tcp_bytes_per_second: Option<u64>,
+    pub strict_authentication: bool,
+    pub acknowledge_dissociate: bool,
+    pub max_connections_per_destination: Option<usize>,
+    pub destination_limit_key: DestinationKey,
+    pub max_bytes_per_connection: Option<u64>,
+    pub reject_dissociated_packets: bool,
+    pub max_concurrent_connects: Option<usize>,
+    pub max_udp_associations: Option<usize>,
+    pub max_udp_associations_per_second: Option<u64>,
+    pub ipv6_flow_label: Option<u32>,
+    pub shutdown_grace_period: Duration,
+    pub disable_udp: bool,
+    pub connect_udp_sockets: bool,
+    pub allow_self_relay: bool,
+    pub max_migrations: Option<usize>,
+    pub max_connection_lifetime: Option<Duration>,
+    pub max_pending_connections: Option<usize>,
+    pub max_outbound_connects: Option<usize>,
+    pub buffer_pool_size: Option<usize>,
+    pub coalesce_udp_replies: bool,
+    pub trace_peers: Vec<IpAddr>,
+    pub allowed_clients: Vec<IpNetwork>,
+    pub udp_receive_buffer_size: Option<usize>,
+    pub udp_send_buffer_size: Option<usize>,
+    pub throughput_time_constant: Duration,
+    pub stats_interval: Option<Duration>,
+    pub metrics_log_interval: Option<Duration>,
+    pub first_byte_timeout: Option<Duration>,
+    pub command_read_timeout: Option<Duration>,
+    pub close_on_truncated_command: bool,
+    pub max_domain_length: Option<usize>,
+    pub max_resolved_addresses: Option<usize>,
+    pub resolve_retries: usize,
+    pub resolve_retry_time: Duration,
+    pub error_log_burst: Option<u64>,
+    pub udp_pacing_rate: Option<u64>,
+    pub udp_pacing_latency: Duration,
+    pub udp_address_preference: AddressPreference,
+    pub disable_tcp_nodelay: bool,
+    pub reset_on_target_reset: bool,
     pub log_level: LevelFilter,
+    pub check: bool,
 }
 
 impl Config {
-    pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
+    /// Parses the command line arguments, the first of which is the program name
+    pub fn parse<A>(args: A) -> Result<Self, ConfigError>
+    where
+        A: IntoIterator,
+        A::Item: AsRef<OsStr>,
+    {
         let raw = RawConfig::parse(args)?;
 
         let server_config = {
@@ -49,6 +111,8 @@ impl Config {
             crypto.alpn_protocols = raw.alpn.into_iter().map(|alpn| alpn.into_bytes()).collect();
 
             let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+            config.migration(!raw.disable_migration);
+
             let transport = Arc::get_mut(&mut config.transport).unwrap();
 
             match raw.congestion_controller {
@@ -69,21 +133,146 @@ impl Config {
             config
         };
 
+        let mut endpoint_config = EndpointConfig::default();
+
+        if let Some(version) = raw.min_quic_version {
+            let min_rank = quic_version_rank(parse_quic_version(&version)?);
+
+            endpoint_config.supported_versions(
+                DEFAULT_SUPPORTED_VERSIONS
+                    .iter()
+                    .copied()
+                    .filter(|version| quic_version_rank(*version) >= min_rank)
+                    .collect(),
+            );
+        }
+
         let port = raw.port.unwrap();
         let token_digest = *blake3::hash(&raw.token.unwrap().into_bytes()).as_bytes();
         let authentication_timeout = Duration::from_secs(raw.authentication_timeout);
         let max_udp_packet_size = raw.max_udp_packet_size;
         let enable_ipv6 = raw.enable_ipv6;
+        let udp_association_reap_threshold =
+            Duration::from_millis(raw.udp_association_reap_threshold);
+        let max_udp_packets_per_second = raw.max_udp_packets_per_second.map(NonZeroU64::get);
+        let max_tcp_bytes_per_second = raw.max_tcp_bytes_per_second.map(NonZeroU64::get);
+        let strict_authentication = raw.strict_authentication;
+        let acknowledge_dissociate = raw.acknowledge_dissociate;
+        let max_connections_per_destination =
+            raw.max_connections_per_destination.map(NonZeroUsize::get);
+        let destination_limit_key = raw.destination_limit_key;
+        let max_bytes_per_connection = raw.max_bytes_per_connection.map(NonZeroU64::get);
+        let reject_dissociated_packets = raw.reject_dissociated_packets;
+        let max_concurrent_connects = raw.max_concurrent_connects.map(NonZeroUsize::get);
+        let max_udp_associations = raw.max_udp_associations.map(NonZeroUsize::get);
+        let max_udp_associations_per_second =
+            raw.max_udp_associations_per_second.map(NonZeroU64::get);
+
+        let ipv6_flow_label = match raw.ipv6_flow_label {
+            Some(label) if label > 0xfffff => return Err(ConfigError::InvalidFlowLabel),
+            label => label,
+        };
+
+        let shutdown_grace_period = Duration::from_millis(raw.shutdown_grace_period);
+        let disable_udp = raw.disable_udp;
+        let connect_udp_sockets = raw.connect_udp_sockets;
+        let allow_self_relay = raw.allow_self_relay;
+        let max_migrations = raw.max_migrations.map(NonZeroUsize::get);
+        let max_connection_lifetime = raw
+            .max_connection_lifetime
+            .map(|lifetime| Duration::from_millis(lifetime.get()));
+        let max_pending_connections = raw.max_pending_connections.map(NonZeroUsize::get);
+        let max_outbound_connects = raw.max_outbound_connects.map(NonZeroUsize::get);
+        let buffer_pool_size = raw.buffer_pool_size.map(NonZeroUsize::get);
+        let coalesce_udp_replies = raw.coalesce_udp_replies;
+        let trace_peers = raw.trace_peers;
+        let allowed_clients = raw
+            .allowed_clients
+            .iter()
+            .map(|net| net.parse())
+            .collect::<Result<_, _>>()?;
+        let udp_receive_buffer_size = raw.udp_receive_buffer_size;
+        let udp_send_buffer_size = raw.udp_send_buffer_size;
+        let throughput_time_constant = Duration::from_millis(raw.throughput_time_constant.get());
+        let stats_interval = raw
+            .stats_interval
+            .map(|interval| Duration::from_millis(interval.get()));
+        let metrics_log_interval = raw
+            .metrics_log_interval
+            .map(|interval| Duration::from_millis(interval.get()));
+        let first_byte_timeout = raw
+            .first_byte_timeout
+            .map(|timeout| Duration::from_millis(timeout.get()));
+        let command_read_timeout = raw
+            .command_read_timeout
+            .map(|timeout| Duration::from_millis(timeout.get()));
+        let close_on_truncated_command = raw.close_on_truncated_command;
+        let max_domain_length = raw.max_domain_length.map(|len| len.get() as usize);
+        let max_resolved_addresses = raw.max_resolved_addresses.map(NonZeroUsize::get);
+        let resolve_retries = raw.resolve_retries;
+        let resolve_retry_time = Duration::from_millis(raw.resolve_retry_time);
+        let error_log_burst = raw.error_log_burst.map(NonZeroU64::get);
+        let udp_pacing_rate = raw.udp_pacing_rate.map(NonZeroU64::get);
+        let udp_pacing_latency = Duration::from_millis(raw.udp_pacing_latency.get());
+        let udp_address_preference = raw.udp_address_preference;
+        let disable_tcp_nodelay = raw.disable_tcp_nodelay;
+        let reset_on_target_reset = raw.reset_on_target_reset;
         let log_level = raw.log_level;
+        let check = raw.check;
 
         Ok(Self {
             server_config,
+            endpoint_config,
             port,
             token_digest,
             authentication_timeout,
             max_udp_packet_size,
             enable_ipv6,
+            udp_association_reap_threshold,
+            max_udp_packets_per_second,
+            max_tcp_bytes_per_second,
+            strict_authentication,
+            acknowledge_dissociate,
+            max_connections_per_destination,
+            destination_limit_key,
+            max_bytes_per_connection,
+            reject_dissociated_packets,
+            max_concurrent_connects,
+            max_udp_associations,
+            max_udp_associations_per_second,
+            ipv6_flow_label,
+            shutdown_grace_period,
+            disable_udp,
+            connect_udp_sockets,
+            allow_self_relay,
+            max_migrations,
+            max_connection_lifetime,
+            max_pending_connections,
+            max_outbound_connects,
+            buffer_pool_size,
+            coalesce_udp_replies,
+            trace_peers,
+            allowed_clients,
+            udp_receive_buffer_size,
+            udp_send_buffer_size,
+            throughput_time_constant,
+            stats_interval,
+            metrics_log_interval,
+            first_byte_timeout,
+            command_read_timeout,
+            close_on_truncated_command,
+            max_domain_length,
+            max_resolved_addresses,
+            resolve_retries,
+            resolve_retry_time,
+            error_log_burst,
+            udp_pacing_rate,
+            udp_pacing_latency,
+            udp_address_preference,
+            disable_tcp_nodelay,
+            reset_on_target_reset,
             log_level,
+            check,
         })
     }
 }
@@ -117,8 +306,152 @@ struct RawConfig {
     #[serde(default = "default::enable_ipv6")]
     enable_ipv6: bool,
 
+    #[serde(default = "default::udp_association_reap_threshold")]
+    udp_association_reap_threshold: u64,
+
+    #[serde(default = "default::max_udp_packets_per_second")]
+    max_udp_packets_per_second: Option<NonZeroU64>,
+
+    #[serde(default = "default::max_tcp_bytes_per_second")]
+    max_tcp_bytes_per_second: Option<NonZeroU64>,
+
+    #[serde(default = "default::strict_authentication")]
+    strict_authentication: bool,
+
+    #[serde(default = "default::acknowledge_dissociate")]
+    acknowledge_dissociate: bool,
+
+    #[serde(default = "default::max_connections_per_destination")]
+    max_connections_per_destination: Option<NonZeroUsize>,
+
+    #[serde(
+        default = "default::destination_limit_key",
+        deserialize_with = "deserialize_from_str"
+    )]
+    destination_limit_key: DestinationKey,
+
+    #[serde(default = "default::max_bytes_per_connection")]
+    max_bytes_per_connection: Option<NonZeroU64>,
+
+    #[serde(default = "default::reject_dissociated_packets")]
+    reject_dissociated_packets: bool,
+
+    #[serde(default = "default::max_concurrent_connects")]
+    max_concurrent_connects: Option<NonZeroUsize>,
+
+    #[serde(default = "default::max_udp_associations")]
+    max_udp_associations: Option<NonZeroUsize>,
+
+    #[serde(default = "default::max_udp_associations_per_second")]
+    max_udp_associations_per_second: Option<NonZeroU64>,
+
+    #[serde(default = "default::ipv6_flow_label")]
+    ipv6_flow_label: Option<u32>,
+
+    #[serde(default = "default::shutdown_grace_period")]
+    shutdown_grace_period: u64,
+
+    #[serde(default = "default::disable_udp")]
+    disable_udp: bool,
+
+    #[serde(default = "default::connect_udp_sockets")]
+    connect_udp_sockets: bool,
+
+    #[serde(default = "default::allow_self_relay")]
+    allow_self_relay: bool,
+
+    #[serde(default = "default::disable_migration")]
+    disable_migration: bool,
+
+    #[serde(default = "default::max_migrations")]
+    max_migrations: Option<NonZeroUsize>,
+
+    #[serde(default = "default::max_connection_lifetime")]
+    max_connection_lifetime: Option<NonZeroU64>,
+
+    #[serde(default = "default::max_pending_connections")]
+    max_pending_connections: Option<NonZeroUsize>,
+
+    #[serde(default = "default::max_outbound_connects")]
+    max_outbound_connects: Option<NonZeroUsize>,
+
+    #[serde(default = "default::buffer_pool_size")]
+    buffer_pool_size: Option<NonZeroUsize>,
+
+    #[serde(default = "default::coalesce_udp_replies")]
+    coalesce_udp_replies: bool,
+
+    #[serde(default = "default::trace_peers")]
+    trace_peers: Vec<IpAddr>,
+
+    #[serde(default = "default::allowed_clients")]
+    allowed_clients: Vec<String>,
+
+    #[serde(default = "default::udp_receive_buffer_size")]
+    udp_receive_buffer_size: Option<usize>,
+
+    #[serde(default = "default::udp_send_buffer_size")]
+    udp_send_buffer_size: Option<usize>,
+
+    #[serde(default = "default::throughput_time_constant")]
+    throughput_time_constant: NonZeroU64,
+
+    #[serde(default = "default::stats_interval")]
+    stats_interval: Option<NonZeroU64>,
+
+    #[serde(default = "default::metrics_log_interval")]
+    metrics_log_interval: Option<NonZeroU64>,
+
+    #[serde(default = "default::first_byte_timeout")]
+    first_byte_timeout: Option<NonZeroU64>,
+
+    #[serde(default = "default::command_read_timeout")]
+    command_read_timeout: Option<NonZeroU64>,
+
+    #[serde(default = "default::close_on_truncated_command")]
+    close_on_truncated_command: bool,
+
+    #[serde(default = "default::max_domain_length")]
+    max_domain_length: Option<NonZeroU8>,
+
+    #[serde(default = "default::max_resolved_addresses")]
+    max_resolved_addresses: Option<NonZeroUsize>,
+
+    #[serde(default = "default::resolve_retries")]
+    resolve_retries: usize,
+
+    #[serde(default = "default::resolve_retry_time")]
+    resolve_retry_time: u64,
+
+    #[serde(default = "default::error_log_burst")]
+    error_log_burst: Option<NonZeroU64>,
+
+    #[serde(default = "default::udp_pacing_rate")]
+    udp_pacing_rate: Option<NonZeroU64>,
+
+    #[serde(default = "default::udp_pacing_latency")]
+    udp_pacing_latency: NonZeroU64,
+
+    #[serde(
+        default = "default::udp_address_preference",
+        deserialize_with = "deserialize_from_str"
+    )]
+    udp_address_preference: AddressPreference,
+
+    #[serde(default = "default::disable_tcp_nodelay")]
+    disable_tcp_nodelay: bool,
+
+    #[serde(default = "default::reset_on_target_reset")]
+    reset_on_target_reset: bool,
+
+    #[serde(default = "default::min_quic_version")]
+    min_quic_version: Option<String>,
+
     #[serde(default = "default::log_level")]
     log_level: LevelFilter,
+
+    #[serde(skip)]
+    check: bool,
 }
 
 impl Default for RawConfig {
@@ -134,13 +467,63 @@ impl Default for RawConfig {
             alpn: default::alpn(),
             max_udp_packet_size: default::max_udp_packet_size(),
             enable_ipv6: default::enable_ipv6(),
+            udp_association_reap_threshold: default::udp_association_reap_threshold(),
+            max_udp_packets_per_second: default::max_udp_packets_per_second(),
+            max_tcp_bytes_per_second: default::max_tcp_bytes_per_second(),
+            strict_authentication: default::strict_authentication(),
+            acknowledge_dissociate: default::acknowledge_dissociate(),
+            max_connections_per_destination: default::max_connections_per_destination(),
+            destination_limit_key: default::destination_limit_key(),
+            max_bytes_per_connection: default::max_bytes_per_connection(),
+            reject_dissociated_packets: default::reject_dissociated_packets(),
+            max_concurrent_connects: default::max_concurrent_connects(),
+            max_udp_associations: default::max_udp_associations(),
+            max_udp_associations_per_second: default::max_udp_associations_per_second(),
+            ipv6_flow_label: default::ipv6_flow_label(),
+            shutdown_grace_period: default::shutdown_grace_period(),
+            disable_udp: default::disable_udp(),
+            connect_udp_sockets: default::connect_udp_sockets(),
+            allow_self_relay: default::allow_self_relay(),
+            disable_migration: default::disable_migration(),
+            max_migrations: default::max_migrations(),
+            max_connection_lifetime: default::max_connection_lifetime(),
+            max_pending_connections: default::max_pending_connections(),
+            max_outbound_connects: default::max_outbound_connects(),
+            buffer_pool_size: default::buffer_pool_size(),
+            coalesce_udp_replies: default::coalesce_udp_replies(),
+            trace_peers: default::trace_peers(),
+            allowed_clients: default::allowed_clients(),
+            udp_receive_buffer_size: default::udp_receive_buffer_size(),
+            udp_send_buffer_size: default::udp_send_buffer_size(),
+            throughput_time_constant: default::throughput_time_constant(),
+            stats_interval: default::stats_interval(),
+            metrics_log_interval: default::metrics_log_interval(),
+            first_byte_timeout: default::first_byte_timeout(),
+            command_read_timeout: default::command_read_timeout(),
+            close_on_truncated_command: default::close_on_truncated_command(),
+            max_domain_length: default::max_domain_length(),
+            max_resolved_addresses: default::max_resolved_addresses(),
+            resolve_retries: default::resolve_retries(),
+            resolve_retry_time: default::resolve_retry_time(),
+            error_log_burst: default::error_log_burst(),
+            udp_pacing_rate: default::udp_pacing_rate(),
+            udp_pacing_latency: default::udp_pacing_latency(),
+            udp_address_preference: default::udp_address_preference(),
+            disable_tcp_nodelay: default::disable_tcp_nodelay(),
+            reset_on_target_reset: default::reset_on_target_reset(),
+            min_quic_version: default::min_quic_version(),
             log_level: default::log_level(),
+            check: false,
         }
     }
 }
 
 impl RawConfig {
-    fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
+    fn parse<A>(args: A) -> Result<Self, ConfigError>
+    where
+        A: IntoIterator,
+        A::Item: AsRef<OsStr>,
+    {
         let mut opts = Options::new();
 
         opts.optopt(
@@ -204,12 +587,316 @@ impl RawConfig {
         opts.optopt(
             "",
             "max-udp-packet-size",
-            "Set the maximum UDP packet size, in bytes. Excess bytes may be discarded. Default: 1536",
+            "Set the maximum UDP packet size, in bytes. Larger packets are dropped. Default: 1536",
             "MAX_UDP_PACKET_SIZE",
         );
 
         opts.optflag("", "enable-ipv6", "Enable IPv6 support");
 
+        opts.optopt(
+            "",
+            "udp-association-reap-threshold",
+            "Set the idle time after which UDP associations are reclaimed when the server receives SIGUSR1, in milliseconds. Default: 60000",
+            "UDP_ASSOCIATION_REAP_THRESHOLD",
+        );
+
+        opts.optopt(
+            "",
+            "max-udp-packets-per-second",
+            "Set the maximum number of UDP packets relayed per second for each connection. Excess packets are dropped. If not set, UDP packets will not be rate limited",
+            "MAX_UDP_PACKETS_PER_SECOND",
+        );
+
+        opts.optopt(
+            "",
+            "max-tcp-bytes-per-second",
+            "Set the maximum TCP relay throughput for each connection, in bytes per second. This limit is shared by all TCP streams of a connection and does not apply to UDP. If not set, TCP relaying will not be rate limited",
+            "MAX_TCP_BYTES_PER_SECOND",
+        );
+
+        opts.optflag(
+            "",
+            "strict-authentication",
+            "Close connections whose first unidirectional stream carries a command other than the authentication. Commands on other streams may arrive before the authentication, as QUIC streams are not ordered, and are held until it arrives in both modes",
+        );
+
+        opts.optflag(
+            "",
+            "acknowledge-dissociate",
+            "Send a `Dissociate` command back to the client after a UDP association is dissociated and its socket is closed. Clients that do not accept unidirectional streams from the server will ignore it",
+        );
+
+        opts.optopt(
+            "",
+            "max-connections-per-destination",
+            "Set the maximum number of concurrent TCP connections relayed to the same destination, across all clients. Excess connect requests are refused. If not set, connections will not be limited",
+            "MAX_CONNECTIONS_PER_DESTINATION",
+        );
+
+        opts.optopt(
+            "",
+            "destination-limit-key",
+            r#"Set how destinations are grouped for `--max-connections-per-destination`. Available: "host", "host_port". Default: "host_port""#,
+            "DESTINATION_LIMIT_KEY",
+        );
+
+        opts.optopt(
+            "",
+            "max-bytes-per-connection",
+            "Set the maximum total bytes relayed in both directions for each connection, including TCP streams and UDP packets. The connection is closed once it is reached. If not set, connections will not be limited",
+            "MAX_BYTES_PER_CONNECTION",
+        );
+
+        opts.optflag(
+            "",
+            "reject-dissociated-packets",
+            "Drop packets carrying an association ID that has already been dissociated on the connection, instead of implicitly creating a new association for it. Packets with never-used association IDs always create an association, and dissociating an unknown ID is always a no-op",
+        );
+
+        opts.optopt(
+            "",
+            "max-concurrent-connects",
+            "Set the maximum number of concurrent TCP relays for each connection. Further `Connect` commands wait until a relay finishes. If not set, TCP relays will not be limited",
+            "MAX_CONCURRENT_CONNECTS",
+        );
+
+        opts.optopt(
+            "",
+            "max-udp-associations",
+            "Set the maximum number of UDP associations for each connection. Packets that would create a new association beyond it are dropped. If not set, UDP associations will not be limited",
+            "MAX_UDP_ASSOCIATIONS",
+        );
+
+        opts.optopt(
+            "",
+            "max-udp-associations-per-second",
+            "Set the maximum number of UDP associations created per second for each connection. Packets that would create a new association beyond it are dropped. If not set, the creation of UDP associations will not be rate limited",
+            "MAX_UDP_ASSOCIATIONS_PER_SECOND",
+        );
+
+        opts.optopt(
+            "",
+            "ipv6-flow-label",
+            "Set the IPv6 flow label (0 - 1048575) of relayed TCP connections to IPv6 destinations. Only supported on Linux and ignored on other platforms. If not set, the flow label will be chosen by the system",
+            "IPV6_FLOW_LABEL",
+        );
+
+        opts.optopt(
+            "",
+            "shutdown-grace-period",
+            "Set the maximum time to wait for existing connections to finish after receiving SIGTERM or SIGINT, in milliseconds. New connections are refused during this period, and the remaining connections are closed after it. Default: 10000",
+            "SHUTDOWN_GRACE_PERIOD",
+        );
+
+        opts.optflag(
+            "",
+            "disable-udp",
+            "Disable UDP relaying. `Packet` commands are refused with a `Dissociate` command and no UDP socket is created. TCP relaying is not affected. As the protocol has no command to open an association, the first UDP ASSOCIATE of a client succeeds, and is closed once the refusal arrives",
+        );
+
+        opts.optflag(
+            "",
+            "connect-udp-sockets",
+            "Connect the socket of a UDP association to the destination of its first packet, so ICMP errors are reported, until a packet to another destination is sent. While connected, packets from any other address are dropped by the system, which breaks full-cone NAT traversal such as STUN. Only supported on Linux",
+        );
+
+        opts.optflag(
+            "",
+            "allow-self-relay",
+            "Allow relaying TCP streams and UDP packets to the listening port of the server on any of its own addresses. By default, such destinations are refused, as relaying to them only loops traffic back into the server. Domains are checked once resolved, against the addresses the host had when the server started",
+        );
+
+        opts.optflag(
+            "",
+            "disable-migration",
+            "Disable QUIC connection migration. Packets from a client whose address has changed are dropped",
+        );
+
+        opts.optopt(
+            "",
+            "max-migrations",
+            "Set the maximum number of address migrations for each connection. Connections migrating more often are closed. If not set, migrations will not be limited",
+            "MAX_MIGRATIONS",
+        );
+
+        opts.optopt(
+            "",
+            "max-connection-lifetime",
+            "Close connections that have been open for longer than this time, in milliseconds. The client is told to reconnect, and the client in this repository does so without failing new requests. Streams still being relayed on the closed connection are interrupted. If not set, connections can stay open indefinitely",
+            "MAX_CONNECTION_LIFETIME",
+        );
+
+        opts.optopt(
+            "",
+            "max-pending-connections",
+            "Set the maximum number of connections that are handshaking or waiting for authentication at the same time. New connections beyond it are refused. If not set, pending connections will not be limited",
+            "MAX_PENDING_CONNECTIONS",
+        );
+
+        opts.optopt(
+            "",
+            "max-outbound-connects",
+            "Set the maximum number of `Connect` commands resolving and connecting to their targets at the same time, across all connections. Further `Connect` commands wait until one of them is connected or has failed. The relaying that follows is not counted. If not set, outbound connects will not be limited",
+            "MAX_OUTBOUND_CONNECTS",
+        );
+
+        opts.optopt(
+            "",
+            "buffer-pool-size",
+            "Keep up to this many relay buffers released by finished TCP streams, and reuse them for new streams instead of allocating. If not set, each stream allocates its own buffers",
+            "BUFFER_POOL_SIZE",
+        );
+
+        opts.optflag(
+            "",
+            "coalesce-udp-replies",
+            "Pack UDP packets waiting to be sent back to a client into as few QUIC datagrams as possible, each packet keeping its own `Packet` header. Only applies to the UDP relay mode `native`. This is not negotiated: clients must split datagrams by the `Packet` length, which earlier releases of the client do not, so only enable this when all clients do",
+        );
+
+        opts.optmulti(
+            "",
+            "trace-peer",
+            "Log every command sent to and received from connections of this IP address, at the trace log level. This option can be used multiple times to trace multiple peers",
+            "TRACE_PEER",
+        );
+
+        opts.optmulti(
+            "",
+            "allow-client",
+            "Only accept connections from this IP address or CIDR range (e.g. 192.0.2.0/24). Connections from other addresses are dropped before the handshake completes. This option can be used multiple times. If not set, connections from all addresses are accepted",
+            "ALLOW_CLIENT",
+        );
+
+        opts.optopt(
+            "",
+            "udp-receive-buffer-size",
+            "Set the receive buffer size (SO_RCVBUF) of the server's UDP socket, in bytes. The OS may clamp it. If not set, the OS default is used",
+            "UDP_RECEIVE_BUFFER_SIZE",
+        );
+
+        opts.optopt(
+            "",
+            "udp-send-buffer-size",
+            "Set the send buffer size (SO_SNDBUF) of the server's UDP socket, in bytes. The OS may clamp it. If not set, the OS default is used",
+            "UDP_SEND_BUFFER_SIZE",
+        );
+
+        opts.optopt(
+            "",
+            "min-quic-version",
+            r#"Set the minimum QUIC version the server accepts. Available: "draft-29" to "draft-34", "v1". Clients offering an older version are answered with a version negotiation packet listing the accepted versions. If not set, all versions supported by quinn are accepted"#,
+            "MIN_QUIC_VERSION",
+        );
+
+        opts.optopt(
+            "",
+            "throughput-time-constant",
+            "Set the time constant of the moving average used to estimate the throughput of each connection, in milliseconds. A smaller value follows changes faster, a larger one is smoother. Default: 5000",
+            "THROUGHPUT_TIME_CONSTANT",
+        );
+
+        opts.optopt(
+            "",
+            "stats-interval",
+            "Log a statistics line for each connection at this interval, in milliseconds, including its throughput, RTT, active TCP streams and UDP associations, and the rates of sent datagrams and congestion events since the previous line. If not set, statistics are not logged periodically",
+            "STATS_INTERVAL",
+        );
+
+        opts.optopt(
+            "",
+            "metrics-log-interval",
+            "Count authentications, closed connections and rejections by reason, and log the totals at this interval, in milliseconds. If not set, nothing is counted",
+            "METRICS_LOG_INTERVAL",
+        );
+
+        opts.optopt(
+            "",
+            "first-byte-timeout",
+            "Close relayed TCP streams on which no data is transferred in either direction within this time after the target is connected, in milliseconds. Streams that have transferred data are not affected, regardless of how long they stay idle afterwards. If not set, streams are never closed for this reason",
+            "FIRST_BYTE_TIMEOUT",
+        );
+
+        opts.optopt(
+            "",
+            "command-read-timeout",
+            "Reset streams on which the command is not fully received within this time after the stream is opened, in milliseconds. This only covers the command header, not the relayed data that follows. If not set, commands can take any time to arrive",
+            "COMMAND_READ_TIMEOUT",
+        );
+
+        opts.optflag(
+            "",
+            "close-on-truncated-command",
+            "Close the connection when a stream ends in the middle of a command. If not set, only the stream is reset and the connection is kept",
+        );
+
+        opts.optopt(
+            "",
+            "max-domain-length",
+            "Refuse `Connect` commands and drop UDP packets whose target domain is longer than this many bytes (1 - 255). IP address targets are not affected. If not set, domains up to the protocol limit of 255 bytes are accepted",
+            "MAX_DOMAIN_LENGTH",
+        );
+
+        opts.optopt(
+            "",
+            "max-resolved-addresses",
+            "Set the maximum number of addresses a domain in a `Connect` command resolves to that the server tries to connect to, in the order they are resolved. The rest are ignored. If not set, all resolved addresses are tried until one is connected",
+            "MAX_RESOLVED_ADDRESSES",
+        );
+
+        opts.optopt(
+            "",
+            "resolve-retries",
+            "Set the maximum number of times the resolution of a domain in a `Connect` command is retried after a temporary failure, such as a DNS server failure or timeout. Retries back off exponentially, starting at 100 milliseconds. Definitive failures, such as a domain that does not exist, are never retried. Default: 0",
+            "RESOLVE_RETRIES",
+        );
+
+        opts.optopt(
+            "",
+            "resolve-retry-time",
+            "Set the total time after which the resolution of a domain is not retried anymore for `--resolve-retries`, in milliseconds. Default: 5000",
+            "RESOLVE_RETRY_TIME",
+        );
+
+        opts.optopt(
+            "",
+            "error-log-burst",
+            "Log at most this many identical connection errors per minute. Further occurrences are counted, and a summary is logged once the minute ends. If not set, every error is logged",
+            "ERROR_LOG_BURST",
+        );
+
+        opts.optopt(
+            "",
+            "udp-pacing-rate",
+            "Space out the UDP packets sent by each UDP association to at most this many per second, so that bursts from the client do not overrun buffers on the way. Packets that would wait longer than `--udp-pacing-latency` are dropped. If not set, UDP packets are sent as soon as they arrive",
+            "UDP_PACING_RATE",
+        );
+
+        opts.optopt(
+            "",
+            "udp-pacing-latency",
+            "Set the longest time a UDP packet may be delayed by `--udp-pacing-rate`, in milliseconds. Default: 50",
+            "UDP_PACING_LATENCY",
+        );
+
+        opts.optopt(
+            "",
+            "udp-address-preference",
+            r#"Set the address family UDP associations use for destinations given as domains. The socket of each association is bound with the family of its first destination, and later destinations only resolve to addresses of that family. Packets to destinations without an address of that family are dropped. Available: "ipv4_only", "ipv6_only", "prefer_ipv4", "prefer_ipv6". Default: "prefer_ipv4""#,
+            "UDP_ADDRESS_PREFERENCE",
+        );
+
+        opts.optflag(
+            "",
+            "disable-tcp-nodelay",
+            "Keep Nagle's algorithm enabled on the TCP connections to relay targets, which batches small writes at the cost of latency. If not set, `TCP_NODELAY` is set on them",
+        );
+
+        opts.optflag(
+            "",
+            "reset-on-target-reset",
+            "Reset the relayed stream when the target resets its TCP connection, so the client sees the reset. If not set, the stream is finished as if the target closed the connection normally",
+        );
+
         opts.optopt(
             "",
             "log-level",
@@ -217,10 +904,16 @@ impl RawConfig {
             "LOG_LEVEL",
         );
 
+        opts.optflag(
+            "",
+            "check",
+            "Check the configuration, load the certificate and the private key and bind the listening port, then exit without serving. The exit status is non-zero if any of these fails",
+        );
+
         opts.optflag("v", "version", "Print the version");
         opts.optflag("h", "help", "Print this help menu");
 
-        let matches = opts.parse(args.skip(1))?;
+        let matches = opts.parse(args.into_iter().skip(1))?;
 
         if matches.opt_present("help") {
             return Err(ConfigError::Help(opts.usage(env!("CARGO_PKG_NAME"))));
@@ -301,10 +994,175 @@ impl RawConfig {
 
         raw.enable_ipv6 |= matches.opt_present("enable-ipv6");
 
+        if let Some(threshold) = matches.opt_str("udp-association-reap-threshold") {
+            raw.udp_association_reap_threshold = threshold.parse()?;
+        };
+
+        if let Some(rate) = matches.opt_str("max-udp-packets-per-second") {
+            raw.max_udp_packets_per_second = Some(rate.parse()?);
+        };
+
+        if let Some(rate) = matches.opt_str("max-tcp-bytes-per-second") {
+            raw.max_tcp_bytes_per_second = Some(rate.parse()?);
+        };
+
+        raw.strict_authentication |= matches.opt_present("strict-authentication");
+
+        raw.acknowledge_dissociate |= matches.opt_present("acknowledge-dissociate");
+
+        if let Some(max) = matches.opt_str("max-connections-per-destination") {
+            raw.max_connections_per_destination = Some(max.parse()?);
+        };
+
+        if let Some(key) = matches.opt_str("destination-limit-key") {
+            raw.destination_limit_key = key.parse()?;
+        };
+
+        if let Some(max) = matches.opt_str("max-bytes-per-connection") {
+            raw.max_bytes_per_connection = Some(max.parse()?);
+        };
+
+        raw.reject_dissociated_packets |= matches.opt_present("reject-dissociated-packets");
+
+        if let Some(max) = matches.opt_str("max-concurrent-connects") {
+            raw.max_concurrent_connects = Some(max.parse()?);
+        };
+
+        if let Some(max) = matches.opt_str("max-udp-associations") {
+            raw.max_udp_associations = Some(max.parse()?);
+        };
+
+        if let Some(max) = matches.opt_str("max-udp-associations-per-second") {
+            raw.max_udp_associations_per_second = Some(max.parse()?);
+        };
+
+        if let Some(label) = matches.opt_str("ipv6-flow-label") {
+            raw.ipv6_flow_label = Some(label.parse()?);
+        };
+
+        if let Some(period) = matches.opt_str("shutdown-grace-period") {
+            raw.shutdown_grace_period = period.parse()?;
+        };
+
+        raw.disable_udp |= matches.opt_present("disable-udp");
+
+        raw.connect_udp_sockets |= matches.opt_present("connect-udp-sockets");
+
+        raw.allow_self_relay |= matches.opt_present("allow-self-relay");
+
+        raw.disable_migration |= matches.opt_present("disable-migration");
+
+        if let Some(max) = matches.opt_str("max-migrations") {
+            raw.max_migrations = Some(max.parse()?);
+        };
+
+        if let Some(lifetime) = matches.opt_str("max-connection-lifetime") {
+            raw.max_connection_lifetime = Some(lifetime.parse()?);
+        };
+
+        if let Some(max) = matches.opt_str("max-pending-connections") {
+            raw.max_pending_connections = Some(max.parse()?);
+        };
+
+        if let Some(max) = matches.opt_str("max-outbound-connects") {
+            raw.max_outbound_connects = Some(max.parse()?);
+        };
+
+        if let Some(size) = matches.opt_str("buffer-pool-size") {
+            raw.buffer_pool_size = Some(size.parse()?);
+        };
+
+        raw.coalesce_udp_replies |= matches.opt_present("coalesce-udp-replies");
+
+        let trace_peers = matches.opt_strs("trace-peer");
+
+        if !trace_peers.is_empty() {
+            raw.trace_peers = trace_peers
+                .iter()
+                .map(|ip| ip.parse())
+                .collect::<Result<_, _>>()?;
+        }
+
+        let allowed_clients = matches.opt_strs("allow-client");
+
+        if !allowed_clients.is_empty() {
+            raw.allowed_clients = allowed_clients;
+        }
+
+        if let Some(size) = matches.opt_str("udp-receive-buffer-size") {
+            raw.udp_receive_buffer_size = Some(size.parse()?);
+        };
+
+        if let Some(size) = matches.opt_str("udp-send-buffer-size") {
+            raw.udp_send_buffer_size = Some(size.parse()?);
+        };
+
+        raw.min_quic_version = matches.opt_str("min-quic-version").or(raw.min_quic_version);
+
+        if let Some(time_constant) = matches.opt_str("throughput-time-constant") {
+            raw.throughput_time_constant = time_constant.parse()?;
+        };
+
+        if let Some(interval) = matches.opt_str("stats-interval") {
+            raw.stats_interval = Some(interval.parse()?);
+        };
+
+        if let Some(interval) = matches.opt_str("metrics-log-interval") {
+            raw.metrics_log_interval = Some(interval.parse()?);
+        };
+
+        if let Some(timeout) = matches.opt_str("first-byte-timeout") {
+            raw.first_byte_timeout = Some(timeout.parse()?);
+        };
+
+        if let Some(timeout) = matches.opt_str("command-read-timeout") {
+            raw.command_read_timeout = Some(timeout.parse()?);
+        };
+
+        raw.close_on_truncated_command |= matches.opt_present("close-on-truncated-command");
+
+        if let Some(len) = matches.opt_str("max-domain-length") {
+            raw.max_domain_length = Some(len.parse()?);
+        };
+
+        if let Some(max) = matches.opt_str("max-resolved-addresses") {
+            raw.max_resolved_addresses = Some(max.parse()?);
+        };
+
+        if let Some(retries) = matches.opt_str("resolve-retries") {
+            raw.resolve_retries = retries.parse()?;
+        };
+
+        if let Some(time) = matches.opt_str("resolve-retry-time") {
+            raw.resolve_retry_time = time.parse()?;
+        };
+
+        if let Some(burst) = matches.opt_str("error-log-burst") {
+            raw.error_log_burst = Some(burst.parse()?);
+        };
+
+        if let Some(rate) = matches.opt_str("udp-pacing-rate") {
+            raw.udp_pacing_rate = Some(rate.parse()?);
+        };
+
+        if let Some(latency) = matches.opt_str("udp-pacing-latency") {
+            raw.udp_pacing_latency = latency.parse()?;
+        };
+
+        if let Some(pref) = matches.opt_str("udp-address-preference") {
+            raw.udp_address_preference = pref.parse()?;
+        };
+
+        raw.disable_tcp_nodelay |= matches.opt_present("disable-tcp-nodelay");
+
+        raw.reset_on_target_reset |= matches.opt_present("reset-on-target-reset");
+
         if let Some(log_level) = matches.opt_str("log-level") {
             raw.log_level = log_level.parse()?;
         };
 
+        raw.check = matches.opt_present("check");
+
         Ok(raw)
     }
 
@@ -337,6 +1195,63 @@ impl FromStr for CongestionController {
     }
 }
 
+impl FromStr for DestinationKey {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("host") {
+            Ok(DestinationKey::Host)
+        } else if s.eq_ignore_ascii_case("host_port") || s.eq_ignore_ascii_case("hostport") {
+            Ok(DestinationKey::HostPort)
+        } else {
+            Err(ConfigError::InvalidDestinationLimitKey)
+        }
+    }
+}
+
+impl FromStr for AddressPreference {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("ipv4_only") {
+            Ok(AddressPreference::Ipv4Only)
+        } else if s.eq_ignore_ascii_case("ipv6_only") {
+            Ok(AddressPreference::Ipv6Only)
+        } else if s.eq_ignore_ascii_case("prefer_ipv4") {
+            Ok(AddressPreference::PreferIpv4)
+        } else if s.eq_ignore_ascii_case("prefer_ipv6") {
+            Ok(AddressPreference::PreferIpv6)
+        } else {
+            Err(ConfigError::InvalidUdpAddressPreference)
+        }
+    }
+}
+
+fn parse_quic_version(s: &str) -> Result<u32, ConfigError> {
+    if s.eq_ignore_ascii_case("v1") || s == "1" {
+        return Ok(1);
+    }
+
+    match s.to_ascii_lowercase().strip_prefix("draft-") {
+        Some(draft) => match draft.parse::<u32>() {
+            Ok(draft) if DEFAULT_SUPPORTED_VERSIONS.contains(&(0xff00_0000 | draft)) => {
+                Ok(0xff00_0000 | draft)
+            }
+            _ => Err(ConfigError::InvalidQuicVersion),
+        },
+        None => Err(ConfigError::InvalidQuicVersion),
+    }
+}
+
+// drafts are ordered by their number, and all of them are older than v1
+fn quic_version_rank(version: u32) -> u32 {
+    if version == 1 {
+        u32::MAX
+    } else {
+        version & 0xffff
+    }
+}
+
 fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: FromStr,
@@ -374,6 +1289,186 @@ mod default {
         false
     }
 
+    pub(super) const fn udp_association_reap_threshold() -> u64 {
+        60000
+    }
+
+    pub(super) const fn max_udp_packets_per_second() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn max_tcp_bytes_per_second() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn strict_authentication() -> bool {
+        false
+    }
+
+    pub(super) const fn acknowledge_dissociate() -> bool {
+        false
+    }
+
+    pub(super) const fn max_connections_per_destination() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn destination_limit_key() -> DestinationKey {
+        DestinationKey::HostPort
+    }
+
+    pub(super) const fn max_bytes_per_connection() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn reject_dissociated_packets() -> bool {
+        false
+    }
+
+    pub(super) const fn max_concurrent_connects() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn max_udp_associations() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn max_udp_associations_per_second() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn ipv6_flow_label() -> Option<u32> {
+        None
+    }
+
+    pub(super) const fn shutdown_grace_period() -> u64 {
+        10000
+    }
+
+    pub(super) const fn disable_udp() -> bool {
+        false
+    }
+
+    pub(super) const fn connect_udp_sockets() -> bool {
+        false
+    }
+
+    pub(super) const fn allow_self_relay() -> bool {
+        false
+    }
+
+    pub(super) const fn disable_migration() -> bool {
+        false
+    }
+
+    pub(super) const fn max_migrations() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn max_connection_lifetime() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn max_pending_connections() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn max_outbound_connects() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn buffer_pool_size() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn coalesce_udp_replies() -> bool {
+        false
+    }
+
+    pub(super) const fn trace_peers() -> Vec<IpAddr> {
+        Vec::new()
+    }
+
+    pub(super) const fn allowed_clients() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub(super) const fn udp_receive_buffer_size() -> Option<usize> {
+        None
+    }
+
+    pub(super) const fn udp_send_buffer_size() -> Option<usize> {
+        None
+    }
+
+    pub(super) fn throughput_time_constant() -> NonZeroU64 {
+        NonZeroU64::new(5000).unwrap()
+    }
+
+    pub(super) const fn stats_interval() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn metrics_log_interval() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn first_byte_timeout() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn command_read_timeout() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn close_on_truncated_command() -> bool {
+        false
+    }
+
+    pub(super) const fn max_domain_length() -> Option<NonZeroU8> {
+        None
+    }
+
+    pub(super) const fn max_resolved_addresses() -> Option<NonZeroUsize> {
+        None
+    }
+
+    pub(super) const fn resolve_retries() -> usize {
+        0
+    }
+
+    pub(super) const fn resolve_retry_time() -> u64 {
+        5000
+    }
+
+    pub(super) const fn error_log_burst() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) const fn udp_pacing_rate() -> Option<NonZeroU64> {
+        None
+    }
+
+    pub(super) fn udp_pacing_latency() -> NonZeroU64 {
+        NonZeroU64::new(50).unwrap()
+    }
+
+    pub(super) const fn udp_address_preference() -> AddressPreference {
+        AddressPreference::PreferIpv4
+    }
+
+    pub(super) const fn disable_tcp_nodelay() -> bool {
+        false
+    }
+
+    pub(super) const fn reset_on_target_reset() -> bool {
+        false
+    }
+
+    pub(super) const fn min_quic_version() -> Option<String> {
+        None
+    }
+
     pub(super) const fn log_level() -> LevelFilter {
         LevelFilter::Info
     }
@@ -397,10 +1492,75 @@ pub enum ConfigError {
     MissingOption(&'static str),
     #[error(transparent)]
     ParseInt(#[from] ParseIntError),
+    #[error(transparent)]
+    ParseIpAddr(#[from] AddrParseError),
+    #[error(transparent)]
+    ParseIpNetwork(#[from] ParseIpNetworkError),
     #[error("Invalid congestion controller")]
     InvalidCongestionController,
+    #[error("Invalid destination limit key")]
+    InvalidDestinationLimitKey,
+    #[error("Invalid UDP address preference")]
+    InvalidUdpAddressPreference,
+    #[error("IPv6 flow label must be within 20 bits")]
+    InvalidFlowLabel,
+    #[error("Invalid QUIC version")]
+    InvalidQuicVersion,
     #[error(transparent)]
     ParseLogLevel(#[from] ParseLevelError),
     #[error("Failed to load certificate / private key: {0}")]
     Rustls(#[from] RustlsError),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_quic_version, quic_version_rank, ConfigError};
+    use crate::connection::{AddressPreference, DestinationKey};
+
+    #[test]
+    fn destination_keys_are_parsed() {
+        assert!(matches!("host".parse(), Ok(DestinationKey::Host)));
+        assert!(matches!("HOST_PORT".parse(), Ok(DestinationKey::HostPort)));
+        assert!(matches!("hostport".parse(), Ok(DestinationKey::HostPort)));
+        assert!(matches!(
+            "port".parse::<DestinationKey>(),
+            Err(ConfigError::InvalidDestinationLimitKey)
+        ));
+    }
+
+    #[test]
+    fn address_preferences_are_parsed() {
+        assert!(matches!(
+            "ipv4_only".parse(),
+            Ok(AddressPreference::Ipv4Only)
+        ));
+        assert!(matches!(
+            "IPv6_Only".parse(),
+            Ok(AddressPreference::Ipv6Only)
+        ));
+        assert!(matches!(
+            "prefer_ipv4".parse(),
+            Ok(AddressPreference::PreferIpv4)
+        ));
+        assert!(matches!(
+            "prefer_ipv6".parse(),
+            Ok(AddressPreference::PreferIpv6)
+        ));
+        assert!(matches!(
+            "ipv4".parse::<AddressPreference>(),
+            Err(ConfigError::InvalidUdpAddressPreference)
+        ));
+    }
+
+    #[test]
+    fn quic_versions_are_parsed_and_ranked() {
+        assert_eq!(parse_quic_version("v1").unwrap(), 1);
+        assert_eq!(parse_quic_version("1").unwrap(), 1);
+        assert_eq!(parse_quic_version("Draft-29").unwrap(), 0xff00_001d);
+        assert!(parse_quic_version("draft-1").is_err());
+        assert!(parse_quic_version("v2").is_err());
+
+        assert!(quic_version_rank(1) > quic_version_rank(0xff00_0022));
+        assert!(quic_version_rank(0xff00_0022) > quic_version_rank(0xff00_001d));
+    }
+}
@@ -26,8 +26,13 @@ impl IsAuthenticated {
         }
     }
 
-    pub fn set_authenticated(&self) {
-        self.is_authenticated.store(true, Ordering::Release);
+    /// Returns `false` if the connection was already authenticated
+    pub fn set_authenticated(&self) -> bool {
+        !self.is_authenticated.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn check(&self) -> bool {
+        self.is_authenticated.load(Ordering::Acquire)
     }
 
     pub fn wake(&self) {
@@ -47,7 +52,50 @@ impl Future for IsAuthenticated {
             Poll::Ready(true)
         } else {
             self.broadcast.lock().push(cx.waker().clone());
-            Poll::Pending
+
+            // the authentication may have completed before the waker was registered
+            if self.is_connection_closed.check() {
+                Poll::Ready(false)
+            } else if self.is_authenticated.load(Ordering::Acquire) {
+                Poll::Ready(true)
+            } else {
+                Poll::Pending
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{IsAuthenticated, IsClosed};
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn early_commands_wait_for_the_authentication() {
+        let is_authenticated = IsAuthenticated::new(IsClosed::new());
+        let early_command = tokio::spawn(is_authenticated.clone());
+
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(!early_command.is_finished());
+
+        assert!(is_authenticated.set_authenticated());
+        is_authenticated.wake();
+
+        assert!(early_command.await.unwrap());
+        assert!(!is_authenticated.set_authenticated());
+    }
+
+    #[tokio::test]
+    async fn early_commands_are_dropped_when_the_connection_closes() {
+        let is_closed = IsClosed::new();
+        let is_authenticated = IsAuthenticated::new(is_closed.clone());
+        let early_command = tokio::spawn(is_authenticated.clone());
+
+        time::sleep(Duration::from_millis(10)).await;
+        is_closed.set_closed();
+        is_authenticated.wake();
+
+        assert!(!early_command.await.unwrap());
+    }
+}
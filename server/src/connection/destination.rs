@@ -0,0 +1,248 @@
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tuic_protocol::Address;
+
+#[derive(Clone, Copy)]
+pub enum DestinationKey {
+    Host,
+    HostPort,
+}
+
+pub struct DestinationLimiter {
+    max_connections: usize,
+    key: DestinationKey,
+    connections: Mutex<HashMap<String, usize>>,
+}
+
+impl DestinationLimiter {
+    pub fn new(max_conns: usize, key: DestinationKey) -> Self {
+        Self {
+            max_connections: max_conns,
+            key,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>, addr: &Address) -> Option<DestinationGuard> {
+        let key = match (self.key, addr) {
+            (DestinationKey::Host, Address::DomainAddress(hostname, _)) => {
+                hostname.to_ascii_lowercase()
+            }
+            (DestinationKey::Host, Address::SocketAddress(addr)) => addr.ip().to_string(),
+            (DestinationKey::HostPort, Address::DomainAddress(hostname, port)) => {
+                format!("{}:{port}", hostname.to_ascii_lowercase())
+            }
+            (DestinationKey::HostPort, Address::SocketAddress(addr)) => addr.to_string(),
+        };
+
+        let mut conns = self.connections.lock();
+        let count = conns.entry(key.clone()).or_insert(0);
+
+        if *count < self.max_connections {
+            *count += 1;
+
+            Some(DestinationGuard {
+                limiter: self.clone(),
+                key,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The address the server listens on, which relayed traffic is not sent to unless `--allow-self-relay` is set
+#[derive(Clone)]
+pub struct ListenAddress {
+    addr: SocketAddr,
+    local_ips: Option<Arc<HashSet<IpAddr>>>,
+}
+
+impl ListenAddress {
+    /// The addresses assigned to the host are collected once here, so checking a destination does not need a system call
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            local_ips: local_ips().map(Arc::new),
+        }
+    }
+
+    /// Whether traffic sent to `addr` would reach the server itself
+    ///
+    /// The server listens on the unspecified address, so this is the case for every address assigned to the host. Where those can not be listed, they are told apart by whether a socket can be bound to them
+    pub fn matches(&self, addr: SocketAddr) -> bool {
+        if addr.port() != self.addr.port() {
+            return false;
+        }
+
+        let ip = addr.ip().to_canonical();
+
+        if ip.is_ipv6() && self.addr.is_ipv4() {
+            return false;
+        }
+
+        ip.is_unspecified()
+            || ip.is_loopback()
+            || match &self.local_ips {
+                Some(local_ips) => local_ips.contains(&ip),
+                None => std::net::UdpSocket::bind((ip, 0)).is_ok(),
+            }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn local_ips() -> Option<HashSet<IpAddr>> {
+    use std::{
+        io::Error as IoError,
+        net::{Ipv4Addr, Ipv6Addr},
+        ptr,
+    };
+
+    let mut ifaddrs = ptr::null_mut();
+
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        let err = IoError::last_os_error();
+        log::warn!("Failed to list the addresses of the host: {err}");
+        return None;
+    }
+
+    let mut ips = HashSet::new();
+    let mut cur = ifaddrs;
+
+    while let Some(ifaddr) = unsafe { cur.as_ref() } {
+        if let Some(addr) = unsafe { ifaddr.ifa_addr.as_ref() } {
+            match libc::c_int::from(addr.sa_family) {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                    ips.insert(IpAddr::from(Ipv4Addr::from(u32::from_be(
+                        addr.sin_addr.s_addr,
+                    ))));
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                    ips.insert(IpAddr::from(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+
+        cur = ifaddr.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Some(ips)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn local_ips() -> Option<HashSet<IpAddr>> {
+    None
+}
+
+pub struct DestinationGuard {
+    limiter: Arc<DestinationLimiter>,
+    key: String,
+}
+
+impl Drop for DestinationGuard {
+    fn drop(&mut self) {
+        let mut conns = self.limiter.connections.lock();
+
+        if let Some(count) = conns.get_mut(&self.key) {
+            *count -= 1;
+
+            if *count == 0 {
+                conns.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_ips, DestinationKey, DestinationLimiter, ListenAddress};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+    };
+    use tuic_protocol::Address;
+
+    const PORT: u16 = 443;
+
+    fn domain(host: &str, port: u16) -> Address {
+        Address::DomainAddress(host.to_owned(), port)
+    }
+
+    #[test]
+    fn connections_are_limited_per_host() {
+        let limiter = Arc::new(DestinationLimiter::new(2, DestinationKey::Host));
+
+        let first = limiter.acquire(&domain("example.com", 80));
+        let second = limiter.acquire(&domain("EXAMPLE.com", 443));
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.acquire(&domain("example.com", 8080)).is_none());
+        assert!(limiter.acquire(&domain("example.net", 80)).is_some());
+
+        drop(first);
+        assert!(limiter.acquire(&domain("example.com", 80)).is_some());
+
+        drop(second);
+        assert!(limiter.connections.lock().is_empty());
+    }
+
+    #[test]
+    fn connections_are_limited_per_host_and_port() {
+        let limiter = Arc::new(DestinationLimiter::new(1, DestinationKey::HostPort));
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+
+        let _guard = limiter.acquire(&addr).unwrap();
+        assert!(limiter.acquire(&addr).is_none());
+        assert!(limiter
+            .acquire(&Address::SocketAddress(SocketAddr::from((
+                Ipv4Addr::new(192, 0, 2, 1),
+                81
+            ))))
+            .is_some());
+
+        let _guard = limiter.acquire(&domain("example.com", 80)).unwrap();
+        assert!(limiter.acquire(&domain("Example.COM", 80)).is_none());
+        assert!(limiter.acquire(&domain("example.com", 443)).is_some());
+    }
+
+    #[test]
+    fn own_addresses_on_the_listening_port_match() {
+        let listen_addr = ListenAddress::new(SocketAddr::from((Ipv6Addr::UNSPECIFIED, PORT)));
+
+        assert!(listen_addr.matches(SocketAddr::from((Ipv4Addr::LOCALHOST, PORT))));
+        assert!(listen_addr.matches(SocketAddr::from((Ipv6Addr::LOCALHOST, PORT))));
+        assert!(listen_addr.matches(SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT))));
+        assert!(listen_addr.matches(SocketAddr::from((
+            Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+            PORT
+        ))));
+
+        for ip in local_ips().into_iter().flatten() {
+            assert!(listen_addr.matches(SocketAddr::new(ip, PORT)), "{ip}");
+        }
+    }
+
+    #[test]
+    fn other_ports_and_hosts_do_not_match() {
+        let listen_addr = ListenAddress::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)));
+
+        assert!(!listen_addr.matches(SocketAddr::from((Ipv4Addr::LOCALHOST, PORT + 1))));
+        assert!(!listen_addr.matches(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), PORT))));
+        assert!(!listen_addr.matches(SocketAddr::from((Ipv6Addr::LOCALHOST, PORT))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn host_addresses_include_the_loopback() {
+        let ips = local_ips().unwrap();
+        assert!(ips.contains(&Ipv4Addr::LOCALHOST.into()));
+    }
+}
@@ -1,20 +1,40 @@
-use super::{task, Connection, UdpPacketSource};
+use super::{
+    task::{self, ByteAccounting, ConnectOutcome},
+    udp::RecvPacket,
+    Connection, UdpPacketSource,
+};
+use crate::metrics::RejectionReason;
 use bytes::Bytes;
-use quinn::{RecvStream, SendStream, VarInt};
+use quinn::{RecvStream, SendStream, StreamId, VarInt};
+use std::{io::ErrorKind, sync::atomic::Ordering, time::Instant};
 use thiserror::Error;
-use tuic_protocol::{Address, Command, Error as ProtocolError};
+use tokio::time;
+use tuic_protocol::{Address, CloseCode, Command, Error as ProtocolError};
 
 impl Connection {
     pub async fn process_uni_stream(&self, mut stream: RecvStream) -> Result<(), DispatchError> {
-        let cmd = Command::read_from(&mut stream).await?;
+        self.check_migration()?;
+
+        let cmd = match self.read_command(&mut stream).await? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+
+        self.trace_command("recv", &cmd);
+        check_direction(&cmd)?;
 
         if let Command::Authenticate { digest } = cmd {
-            let rmt_addr = self.controller.remote_address();
+            let ctx = self.context();
 
-            if digest == self.expected_token_digest {
-                log::debug!("[{rmt_addr}] [authentication]");
+            if digest == self.config.expected_token_digest {
+                log::debug!("[{ctx}] [authentication]");
+
+                if self.is_authenticated.set_authenticated() {
+                    self.config
+                        .metrics
+                        .on_authenticated(self.controller.remote_address());
+                }
 
-                self.is_authenticated.set_authenticated();
                 self.is_authenticated.wake();
                 return Ok(());
             } else {
@@ -26,8 +46,15 @@ impl Connection {
             }
         }
 
+        check_strict_authentication(
+            self.config.strict_authentication,
+            stream.id(),
+            self.is_authenticated.check(),
+        )?;
+
         if self.is_authenticated.clone().await {
             let rmt_addr = self.controller.remote_address();
+            let ctx = self.context();
 
             match cmd {
                 Command::Authenticate { .. } => unreachable!(),
@@ -36,9 +63,29 @@ impl Connection {
                     len,
                     addr,
                 } => {
+                    if self.refuse_association(assoc_id).await {
+                        return Ok(());
+                    }
+
                     if self.udp_packet_from.uni_stream() {
                         let dst_addr = addr.to_string();
-                        log::debug!("[{rmt_addr}] [packet-from-quic] [{assoc_id}] [{dst_addr}]");
+
+                        if self.is_udp_rate_limited() {
+                            log::debug!(
+                                "[{ctx}] [packet-from-quic] [{assoc_id}] [{dst_addr}] rate limited"
+                            );
+                            return Ok(());
+                        }
+
+                        if let Some(reason) = self.check_destination(&addr) {
+                            log::debug!("[{ctx}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {reason}, packet dropped");
+                            self.config.metrics.on_rejection(RejectionReason::Policy);
+                            return Ok(());
+                        }
+
+                        self.record_bytes(len as usize)?;
+
+                        log::debug!("[{ctx}] [packet-from-quic] [{assoc_id}] [{dst_addr}]");
 
                         let res = task::packet_from_uni_stream(
                             stream,
@@ -47,14 +94,13 @@ impl Connection {
                             len,
                             addr,
                             rmt_addr,
-                            self.max_udp_packet_size,
                         )
                         .await;
 
                         match res {
                             Ok(()) => {}
                             Err(err) => log::warn!(
-                                "[{rmt_addr}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {err}"
+                                "[{ctx}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {err}"
                             ),
                         }
 
@@ -64,17 +110,24 @@ impl Connection {
                     }
                 }
                 Command::Dissociate { assoc_id } => {
-                    let res = task::dissociate(self.udp_sessions.clone(), assoc_id, rmt_addr).await;
+                    let mut res =
+                        task::dissociate(self.udp_sessions.clone(), assoc_id, rmt_addr).await;
+
+                    if res.is_ok() && self.config.acknowledge_dissociate {
+                        log::debug!("[{ctx}] [dissociate] [{assoc_id}] [acknowledge]");
+                        self.trace_command("send", &Command::new_dissociate(assoc_id));
+                        res = task::send_dissociate(self.controller.clone(), assoc_id).await;
+                    }
 
                     match res {
                         Ok(()) => {}
-                        Err(err) => log::warn!("[{rmt_addr}] [dissociate] {err}"),
+                        Err(err) => log::warn!("[{ctx}] [dissociate] {err}"),
                     }
 
                     Ok(())
                 }
                 Command::Heartbeat => {
-                    log::debug!("[{rmt_addr}] [heartbeat]");
+                    log::debug!("[{ctx}] [heartbeat]");
                     Ok(())
                 }
                 _ => Err(DispatchError::BadCommand),
@@ -86,24 +139,123 @@ impl Connection {
 
     pub async fn process_bi_stream(
         &self,
-        send: SendStream,
+        mut send: SendStream,
         mut recv: RecvStream,
     ) -> Result<(), DispatchError> {
-        let cmd = Command::read_from(&mut recv).await?;
+        self.check_migration()?;
 
+        let cmd = match self.read_command(&mut recv).await? {
+            Some(cmd) => cmd,
+            None => {
+                let _ = send.reset(VarInt::from_u32(0));
+                return Ok(());
+            }
+        };
+
+        self.trace_command("recv", &cmd);
+        check_direction(&cmd)?;
         if self.is_authenticated.clone().await {
-            let rmt_addr = self.controller.remote_address();
+            let ctx = self.context();
 
             match cmd {
                 Command::Connect { addr } => {
                     let dst_addr = addr.to_string();
-                    log::info!("[{rmt_addr}] [connect] [{dst_addr}]");
+                    log::info!("[{ctx}] [connect] [{dst_addr}]");
 
-                    let res = task::connect(send, recv, addr).await;
+                    if let Some(reason) = self.check_destination(&addr) {
+                        log::warn!("[{ctx}] [connect] [{dst_addr}] {reason}");
+                        self.config.metrics.on_rejection(RejectionReason::Policy);
+                        self.refuse_connect(send, &dst_addr).await;
+                        return Ok(());
+                    }
+
+                    let _guard = match &self.config.destination_limiter {
+                        Some(limiter) => match limiter.acquire(&addr) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                log::warn!("[{ctx}] [connect] [{dst_addr}] too many connections to the destination");
+                                self.config.metrics.on_rejection(RejectionReason::OverLimit);
+                                self.refuse_connect(send, &dst_addr).await;
+                                return Ok(());
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let _permit = match &self.connect_semaphore {
+                        Some(semaphore) => match semaphore.try_acquire() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                log::debug!("[{ctx}] [connect] [{dst_addr}] waiting for a free connect slot");
+                                semaphore.acquire().await.ok()
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let outbound_permit = match &self.config.outbound_connects {
+                        Some(semaphore) => match semaphore.try_acquire() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                log::debug!("[{ctx}] [connect] [{dst_addr}] waiting for a free outbound connect slot");
+                                let started = Instant::now();
+                                let permit = semaphore.acquire().await.ok();
+                                self.config.metrics.on_outbound_saturated(started.elapsed());
+                                permit
+                            }
+                        },
+                        None => None,
+                    };
+
+                    self.tcp_streams.fetch_add(1, Ordering::AcqRel);
+
+                    let stream = task::connect_target(&addr, &self.config).await;
+
+                    drop(outbound_permit);
+
+                    if let Some(upstream_addr) =
+                        stream.as_ref().and_then(|stream| stream.peer_addr().ok())
+                    {
+                        log::debug!("[{ctx}] [connect] [{dst_addr}] connected to {upstream_addr}");
+                        *self.last_upstream_addr.lock() = Some(upstream_addr);
+                    }
+
+                    let res = task::connect(
+                        send,
+                        recv,
+                        stream,
+                        self.byte_accounting(),
+                        &self.config,
+                        self.trace_commands.then_some(ctx),
+                    )
+                    .await;
+
+                    self.tcp_streams.fetch_sub(1, Ordering::AcqRel);
 
                     match res {
-                        Ok(()) => {}
-                        Err(err) => log::warn!("[{rmt_addr}] [connect] [{dst_addr}] {err}"),
+                        Ok(ConnectOutcome::Relayed { has_data: true }) => {}
+                        Ok(ConnectOutcome::Relayed { has_data: false }) => {
+                            log::debug!(
+                                "[{ctx}] [connect] [{dst_addr}] closed without any data relayed"
+                            );
+                        }
+                        Ok(ConnectOutcome::Unreachable) => {
+                            log::warn!(
+                                "[{ctx}] [connect] [{dst_addr}] unable to connect to the target"
+                            );
+                            self.config
+                                .metrics
+                                .on_rejection(RejectionReason::UpstreamFailure);
+                        }
+                        Err(err) => log::warn!("[{ctx}] [connect] [{dst_addr}] {err}"),
+                    }
+
+                    if self
+                        .quota
+                        .as_ref()
+                        .is_some_and(|quota| quota.is_exhausted())
+                    {
+                        return Err(DispatchError::QuotaExceeded);
                     }
 
                     Ok(())
@@ -116,17 +268,44 @@ impl Connection {
     }
 
     pub async fn process_datagram(&self, datagram: Bytes) -> Result<(), DispatchError> {
+        self.check_migration()?;
         let cmd = Command::read_from(&mut datagram.as_ref()).await?;
-        let cmd_len = cmd.serialized_len();
+        self.trace_command("recv", &cmd);
+        check_direction(&cmd)?;
 
+        // datagrams may be lost or reordered, so the authentication state must not depend on one
+        if let Command::Authenticate { .. } = cmd {
+            return Err(DispatchError::AuthenticationInDatagram);
+        }
+
+        let cmd_len = cmd.serialized_len();
         if self.is_authenticated.clone().await {
             let rmt_addr = self.controller.remote_address();
+            let ctx = self.context();
 
             match cmd {
                 Command::Packet { assoc_id, addr, .. } => {
+                    if self.refuse_association(assoc_id).await {
+                        return Ok(());
+                    }
+
                     if self.udp_packet_from.datagram() {
                         let dst_addr = addr.to_string();
-                        log::debug!("[{rmt_addr}] [packet-from-native] [{assoc_id}] [{dst_addr}]");
+
+                        if self.is_udp_rate_limited() {
+                            log::debug!("[{ctx}] [packet-from-native] [{assoc_id}] [{dst_addr}] rate limited");
+                            return Ok(());
+                        }
+
+                        if let Some(reason) = self.check_destination(&addr) {
+                            log::debug!("[{ctx}] [packet-from-native] [{assoc_id}] [{dst_addr}] {reason}, packet dropped");
+                            self.config.metrics.on_rejection(RejectionReason::Policy);
+                            return Ok(());
+                        }
+
+                        self.record_bytes(datagram.len() - cmd_len)?;
+
+                        log::debug!("[{ctx}] [packet-from-native] [{assoc_id}] [{dst_addr}]");
 
                         let res = task::packet_from_datagram(
                             datagram.slice(cmd_len..),
@@ -134,7 +313,6 @@ impl Connection {
                             assoc_id,
                             addr,
                             rmt_addr,
-                            self.max_udp_packet_size,
                         )
                         .await;
 
@@ -142,7 +320,7 @@ impl Connection {
                             Ok(()) => {}
                             Err(err) => {
                                 log::warn!(
-                                    "[{rmt_addr}] [packet-from-native] [{assoc_id}] [{dst_addr}] {err}"
+                                    "[{ctx}] [packet-from-native] [{assoc_id}] [{dst_addr}] {err}"
                                 )
                             }
                         }
@@ -152,6 +330,10 @@ impl Connection {
                         Err(DispatchError::BadCommand)
                     }
                 }
+                Command::Heartbeat => {
+                    log::debug!("[{ctx}] [heartbeat]");
+                    Ok(())
+                }
                 _ => Err(DispatchError::BadCommand),
             }
         } else {
@@ -165,35 +347,46 @@ impl Connection {
         pkt: Bytes,
         addr: Address,
     ) -> Result<(), DispatchError> {
-        let rmt_addr = self.controller.remote_address();
+        let ctx = self.context();
         let dst_addr = addr.to_string();
 
+        if self.is_udp_rate_limited() {
+            log::debug!("[{ctx}] [packet-to] [{assoc_id}] [{dst_addr}] rate limited");
+            return Ok(());
+        }
+
+        if !self.check_udp_payload(assoc_id, pkt.len(), &addr) {
+            return Ok(());
+        }
+
+        self.record_bytes(pkt.len())?;
+
         match self.udp_packet_from.check().unwrap() {
             UdpPacketSource::UniStream => {
-                log::debug!("[{rmt_addr}] [packet-to-quic] [{assoc_id}] [{dst_addr}]");
+                log::debug!("[{ctx}] [packet-to-quic] [{assoc_id}] [{dst_addr}]");
 
+                self.trace_sent_packet(assoc_id, pkt.len(), &addr);
                 let res =
                     task::packet_to_uni_stream(self.controller.clone(), assoc_id, pkt, addr).await;
 
                 match res {
                     Ok(()) => {}
                     Err(err) => {
-                        log::warn!("[{rmt_addr}] [packet-to-quic] [{assoc_id}] [{dst_addr}] {err}")
+                        log::warn!("[{ctx}] [packet-to-quic] [{assoc_id}] [{dst_addr}] {err}")
                     }
                 }
             }
             UdpPacketSource::Datagram => {
-                log::debug!("[{rmt_addr}] [packet-to-native] [{assoc_id}] [{dst_addr}]");
+                log::debug!("[{ctx}] [packet-to-native] [{assoc_id}] [{dst_addr}]");
 
+                self.trace_sent_packet(assoc_id, pkt.len(), &addr);
                 let res =
                     task::packet_to_datagram(self.controller.clone(), assoc_id, pkt, addr).await;
 
                 match res {
                     Ok(()) => {}
                     Err(err) => {
-                        log::warn!(
-                            "[{rmt_addr}] [packet-to-native] [{assoc_id}] [{dst_addr}] {err}"
-                        )
+                        log::warn!("[{ctx}] [packet-to-native] [{assoc_id}] [{dst_addr}] {err}")
                     }
                 }
             }
@@ -201,6 +394,236 @@ impl Connection {
 
         Ok(())
     }
+
+    pub async fn process_received_udp_packets(
+        &self,
+        pkts: Vec<RecvPacket>,
+    ) -> Result<(), DispatchError> {
+        let ctx = self.context();
+        let mut checked_pkts = Vec::with_capacity(pkts.len());
+
+        for (assoc_id, pkt, addr) in pkts {
+            if self.is_udp_rate_limited() {
+                log::debug!("[{ctx}] [packet-to] [{assoc_id}] [{addr}] rate limited");
+                continue;
+            }
+
+            if !self.check_udp_payload(assoc_id, pkt.len(), &addr) {
+                continue;
+            }
+
+            self.record_bytes(pkt.len())?;
+
+            log::debug!("[{ctx}] [packet-to-native] [{assoc_id}] [{addr}]");
+            self.trace_sent_packet(assoc_id, pkt.len(), &addr);
+            checked_pkts.push((assoc_id, pkt, addr));
+        }
+
+        let count = checked_pkts.len();
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        match task::packets_to_datagram(self.controller.clone(), checked_pkts).await {
+            Ok(()) if count > 1 => log::debug!("[{ctx}] [packet-to-native] [coalesced {count}]"),
+            Ok(()) => {}
+            Err(err) => log::warn!("[{ctx}] [packet-to-native] [coalesced {count}] {err}"),
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a packet to the client fits in a single datagram or packet, logging it as dropped otherwise
+    fn check_udp_payload(&self, assoc_id: u32, len: usize, addr: &Address) -> bool {
+        match self.max_udp_payload(addr) {
+            Some(max) if len <= max => true,
+            Some(max) => {
+                let ctx = self.context();
+                log::warn!("[{ctx}] [packet-to] [{assoc_id}] [{addr}] packet of {len} bytes exceeds the maximum UDP payload of {max} bytes, dropped");
+                false
+            }
+            None => {
+                let ctx = self.context();
+                log::warn!("[{ctx}] [packet-to] [{assoc_id}] [{addr}] datagrams are not available on the connection, packet dropped");
+                false
+            }
+        }
+    }
+
+    /// Reads the command at the start of a stream. Returns `None` after stopping the stream if the command is not fully received within the command read timeout, or if the stream ends in the middle of it and truncated commands do not close the connection
+    async fn read_command(&self, recv: &mut RecvStream) -> Result<Option<Command>, DispatchError> {
+        let res = match self.config.command_read_timeout {
+            Some(timeout) => match time::timeout(timeout, Command::read_from(recv)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    let _ = recv.stop(VarInt::from_u32(0));
+                    let ctx = self.context();
+                    log::debug!("[{ctx}] [command] not received within {timeout:?}, stream reset");
+                    self.config
+                        .metrics
+                        .on_rejection(RejectionReason::CommandTimeout);
+                    return Ok(None);
+                }
+            },
+            None => Command::read_from(recv).await,
+        };
+
+        match res.map_err(command_error) {
+            Ok(cmd) => Ok(Some(cmd)),
+            Err(err @ DispatchError::TruncatedCommand) => {
+                if self.config.close_on_truncated_command {
+                    return Err(err);
+                }
+
+                let _ = recv.stop(VarInt::from_u32(0));
+                self.config
+                    .metrics
+                    .on_rejection(RejectionReason::ProtocolError);
+
+                let event = err.to_string();
+
+                if self
+                    .config
+                    .log_throttle
+                    .as_ref()
+                    .is_none_or(|throttle| throttle.check(&event))
+                {
+                    let ctx = self.context();
+                    log::warn!("[{ctx}] [command] {event}, stream reset");
+                }
+
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Checks the destination of a `Connect` or `Packet` against the configured policy, returning the reason if it is refused
+    ///
+    /// Domains are only compared with the listening address once resolved, in `task::connect_target` and when the UDP session sends a packet
+    fn check_destination(&self, addr: &Address) -> Option<String> {
+        match (
+            addr,
+            self.config.max_domain_length,
+            self.config.listen_address.as_ref(),
+        ) {
+            (Address::DomainAddress(domain, _), Some(max), _) if domain.len() > max => {
+                Some(format!("domain longer than {max} bytes"))
+            }
+            (Address::SocketAddress(addr), _, Some(listen_addr)) if listen_addr.matches(*addr) => {
+                Some("the listening address of the server".to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    async fn refuse_connect(&self, send: SendStream, dst_addr: &str) {
+        self.trace_command("send", &Command::new_response(false));
+
+        match task::refuse_connect(send).await {
+            Ok(()) => {}
+            Err(err) => log::warn!("[{}] [connect] [{dst_addr}] {err}", self.context()),
+        }
+    }
+
+    async fn refuse_association(&self, assoc_id: u32) -> bool {
+        let refused = match &self.refused_associations {
+            Some(refused) => refused,
+            None => return false,
+        };
+
+        if refused.insert(assoc_id) {
+            let ctx = self.context();
+            log::warn!("[{ctx}] [packet] [{assoc_id}] UDP relaying is disabled");
+            self.config.metrics.on_rejection(RejectionReason::Policy);
+
+            if !refused.may_reply() {
+                log::debug!(
+                    "[{ctx}] [dissociate] [{assoc_id}] too many refused associations, not answered"
+                );
+                return true;
+            }
+
+            self.trace_command("send", &Command::new_dissociate(assoc_id));
+
+            match task::send_dissociate(self.controller.clone(), assoc_id).await {
+                Ok(()) => {}
+                Err(err) => log::warn!("[{ctx}] [dissociate] [{assoc_id}] {err}"),
+            }
+        }
+
+        true
+    }
+
+    fn check_migration(&self) -> Result<(), DispatchError> {
+        let rmt_addr = self.controller.remote_address();
+
+        if let Some((prev_addr, count)) = self.migration_tracker.observe(rmt_addr) {
+            let ctx = self.context();
+            log::info!("[{ctx}] [migration] [{prev_addr}] -> [{rmt_addr}] [{count}]");
+
+            if self.config.max_migrations.is_some_and(|max| count > max) {
+                return Err(DispatchError::TooManyMigrations);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn byte_accounting(&self) -> ByteAccounting<'_> {
+        ByteAccounting {
+            rate_limiter: self.tcp_rate_limiter.as_deref(),
+            quota: self.quota.as_deref(),
+            throughput: &self.throughput,
+        }
+    }
+
+    fn record_bytes(&self, len: usize) -> Result<(), DispatchError> {
+        self.throughput.record(len as u64);
+
+        match &self.quota {
+            Some(quota) if !quota.consume(len as u64) => Err(DispatchError::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_udp_rate_limited(&self) -> bool {
+        self.udp_rate_limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.try_acquire(1))
+    }
+}
+
+/// The client opens the stream carrying the authentication before any other unidirectional stream, so under strict authentication a different command on that stream is a violation. Commands on other streams may overtake the authentication, as QUIC streams are not ordered, and wait for it instead
+fn check_strict_authentication(
+    strict: bool,
+    id: StreamId,
+    is_authenticated: bool,
+) -> Result<(), DispatchError> {
+    if strict && id.index() == 0 && !is_authenticated {
+        Err(DispatchError::Unauthenticated)
+    } else {
+        Ok(())
+    }
+}
+
+/// Streams ending in the middle of a command are told apart from malformed commands, as they do not have to close the connection
+fn command_error(err: ProtocolError) -> DispatchError {
+    match err {
+        ProtocolError::Io(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            DispatchError::TruncatedCommand
+        }
+        err => DispatchError::Protocol(err),
+    }
+}
+
+/// `Response` is only sent by the server, so receiving one means the client does not speak the protocol
+fn check_direction(cmd: &Command) -> Result<(), DispatchError> {
+    match cmd {
+        Command::Response(_) => Err(DispatchError::UnexpectedResponse),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Error, Debug)]
@@ -213,20 +636,165 @@ pub enum DispatchError {
     AuthenticationTimeout,
     #[error("bad command")]
     BadCommand,
+    #[error("command received before authentication")]
+    Unauthenticated,
+    #[error("byte quota exceeded")]
+    QuotaExceeded,
+    #[error("too many migrations")]
+    TooManyMigrations,
+    #[error("response command received from the client")]
+    UnexpectedResponse,
+    #[error("authentication received in a datagram")]
+    AuthenticationInDatagram,
+    #[error("stream ended in the middle of a command")]
+    TruncatedCommand,
+    #[error("connection lifetime expired")]
+    LifetimeExpired,
 }
 
 impl DispatchError {
-    const CODE_PROTOCOL: VarInt = VarInt::from_u32(0xfffffff0);
-    const CODE_AUTHENTICATION_FAILED: VarInt = VarInt::from_u32(0xfffffff1);
-    const CODE_AUTHENTICATION_TIMEOUT: VarInt = VarInt::from_u32(0xfffffff2);
-    const CODE_BAD_COMMAND: VarInt = VarInt::from_u32(0xfffffff3);
-
     pub fn as_error_code(&self) -> VarInt {
+        let code = match self {
+            Self::Protocol(_) => CloseCode::Protocol,
+            Self::AuthenticationFailed => CloseCode::AuthenticationFailed,
+            Self::AuthenticationTimeout => CloseCode::AuthenticationTimeout,
+            Self::BadCommand => CloseCode::BadCommand,
+            Self::Unauthenticated => CloseCode::Unauthenticated,
+            Self::QuotaExceeded => CloseCode::QuotaExceeded,
+            Self::TooManyMigrations => CloseCode::TooManyMigrations,
+            Self::UnexpectedResponse => CloseCode::UnexpectedResponse,
+            Self::AuthenticationInDatagram => CloseCode::AuthenticationInDatagram,
+            Self::TruncatedCommand => CloseCode::TruncatedCommand,
+            Self::LifetimeExpired => CloseCode::LifetimeExpired,
+        };
+
+        VarInt::from_u32(code.as_u32())
+    }
+
+    /// Authentication timeouts are reported where the connection is closed, not for every pending stream
+    pub fn rejection_reason(&self) -> Option<RejectionReason> {
         match self {
-            Self::Protocol(_) => Self::CODE_PROTOCOL,
-            Self::AuthenticationFailed => Self::CODE_AUTHENTICATION_FAILED,
-            Self::AuthenticationTimeout => Self::CODE_AUTHENTICATION_TIMEOUT,
-            Self::BadCommand => Self::CODE_BAD_COMMAND,
+            Self::Protocol(_)
+            | Self::BadCommand
+            | Self::Unauthenticated
+            | Self::UnexpectedResponse
+            | Self::AuthenticationInDatagram
+            | Self::TruncatedCommand => Some(RejectionReason::ProtocolError),
+            Self::AuthenticationFailed => Some(RejectionReason::AuthenticationFailure),
+            Self::AuthenticationTimeout => None,
+            Self::QuotaExceeded => Some(RejectionReason::OverLimit),
+            Self::TooManyMigrations => Some(RejectionReason::Policy),
+            Self::LifetimeExpired => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_direction, check_strict_authentication, command_error, DispatchError};
+    use crate::metrics::RejectionReason;
+    use quinn::{StreamId, VarInt};
+    use tuic_protocol::{
+        testing::{self, ExpectedError},
+        CloseCode, Command,
+    };
+
+    #[test]
+    fn malformed_commands_close_with_their_code() {
+        for case in testing::malformed_commands() {
+            let err = match Command::from_bytes(&case.bytes) {
+                Ok((cmd, _)) => panic!("{}: parsed as {cmd}", case.name),
+                Err(err) => command_error(err),
+            };
+
+            let code = match case.expected {
+                ExpectedError::UnexpectedEof => CloseCode::TruncatedCommand,
+                _ => CloseCode::Protocol,
+            };
+
+            assert_eq!(
+                err.as_error_code(),
+                VarInt::from_u32(code.as_u32()),
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                err.rejection_reason(),
+                Some(RejectionReason::ProtocolError),
+                "{}",
+                case.name
+            );
         }
     }
+
+    #[test]
+    fn connection_errors_are_counted_by_reason() {
+        let cases = [
+            (
+                DispatchError::BadCommand,
+                Some(RejectionReason::ProtocolError),
+            ),
+            (
+                DispatchError::Unauthenticated,
+                Some(RejectionReason::ProtocolError),
+            ),
+            (
+                DispatchError::UnexpectedResponse,
+                Some(RejectionReason::ProtocolError),
+            ),
+            (
+                DispatchError::AuthenticationInDatagram,
+                Some(RejectionReason::ProtocolError),
+            ),
+            (
+                DispatchError::TruncatedCommand,
+                Some(RejectionReason::ProtocolError),
+            ),
+            (
+                DispatchError::AuthenticationFailed,
+                Some(RejectionReason::AuthenticationFailure),
+            ),
+            (
+                DispatchError::QuotaExceeded,
+                Some(RejectionReason::OverLimit),
+            ),
+            (
+                DispatchError::TooManyMigrations,
+                Some(RejectionReason::Policy),
+            ),
+            (DispatchError::AuthenticationTimeout, None),
+            (DispatchError::LifetimeExpired, None),
+        ];
+
+        for (err, reason) in cases {
+            assert_eq!(err.rejection_reason(), reason, "{err}");
+        }
+    }
+
+    #[test]
+    fn only_responses_are_rejected_by_direction() {
+        for (cmd, _) in testing::valid_commands() {
+            match (&cmd, check_direction(&cmd)) {
+                (Command::Response(_), Err(DispatchError::UnexpectedResponse)) => {}
+                (Command::Response(_), _) => panic!("{cmd} accepted"),
+                (_, Ok(())) => {}
+                (_, Err(err)) => panic!("{cmd} rejected: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn strict_authentication_rejects_other_commands_on_the_authentication_stream() {
+        // client-initiated unidirectional streams have the low bits set to 0b10
+        let auth_stream = StreamId::from(VarInt::from_u32(0b10));
+        let other_stream = StreamId::from(VarInt::from_u32(1 << 2 | 0b10));
+
+        assert!(matches!(
+            check_strict_authentication(true, auth_stream, false),
+            Err(DispatchError::Unauthenticated)
+        ));
+        assert!(check_strict_authentication(true, auth_stream, true).is_ok());
+        assert!(check_strict_authentication(true, other_stream, false).is_ok());
+        assert!(check_strict_authentication(false, auth_stream, false).is_ok());
+    }
 }
@@ -0,0 +1,51 @@
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+
+pub struct MigrationTracker {
+    state: Mutex<MigrationState>,
+}
+
+struct MigrationState {
+    rmt_addr: SocketAddr,
+    count: usize,
+}
+
+impl MigrationTracker {
+    pub fn new(rmt_addr: SocketAddr) -> Self {
+        Self {
+            state: Mutex::new(MigrationState { rmt_addr, count: 0 }),
+        }
+    }
+
+    /// Records the current remote address, returning the previous address and the number of migrations so far if it has changed
+    pub fn observe(&self, rmt_addr: SocketAddr) -> Option<(SocketAddr, usize)> {
+        let mut state = self.state.lock();
+
+        if state.rmt_addr == rmt_addr {
+            None
+        } else {
+            let prev_addr = state.rmt_addr;
+            state.rmt_addr = rmt_addr;
+            state.count += 1;
+            Some((prev_addr, state.count))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MigrationTracker;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn only_address_changes_are_counted() {
+        let first = SocketAddr::from(([192, 0, 2, 1], 1000));
+        let second = SocketAddr::from(([192, 0, 2, 1], 2000));
+        let tracker = MigrationTracker::new(first);
+
+        assert_eq!(tracker.observe(first), None);
+        assert_eq!(tracker.observe(second), Some((first, 1)));
+        assert_eq!(tracker.observe(second), None);
+        assert_eq!(tracker.observe(first), Some((second, 2)));
+    }
+}
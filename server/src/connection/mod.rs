@@ -1,50 +1,233 @@
 use self::{
     authenticate::IsAuthenticated,
     dispatch::DispatchError,
-    udp::{RecvPacketReceiver, UdpPacketFrom, UdpPacketSource, UdpSessionMap},
+    migration::MigrationTracker,
+    quota::ByteQuota,
+    rate_limit::RateLimiter,
+    throughput::Throughput,
+    udp::{RecvPacketReceiver, RefusedAssociations, UdpPacketFrom, UdpPacketSource, UdpSessionMap},
+};
+use crate::{
+    buffer::BufferProvider,
+    catch_panic::spawn_catching_panic,
+    log_throttle::LogThrottle,
+    metrics::{ConnectionInfo, Metrics, PathStatsDelta, RejectionReason},
+    snapshot::ConnectionSnapshot,
 };
 use futures_util::StreamExt;
 use parking_lot::Mutex;
 use quinn::{
     Connecting, Connection as QuinnConnection, ConnectionError, Datagrams, IncomingBiStreams,
-    IncomingUniStreams, NewConnection,
+    IncomingUniStreams, NewConnection, VarInt,
 };
 use std::{
-    future::Future,
+    fmt::{Display, Formatter, Result as FmtResult},
+    future::{self, Future},
+    net::SocketAddr,
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
     },
     task::{Context, Poll, Waker},
+    thread,
     time::Duration,
 };
-use tokio::time;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
+};
+use tuic_protocol::{Address, Command};
+
+pub use self::{
+    destination::{DestinationKey, DestinationLimiter, ListenAddress},
+    udp::AddressPreference,
+};
 
 mod authenticate;
+mod destination;
 mod dispatch;
+mod migration;
+mod pacer;
+mod quota;
+mod rate_limit;
+mod recent_ids;
+mod resolve;
 mod task;
+mod throughput;
 mod udp;
 
+pub type ConnectionRegistry = Mutex<Vec<RegisteredConnection>>;
+
+/// The state of a connection the server looks into, held without keeping the connection alive
+pub struct RegisteredConnection {
+    rmt_addr: SocketAddr,
+    udp_sessions: Weak<UdpSessionMap>,
+    tcp_streams: Weak<AtomicUsize>,
+    throughput: Weak<Throughput>,
+}
+
+impl RegisteredConnection {
+    pub fn is_alive(&self) -> bool {
+        self.udp_sessions.strong_count() > 0
+    }
+
+    pub fn reap(&self, idle_threshold: Duration) -> usize {
+        self.udp_sessions
+            .upgrade()
+            .map_or(0, |sessions| sessions.reap(idle_threshold, self.rmt_addr))
+    }
+
+    pub fn snapshot(&self) -> Option<ConnectionSnapshot> {
+        let udp_sessions = self.udp_sessions.upgrade()?;
+
+        Some(ConnectionSnapshot {
+            remote_addr: self.rmt_addr,
+            tcp_streams: self
+                .tcp_streams
+                .upgrade()
+                .map_or(0, |count| count.load(Ordering::Acquire)),
+            throughput: self
+                .throughput
+                .upgrade()
+                .map_or(0.0, |throughput| throughput.rate()),
+            udp_associations: udp_sessions.snapshot(),
+        })
+    }
+}
+
+/// The settings and server-wide state every connection is handled with
+pub struct ConnectionConfig {
+    pub expected_token_digest: [u8; 32],
+    pub authentication_timeout: Duration,
+    pub max_udp_packet_size: usize,
+    pub max_udp_packets_per_second: Option<u64>,
+    pub max_tcp_bytes_per_second: Option<u64>,
+    pub strict_authentication: bool,
+    pub acknowledge_dissociate: bool,
+    pub destination_limiter: Option<Arc<DestinationLimiter>>,
+    pub max_bytes_per_connection: Option<u64>,
+    pub reject_dissociated_packets: bool,
+    pub max_concurrent_connects: Option<usize>,
+    pub max_udp_associations: Option<usize>,
+    pub max_udp_associations_per_second: Option<u64>,
+    pub ipv6_flow_label: Option<u32>,
+    pub disable_udp: bool,
+    pub connect_udp_sockets: bool,
+    pub listen_address: Option<ListenAddress>,
+    pub max_migrations: Option<usize>,
+    pub max_connection_lifetime: Option<Duration>,
+    pub pending_connections: Option<Arc<Semaphore>>,
+    pub outbound_connects: Option<Semaphore>,
+    pub coalesce_udp_replies: bool,
+    pub throughput_time_constant: Duration,
+    pub stats_interval: Option<Duration>,
+    pub first_byte_timeout: Option<Duration>,
+    pub command_read_timeout: Option<Duration>,
+    pub close_on_truncated_command: bool,
+    pub max_domain_length: Option<usize>,
+    pub max_resolved_addresses: Option<usize>,
+    pub resolve_retries: usize,
+    pub resolve_retry_time: Duration,
+    pub log_throttle: Option<LogThrottle>,
+    pub udp_pacing_rate: Option<u64>,
+    pub udp_pacing_latency: Duration,
+    pub udp_address_preference: AddressPreference,
+    pub tcp_nodelay: bool,
+    pub reset_on_target_reset: bool,
+    pub buffer_provider: Arc<dyn BufferProvider>,
+    pub metrics: Arc<dyn Metrics>,
+}
+
+#[cfg(test)]
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            expected_token_digest: [0; 32],
+            authentication_timeout: Duration::from_secs(1),
+            max_udp_packet_size: 1536,
+            max_udp_packets_per_second: None,
+            max_tcp_bytes_per_second: None,
+            strict_authentication: false,
+            acknowledge_dissociate: false,
+            destination_limiter: None,
+            max_bytes_per_connection: None,
+            reject_dissociated_packets: false,
+            max_concurrent_connects: None,
+            max_udp_associations: None,
+            max_udp_associations_per_second: None,
+            ipv6_flow_label: None,
+            disable_udp: false,
+            connect_udp_sockets: false,
+            listen_address: None,
+            max_migrations: None,
+            max_connection_lifetime: None,
+            pending_connections: None,
+            outbound_connects: None,
+            coalesce_udp_replies: false,
+            throughput_time_constant: Duration::from_secs(5),
+            stats_interval: None,
+            first_byte_timeout: None,
+            command_read_timeout: None,
+            close_on_truncated_command: false,
+            max_domain_length: None,
+            max_resolved_addresses: None,
+            resolve_retries: 0,
+            resolve_retry_time: Duration::from_secs(5),
+            log_throttle: None,
+            udp_pacing_rate: None,
+            udp_pacing_latency: Duration::from_millis(50),
+            udp_address_preference: AddressPreference::PreferIpv4,
+            tcp_nodelay: true,
+            reset_on_target_reset: false,
+            buffer_provider: Arc::new(crate::buffer::HeapBufferProvider),
+            metrics: Arc::new(crate::metrics::NoopMetrics),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Connection {
     controller: QuinnConnection,
+    config: Arc<ConnectionConfig>,
     udp_packet_from: UdpPacketFrom,
     udp_sessions: Arc<UdpSessionMap>,
-    expected_token_digest: [u8; 32],
     is_authenticated: IsAuthenticated,
-    max_udp_packet_size: usize,
+    udp_rate_limiter: Option<Arc<RateLimiter>>,
+    tcp_rate_limiter: Option<Arc<RateLimiter>>,
+    quota: Option<Arc<ByteQuota>>,
+    throughput: Arc<Throughput>,
+    tcp_streams: Arc<AtomicUsize>,
+    last_upstream_addr: Arc<Mutex<Option<SocketAddr>>>,
+    connect_semaphore: Option<Arc<Semaphore>>,
+    refused_associations: Option<Arc<RefusedAssociations>>,
+    migration_tracker: Arc<MigrationTracker>,
+    trace_commands: bool,
 }
 
 impl Connection {
     pub async fn handle(
         conn: Connecting,
-        exp_token_dgst: [u8; 32],
-        auth_timeout: Duration,
-        max_udp_pkt_size: usize,
+        config: Arc<ConnectionConfig>,
+        conn_registry: Arc<ConnectionRegistry>,
+        trace_commands: bool,
     ) {
         let rmt_addr = conn.remote_address();
 
+        let metrics = &config.metrics;
+
+        let pending_permit = match &config.pending_connections {
+            Some(pending_conns) => match pending_conns.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    log::warn!("[{rmt_addr}] too many pending connections, connection refused");
+                    metrics.on_rejection(RejectionReason::OverLimit);
+                    return;
+                }
+            },
+            None => None,
+        };
+
         match conn.await {
             Ok(NewConnection {
                 connection,
@@ -53,19 +236,59 @@ impl Connection {
                 datagrams,
                 ..
             }) => {
-                log::debug!("[{rmt_addr}] [establish]");
+                let ctx = ConnectionContext::new(rmt_addr, connection.stable_id());
+                log::debug!("[{ctx}] [establish]");
+
+                let (udp_sessions, recv_pkt_rx) = UdpSessionMap::new(config.clone());
+                let udp_sessions = Arc::new(udp_sessions);
+                let throughput = Arc::new(Throughput::new(config.throughput_time_constant));
+                let tcp_streams = Arc::new(AtomicUsize::new(0));
+
+                {
+                    let mut registry = conn_registry.lock();
+                    registry.retain(RegisteredConnection::is_alive);
+                    registry.push(RegisteredConnection {
+                        rmt_addr,
+                        udp_sessions: Arc::downgrade(&udp_sessions),
+                        tcp_streams: Arc::downgrade(&tcp_streams),
+                        throughput: Arc::downgrade(&throughput),
+                    });
+                }
 
-                let (udp_sessions, recv_pkt_rx) = UdpSessionMap::new();
                 let is_closed = IsClosed::new();
                 let is_authed = IsAuthenticated::new(is_closed.clone());
 
                 let conn = Self {
                     controller: connection,
+                    config: config.clone(),
                     udp_packet_from: UdpPacketFrom::new(),
-                    udp_sessions: Arc::new(udp_sessions),
-                    expected_token_digest: exp_token_dgst,
+                    udp_sessions,
                     is_authenticated: is_authed,
-                    max_udp_packet_size: max_udp_pkt_size,
+                    udp_rate_limiter: config
+                        .max_udp_packets_per_second
+                        .map(|rate| Arc::new(RateLimiter::new(rate))),
+                    tcp_rate_limiter: config
+                        .max_tcp_bytes_per_second
+                        .map(|rate| Arc::new(RateLimiter::new(rate))),
+                    quota: config
+                        .max_bytes_per_connection
+                        .map(|limit| Arc::new(ByteQuota::new(limit))),
+                    throughput,
+                    tcp_streams,
+                    last_upstream_addr: Arc::new(Mutex::new(None)),
+                    connect_semaphore: config
+                        .max_concurrent_connects
+                        .map(|max| Arc::new(Semaphore::new(max))),
+                    refused_associations: config
+                        .disable_udp
+                        .then(|| Arc::new(RefusedAssociations::new())),
+                    migration_tracker: Arc::new(MigrationTracker::new(rmt_addr)),
+                    trace_commands,
+                };
+
+                let _close_on_panic = CloseOnPanic {
+                    controller: &conn.controller,
+                    is_closed: &is_closed,
                 };
 
                 let res = tokio::select! {
@@ -73,7 +296,9 @@ impl Connection {
                     res = Self::listen_bi_streams(conn.clone(), bi_streams) => res,
                     res = Self::listen_datagrams(conn.clone(), datagrams) => res,
                     res = Self::listen_received_udp_packet(conn.clone(), recv_pkt_rx) => res,
-                    Err(err) = Self::handle_authentication_timeout(conn, auth_timeout) => Err(err),
+                    res = Self::sample_stats(conn.clone(), config.stats_interval) => res,
+                    Err(err) = Self::handle_authentication_timeout(conn.clone(), config.authentication_timeout, pending_permit) => Err(err),
+                    res = Self::handle_lifetime(conn.clone(), config.max_connection_lifetime) => res,
                 };
 
                 match res {
@@ -83,13 +308,33 @@ impl Connection {
 
                         match err {
                             ConnectionError::TimedOut => {
-                                log::debug!("[{rmt_addr}] [disconnect] [connection timeout]")
+                                log::debug!("[{ctx}] [disconnect] [connection timeout]");
+                                metrics.on_rejection(RejectionReason::IdleTimeout);
                             }
                             ConnectionError::LocallyClosed => {
-                                log::debug!("[{rmt_addr}] [disconnect] [locally closed]")
+                                log::debug!("[{ctx}] [disconnect] [locally closed]")
+                            }
+                            err => {
+                                let event = format!("[disconnect] {err}");
+
+                                if config
+                                    .log_throttle
+                                    .as_ref()
+                                    .is_none_or(|throttle| throttle.check(&event))
+                                {
+                                    log::error!("[{ctx}] {event}");
+                                }
                             }
-                            err => log::error!("[{rmt_addr}] [disconnect] {err}"),
                         }
+
+                        let info = conn.info();
+                        log::debug!("[{ctx}] [throughput] {:.0} bytes/s", info.throughput);
+
+                        if let Some(addr) = info.last_upstream_addr {
+                            log::debug!("[{ctx}] [upstream] last connected to {addr}");
+                        }
+
+                        metrics.on_connection_closed(rmt_addr, &info);
                     }
                 }
             }
@@ -97,6 +342,86 @@ impl Connection {
         }
     }
 
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            throughput: self.throughput.rate(),
+            rtt: self.controller.rtt(),
+            tcp_streams: self.tcp_streams.load(Ordering::Acquire),
+            udp_associations: self.udp_sessions.association_count(),
+            last_upstream_addr: *self.last_upstream_addr.lock(),
+        }
+    }
+
+    fn context(&self) -> ConnectionContext {
+        ConnectionContext::new(
+            self.controller.remote_address(),
+            self.controller.stable_id(),
+        )
+    }
+
+    async fn handle_lifetime(self, lifetime: Option<Duration>) -> Result<(), ConnectionError> {
+        let lifetime = match lifetime {
+            Some(lifetime) => lifetime,
+            None => return future::pending().await,
+        };
+
+        time::sleep(lifetime).await;
+
+        let err = DispatchError::LifetimeExpired;
+        self.controller
+            .close(err.as_error_code(), err.to_string().as_bytes());
+
+        let ctx = self.context();
+        log::info!("[{ctx}] [disconnect] {err} after {lifetime:?}");
+
+        Err(ConnectionError::LocallyClosed)
+    }
+
+    async fn sample_stats(self, interval: Option<Duration>) -> Result<(), ConnectionError> {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return future::pending().await,
+        };
+
+        let mut timer = time::interval_at(Instant::now() + interval, interval);
+        let ctx = self.context();
+        let mut last_stats = self.controller.stats();
+        let mut last_sampled = Instant::now();
+
+        loop {
+            timer.tick().await;
+            let info = self.info();
+            let stats = self.controller.stats();
+            let now = Instant::now();
+
+            let delta = PathStatsDelta {
+                interval: now - last_sampled,
+                sent_datagrams: stats.udp_tx.datagrams - last_stats.udp_tx.datagrams,
+                congestion_events: stats.path.congestion_events - last_stats.path.congestion_events,
+            };
+
+            last_stats = stats;
+            last_sampled = now;
+
+            let secs = delta.interval.as_secs_f64();
+
+            log::info!(
+                "[{ctx}] [stats] throughput: {:.0} bytes/s, rtt: {} ms, tcp streams: {}, udp associations: {}, sent datagrams: {:.0}/s, congestion events: {} ({:.2}/s)",
+                info.throughput,
+                info.rtt.as_millis(),
+                info.tcp_streams,
+                info.udp_associations,
+                delta.sent_datagrams as f64 / secs,
+                delta.congestion_events,
+                delta.congestion_events as f64 / secs,
+            );
+
+            self.config
+                .metrics
+                .on_path_stats(self.controller.remote_address(), &delta);
+        }
+    }
+
     async fn listen_uni_streams(
         self,
         mut uni_streams: IncomingUniStreams,
@@ -105,18 +430,7 @@ impl Connection {
             let stream = stream?;
             let conn = self.clone();
 
-            tokio::spawn(async move {
-                match conn.process_uni_stream(stream).await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        conn.controller
-                            .close(err.as_error_code(), err.to_string().as_bytes());
-
-                        let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
-                    }
-                }
-            });
+            self.spawn_task(async move { conn.process_uni_stream(stream).await });
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -130,18 +444,7 @@ impl Connection {
             let (send, recv) = stream?;
             let conn = self.clone();
 
-            tokio::spawn(async move {
-                match conn.process_bi_stream(send, recv).await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        conn.controller
-                            .close(err.as_error_code(), err.to_string().as_bytes());
-
-                        let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
-                    }
-                }
-            });
+            self.spawn_task(async move { conn.process_bi_stream(send, recv).await });
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -152,18 +455,7 @@ impl Connection {
             let datagram = datagram?;
             let conn = self.clone();
 
-            tokio::spawn(async move {
-                match conn.process_datagram(datagram).await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        conn.controller
-                            .close(err.as_error_code(), err.to_string().as_bytes());
-
-                        let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
-                    }
-                }
-            });
+            self.spawn_task(async move { conn.process_datagram(datagram).await });
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -173,32 +465,129 @@ impl Connection {
         self,
         mut recv_pkt_rx: RecvPacketReceiver,
     ) -> Result<(), ConnectionError> {
-        while let Some((assoc_id, pkt, addr)) = recv_pkt_rx.recv().await {
+        let mut pending = None;
+
+        while let Some((assoc_id, pkt, addr)) = match pending.take() {
+            Some(pkt) => Some(pkt),
+            None => recv_pkt_rx.recv().await,
+        } {
             let conn = self.clone();
 
-            tokio::spawn(async move {
-                match conn.process_received_udp_packet(assoc_id, pkt, addr).await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        conn.controller
-                            .close(err.as_error_code(), err.to_string().as_bytes());
+            if let Some(max_size) = self.coalescing_datagram_size() {
+                let (pkts, rest) =
+                    udp::coalesce_udp_packets((assoc_id, pkt, addr), &mut recv_pkt_rx, max_size)
+                        .await;
+                pending = rest;
 
-                        let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
-                    }
-                }
-            });
+                self.spawn_task(async move { conn.process_received_udp_packets(pkts).await });
+
+                continue;
+            }
+
+            self.spawn_task(
+                async move { conn.process_received_udp_packet(assoc_id, pkt, addr).await },
+            );
         }
 
         Err(ConnectionError::LocallyClosed)
     }
 
-    async fn handle_authentication_timeout(self, timeout: Duration) -> Result<(), ConnectionError> {
+    /// Processes a stream, datagram or packet in its own task. A panic in it is logged and counted, and the connection carries on without the task
+    fn spawn_task<F>(&self, task: F)
+    where
+        F: Future<Output = Result<(), DispatchError>> + Send + 'static,
+    {
+        let conn = self.clone();
+        let ctx = self.context();
+        let metrics = self.config.metrics.clone();
+
+        spawn_catching_panic(
+            async move {
+                match task.await {
+                    Ok(()) => {}
+                    Err(err) => conn.close_with_error(err),
+                }
+            },
+            move || {
+                log::error!("[{ctx}] [task] panicked");
+                metrics.on_connection_panic(ctx.rmt_addr);
+            },
+        );
+    }
+
+    fn close_with_error(&self, err: DispatchError) {
+        self.controller
+            .close(err.as_error_code(), err.to_string().as_bytes());
+
+        if let Some(reason) = err.rejection_reason() {
+            self.config.metrics.on_rejection(reason);
+        }
+
+        let event = err.to_string();
+
+        if self
+            .config
+            .log_throttle
+            .as_ref()
+            .is_none_or(|throttle| throttle.check(&event))
+        {
+            let ctx = self.context();
+            log::error!("[{ctx}] {event}");
+        }
+    }
+
+    fn trace_command(&self, dir: &str, cmd: &Command) {
+        if self.trace_commands {
+            let ctx = self.context();
+            log::trace!("[{ctx}] [trace] [{dir}] {cmd}");
+        }
+    }
+
+    fn trace_sent_packet(&self, assoc_id: u32, len: usize, addr: &Address) {
+        if self.trace_commands {
+            self.trace_command(
+                "send",
+                &Command::new_packet(assoc_id, len as u16, addr.clone()),
+            );
+        }
+    }
+
+    /// The largest UDP payload that can currently be relayed to the client for `addr` in a single packet, or `None` if the client does not accept datagrams while it sends them
+    ///
+    /// Datagrams follow the size allowed by the current path MTU. Packets over unidirectional streams are only limited by the length field of the header
+    fn max_udp_payload(&self, addr: &Address) -> Option<usize> {
+        match self.udp_packet_from.check() {
+            Some(UdpPacketSource::Datagram) => self
+                .controller
+                .max_datagram_size()
+                .map(|size| size.saturating_sub(Command::packet_header_len(addr)))
+                .map(|size| size.min(u16::MAX as usize)),
+            _ => Some(u16::MAX as usize),
+        }
+    }
+
+    fn coalescing_datagram_size(&self) -> Option<usize> {
+        if self.config.coalesce_udp_replies
+            && self.udp_packet_from.check() == Some(UdpPacketSource::Datagram)
+        {
+            self.controller.max_datagram_size()
+        } else {
+            None
+        }
+    }
+
+    async fn handle_authentication_timeout(
+        self,
+        timeout: Duration,
+        pending_permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), ConnectionError> {
         let is_timeout = tokio::select! {
             _ = self.is_authenticated.clone() => false,
             () = time::sleep(timeout) => true,
         };
 
+        drop(pending_permit);
+
         if !is_timeout {
             Ok(())
         } else {
@@ -207,15 +596,52 @@ impl Connection {
             self.controller
                 .close(err.as_error_code(), err.to_string().as_bytes());
             self.is_authenticated.wake();
+            self.config
+                .metrics
+                .on_rejection(RejectionReason::AuthenticationTimeout);
 
-            let rmt_addr = self.controller.remote_address();
-            log::error!("[{rmt_addr}] {err}");
+            let ctx = self.context();
+            log::error!("[{ctx}] {err}");
 
             Err(ConnectionError::LocallyClosed)
         }
     }
 }
 
+#[derive(Clone, Copy)]
+struct ConnectionContext {
+    rmt_addr: SocketAddr,
+    id: usize,
+}
+
+impl ConnectionContext {
+    fn new(rmt_addr: SocketAddr, id: usize) -> Self {
+        Self { rmt_addr, id }
+    }
+}
+
+impl Display for ConnectionContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} #{:x}", self.rmt_addr, self.id)
+    }
+}
+
+/// Closes the connection when the handler unwinds from a panic, so the tasks of the connection end and release what they hold
+struct CloseOnPanic<'a> {
+    controller: &'a QuinnConnection,
+    is_closed: &'a IsClosed,
+}
+
+impl Drop for CloseOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.is_closed.set_closed();
+            self.controller
+                .close(VarInt::from_u32(0), b"internal server error");
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct IsClosed(Arc<IsClosedInner>);
 
@@ -257,3 +683,280 @@ impl Future for IsClosed {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConnectionConfig, ConnectionRegistry, RegisteredConnection, Throughput, UdpSessionMap,
+    };
+    use crate::{
+        buffer::{BufferProvider, HeapBufferProvider},
+        catch_panic::spawn_catching_panic,
+        metrics::{CounterMetrics, Metrics, RejectionReason},
+        testing::{self, TestClient},
+    };
+    use bytes::Bytes;
+    use quinn::{ConnectionError, IdleTimeout, TransportConfig, VarInt};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        net::{TcpListener, UdpSocket},
+        sync::{oneshot, Notify, Semaphore},
+        time,
+    };
+    use tuic_protocol::{Address, CloseCode, Command};
+
+    const TOKEN_DIGEST: [u8; 32] = [0; 32];
+
+    fn strict() -> ConnectionConfig {
+        ConnectionConfig {
+            strict_authentication: true,
+            ..ConnectionConfig::default()
+        }
+    }
+
+    async fn target() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn strict_authentication_closes_when_another_command_comes_first() {
+        let mut client = TestClient::connect(strict()).await;
+        let (_target, addr) = target().await;
+
+        client
+            .send_uni(Command::new_connect(Address::SocketAddress(addr)))
+            .await;
+
+        assert_eq!(
+            client.close_code().await,
+            Some(VarInt::from_u32(CloseCode::Unauthenticated.as_u32()))
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_authentication_relays_after_the_token() {
+        let client = TestClient::connect(strict()).await;
+        let (_target, addr) = target().await;
+
+        client.authenticate(TOKEN_DIGEST).await;
+        assert!(client.connect_to(addr).await);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_dropped_without_an_application_close() {
+        let mut server_config = testing::server_config();
+        let mut transport = TransportConfig::default();
+        transport.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(200))));
+        server_config.transport = Arc::new(transport);
+
+        let metrics = Arc::new(CounterMetrics::default());
+        let config = ConnectionConfig {
+            metrics: metrics.clone(),
+            ..ConnectionConfig::default()
+        };
+
+        let mut client = TestClient::connect_with(config, server_config).await;
+        client.authenticate(TOKEN_DIGEST).await;
+
+        assert!(matches!(client.closed().await, ConnectionError::TimedOut));
+
+        time::timeout(Duration::from_secs(1), &mut client.handler)
+            .await
+            .expect("the handler is still running")
+            .unwrap();
+
+        assert_eq!(metrics.rejections(RejectionReason::IdleTimeout), 1);
+    }
+
+    #[tokio::test]
+    async fn connects_sent_before_the_token_wait_for_it() {
+        let client = TestClient::connect(strict()).await;
+        let (_target, addr) = target().await;
+
+        let (accepted, ()) = tokio::join!(client.connect_to(addr), async {
+            time::sleep(Duration::from_millis(50)).await;
+            client.authenticate(TOKEN_DIGEST).await;
+        });
+
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn connects_over_the_cap_wait_for_a_free_slot() {
+        let client = TestClient::connect(ConnectionConfig {
+            max_concurrent_connects: Some(1),
+            ..ConnectionConfig::default()
+        })
+        .await;
+        client.authenticate(TOKEN_DIGEST).await;
+
+        let (target, addr) = target().await;
+        let (mut first, _first_recv) = client.relay_to(addr).await.unwrap();
+        let (first_target, _) = target.accept().await.unwrap();
+
+        let (mut send, mut recv) = client.conn.connection.open_bi().await.unwrap();
+        Command::new_connect(Address::SocketAddress(addr))
+            .write_to(&mut send)
+            .await
+            .unwrap();
+
+        assert!(
+            time::timeout(Duration::from_millis(300), Command::read_from(&mut recv))
+                .await
+                .is_err(),
+            "the connect over the cap was answered"
+        );
+
+        // UDP associations are not held back by the cap
+        let dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client
+            .send_packet(0, dst.local_addr().unwrap(), b"packet")
+            .await;
+        let mut buf = [0; 16];
+        time::timeout(Duration::from_secs(5), dst.recv_from(&mut buf))
+            .await
+            .expect("the packet was not relayed")
+            .unwrap();
+
+        // ending the first relay frees its slot
+        first.finish().await.unwrap();
+        drop(first_target);
+
+        let res = time::timeout(Duration::from_secs(5), Command::read_from(&mut recv))
+            .await
+            .expect("the connect was not answered after the slot was freed")
+            .unwrap();
+        assert!(matches!(res, Command::Response(true)));
+    }
+
+    /// Panics the first time a buffer is acquired, which happens in the task relaying a stream
+    #[derive(Default)]
+    struct PanickingOnceBuffers(AtomicBool);
+
+    impl BufferProvider for PanickingOnceBuffers {
+        fn acquire(&self, size: usize) -> Vec<u8> {
+            if !self.0.swap(true, Ordering::AcqRel) {
+                panic!("relay task panicked");
+            }
+
+            HeapBufferProvider.acquire(size)
+        }
+    }
+
+    #[derive(Default)]
+    struct PanicNotifier(Notify);
+
+    impl Metrics for PanicNotifier {
+        fn on_connection_panic(&self, _rmt_addr: SocketAddr) {
+            self.0.notify_one();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_panicking_stream_task_leaves_the_connection_serving() {
+        let panics = Arc::new(PanicNotifier::default());
+        let config = ConnectionConfig {
+            buffer_provider: Arc::new(PanickingOnceBuffers::default()),
+            metrics: panics.clone(),
+            ..ConnectionConfig::default()
+        };
+
+        let client = TestClient::connect(config).await;
+        client.authenticate(TOKEN_DIGEST).await;
+
+        let (target, addr) = target().await;
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = target.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        assert!(client.connect_to(addr).await);
+        time::timeout(Duration::from_secs(5), panics.0.notified())
+            .await
+            .expect("the relay task did not panic");
+
+        let (mut send, mut recv) = client.relay_to(addr).await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+
+        let mut buf = [0; 4];
+        time::timeout(Duration::from_secs(5), recv.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_releases_its_resources() {
+        let config = Arc::new(ConnectionConfig::default());
+        let rmt_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let pending_conns = Arc::new(Semaphore::new(1));
+        let registry = ConnectionRegistry::default();
+
+        let (udp_sessions, _recv_pkt_rx) = UdpSessionMap::new(config.clone());
+        let udp_sessions = Arc::new(udp_sessions);
+        let tcp_streams = Arc::new(AtomicUsize::new(0));
+        let throughput = Arc::new(Throughput::new(config.throughput_time_constant));
+
+        registry.lock().push(RegisteredConnection {
+            rmt_addr,
+            udp_sessions: Arc::downgrade(&udp_sessions),
+            tcp_streams: Arc::downgrade(&tcp_streams),
+            throughput: Arc::downgrade(&throughput),
+        });
+
+        // an association, with the socket it relays through
+        let dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let dst_addr = Address::SocketAddress(dst.local_addr().unwrap());
+        udp_sessions
+            .send(0, Bytes::from_static(b"packet"), dst_addr, rmt_addr)
+            .await;
+        let (_, assoc_addr) = dst.recv_from(&mut [0; 16]).await.unwrap();
+        assert!(UdpSocket::bind(assoc_addr).await.is_err());
+
+        let permit = pending_conns.clone().try_acquire_owned().unwrap();
+        let (panicked_tx, panicked_rx) = oneshot::channel();
+
+        let handler = spawn_catching_panic(
+            async move {
+                let _held = (permit, udp_sessions, tcp_streams, throughput);
+                panic!("connection handler panicked");
+            },
+            move || {
+                let _ = panicked_tx.send(());
+            },
+        );
+
+        assert!(handler.await.unwrap().is_none());
+        assert!(panicked_rx.await.is_ok());
+
+        assert_eq!(pending_conns.available_permits(), 1);
+        assert!(!registry.lock()[0].is_alive());
+        assert!(registry.lock()[0].snapshot().is_none());
+
+        // the association closes its socket once it sees the association map is gone
+        let rebind = async {
+            while UdpSocket::bind(assoc_addr).await.is_err() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(time::timeout(Duration::from_secs(1), rebind).await.is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Spaces out packets evenly at a fixed rate. Packets that would be delayed longer than the latency budget are dropped instead
+pub struct UdpPacer {
+    interval: Duration,
+    max_delay: Duration,
+    next_send: Instant,
+}
+
+impl UdpPacer {
+    pub fn new(rate: u64, max_delay: Duration) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate as f64),
+            max_delay,
+            next_send: Instant::now(),
+        }
+    }
+
+    /// The number of packets that can be waiting within the latency budget
+    pub fn queue_capacity(&self) -> usize {
+        (self.max_delay.as_secs_f64() / self.interval.as_secs_f64()) as usize + 1
+    }
+
+    /// Waits until the packet queued at `queued_at` may be sent. Returns `false` without waiting if the packet should be dropped
+    pub async fn wait(&mut self, queued_at: Instant) -> bool {
+        let send_at = self.next_send.max(Instant::now());
+
+        if send_at.duration_since(queued_at) > self.max_delay {
+            return false;
+        }
+
+        self.next_send = send_at + self.interval;
+        time::sleep_until(send_at).await;
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UdpPacer;
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+
+    const INTERVAL: Duration = Duration::from_millis(100);
+    const MAX_DELAY: Duration = Duration::from_millis(250);
+
+    #[tokio::test(start_paused = true)]
+    async fn packets_over_the_rate_are_delayed() {
+        let mut pacer = UdpPacer::new(10, MAX_DELAY);
+        let started = Instant::now();
+
+        for i in 0..3 {
+            assert!(pacer.wait(started).await);
+            assert_eq!(started.elapsed(), INTERVAL * i);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packets_beyond_the_latency_budget_are_dropped() {
+        let mut pacer = UdpPacer::new(10, MAX_DELAY);
+        assert_eq!(pacer.queue_capacity(), 3);
+
+        let started = Instant::now();
+
+        for _ in 0..3 {
+            assert!(pacer.wait(started).await);
+        }
+
+        // the next slot is 300 ms after the packet was queued, past the budget
+        let dropped_at = Instant::now();
+        assert!(!pacer.wait(started).await);
+        assert_eq!(dropped_at.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packets_within_the_rate_are_not_delayed() {
+        let mut pacer = UdpPacer::new(10, MAX_DELAY);
+
+        for _ in 0..3 {
+            time::sleep(INTERVAL * 2).await;
+
+            let queued_at = Instant::now();
+            assert!(pacer.wait(queued_at).await);
+            assert_eq!(queued_at.elapsed(), Duration::ZERO);
+        }
+    }
+}
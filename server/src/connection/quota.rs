@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct ByteQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl ByteQuota {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn consume(&self, amount: u64) -> bool {
+        self.used.fetch_add(amount, Ordering::AcqRel) + amount <= self.limit
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used.load(Ordering::Acquire) >= self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteQuota;
+
+    #[test]
+    fn bytes_up_to_the_limit_are_allowed() {
+        let quota = ByteQuota::new(100);
+
+        assert!(quota.consume(60));
+        assert!(quota.consume(40));
+        assert!(quota.is_exhausted());
+        assert!(!quota.consume(1));
+    }
+
+    #[test]
+    fn overshooting_consumption_is_refused() {
+        let quota = ByteQuota::new(100);
+
+        assert!(quota.consume(99));
+        assert!(!quota.is_exhausted());
+        assert!(!quota.consume(2));
+        assert!(quota.is_exhausted());
+    }
+}
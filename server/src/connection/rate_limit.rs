@@ -0,0 +1,106 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+pub struct RateLimiter {
+    rate: f64,
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new(RateLimiterState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn try_acquire(&self, amount: u64) -> bool {
+        let mut state = self.state.lock();
+        state.refill(self.rate);
+
+        if state.tokens >= amount as f64 {
+            state.tokens -= amount as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub async fn acquire(&self, amount: u64) {
+        let wait = {
+            let mut state = self.state.lock();
+            state.refill(self.rate);
+            state.tokens -= amount as f64;
+
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+impl RateLimiterState {
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+    use tokio::time;
+
+    #[test]
+    fn bursts_are_bounded_by_the_rate() {
+        let limiter = RateLimiter::new(10);
+
+        assert!(limiter.try_acquire(10));
+        assert!(!limiter.try_acquire(1));
+    }
+
+    #[tokio::test]
+    async fn tokens_refill_over_time_up_to_the_rate() {
+        let limiter = RateLimiter::new(100);
+        assert!(limiter.try_acquire(100));
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(limiter.try_acquire(3));
+
+        time::sleep(Duration::from_millis(1100)).await;
+        assert!(!limiter.try_acquire(101));
+        assert!(limiter.try_acquire(100));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_the_deficit() {
+        let limiter = RateLimiter::new(1000);
+        assert!(limiter.try_acquire(1000));
+
+        let started = Instant::now();
+        limiter.acquire(100).await;
+        assert!(started.elapsed() >= Duration::from_millis(90));
+
+        // the tokens refilled while waiting paid off the deficit
+        assert!(!limiter.try_acquire(50));
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::{HashSet, VecDeque};
+
+/// A set of association IDs that forgets the oldest ID once it holds `capacity` of them
+pub struct RecentIds {
+    capacity: usize,
+    order: VecDeque<u32>,
+    ids: HashSet<u32>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Returns `false` if the ID was already in the set
+    pub fn insert(&mut self, id: u32) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(id);
+        true
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.ids.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentIds;
+
+    #[test]
+    fn duplicates_are_reported() {
+        let mut ids = RecentIds::new(4);
+
+        assert!(ids.insert(1));
+        assert!(!ids.insert(1));
+        assert!(ids.contains(1));
+        assert!(!ids.contains(2));
+    }
+
+    #[test]
+    fn oldest_ids_are_forgotten_at_capacity() {
+        let mut ids = RecentIds::new(3);
+
+        for id in 0..10 {
+            assert!(ids.insert(id));
+        }
+
+        assert_eq!(ids.ids.len(), 3);
+        assert_eq!(ids.order.len(), 3);
+        assert!((0..7).all(|id| !ids.contains(id)));
+        assert!((7..10).all(|id| ids.contains(id)));
+    }
+}
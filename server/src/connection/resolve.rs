@@ -0,0 +1,273 @@
+use std::{
+    future::Future,
+    io::Error as IoError,
+    net::SocketAddr,
+    time::{Duration, Instant},
+    vec::IntoIter,
+};
+use thiserror::Error;
+use tokio::{task, time};
+use tuic_protocol::Address;
+
+/// Resolves the addresses of targets
+pub trait Resolver {
+    fn lookup(
+        &self,
+        hostname: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>, ResolveError>> + Send;
+}
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    /// The resolver could not answer for now, e.g. the name server did not respond
+    #[error("temporary failure: {0}")]
+    Temporary(String),
+    #[error("{0}")]
+    Permanent(String),
+    #[error(transparent)]
+    Io(#[from] IoError),
+}
+
+impl ResolveError {
+    pub fn is_temporary(&self) -> bool {
+        matches!(self, Self::Temporary(_))
+    }
+}
+
+/// Resolves with `getaddrinfo`, like the standard library does
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    async fn lookup(&self, hostname: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+        let hostname = hostname.to_owned();
+        task::spawn_blocking(move || getaddrinfo(&hostname, port))
+            .await
+            .map_err(IoError::from)?
+    }
+}
+
+/// Resolves the target, retrying temporary failures with exponential backoff as long as the next attempt starts within `max_time`
+pub async fn resolve(
+    resolver: &impl Resolver,
+    addr: &Address,
+    max_retries: usize,
+    max_time: Duration,
+) -> Result<IntoIter<SocketAddr>, ResolveError> {
+    let (hostname, port) = match addr {
+        Address::DomainAddress(hostname, port) => (hostname, *port),
+        Address::SocketAddress(addr) => return Ok(vec![*addr].into_iter()),
+    };
+
+    let deadline = Instant::now() + max_time;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0;
+
+    loop {
+        match resolver.lookup(hostname, port).await {
+            Ok(addrs) => return Ok(addrs.into_iter()),
+            Err(err)
+                if retries < max_retries
+                    && err.is_temporary()
+                    && Instant::now() + backoff < deadline =>
+            {
+                log::debug!("[resolve] [{addr}] {err}, retrying in {backoff:?}");
+                time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Calls `getaddrinfo` directly, as the errors of `std::net::ToSocketAddrs` only keep the description of the error code
+#[cfg(target_os = "linux")]
+fn getaddrinfo(hostname: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+    use std::{
+        ffi::{CStr, CString},
+        io::ErrorKind,
+        mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddrV6},
+        ptr,
+    };
+
+    let c_hostname = CString::new(hostname)
+        .map_err(|_| IoError::new(ErrorKind::InvalidInput, "hostname contains a nul byte"))?;
+
+    let mut hints: libc::addrinfo = unsafe { mem::zeroed() };
+    hints.ai_socktype = libc::SOCK_STREAM;
+
+    let mut res = ptr::null_mut();
+    let code = unsafe { libc::getaddrinfo(c_hostname.as_ptr(), ptr::null(), &hints, &mut res) };
+
+    if code != 0 {
+        if code == libc::EAI_SYSTEM {
+            return Err(ResolveError::Io(IoError::last_os_error()));
+        }
+
+        let desc = unsafe { CStr::from_ptr(libc::gai_strerror(code)) }
+            .to_string_lossy()
+            .into_owned();
+
+        return Err(if code == libc::EAI_AGAIN {
+            ResolveError::Temporary(desc)
+        } else {
+            ResolveError::Permanent(desc)
+        });
+    }
+
+    let mut addrs = Vec::new();
+    let mut cur = res;
+
+    while let Some(info) = unsafe { cur.as_ref() } {
+        if let Some(addr) = unsafe { info.ai_addr.as_ref() } {
+            match addr.sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in) };
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    addrs.push(SocketAddr::from((ip, port)));
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in6) };
+                    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    addrs.push(SocketAddr::from(SocketAddrV6::new(
+                        ip,
+                        port,
+                        addr.sin6_flowinfo,
+                        addr.sin6_scope_id,
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        cur = info.ai_next;
+    }
+
+    unsafe { libc::freeaddrinfo(res) };
+
+    Ok(addrs)
+}
+
+/// Without access to the error code, only timeouts are known to be temporary
+#[cfg(not(target_os = "linux"))]
+fn getaddrinfo(hostname: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+    use std::{io::ErrorKind, net::ToSocketAddrs};
+
+    match (hostname, port).to_socket_addrs() {
+        Ok(addrs) => Ok(addrs.collect()),
+        Err(err) if err.kind() == ErrorKind::TimedOut => {
+            Err(ResolveError::Temporary(err.to_string()))
+        }
+        Err(err) => Err(ResolveError::Io(err)),
+    }
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, ResolveError, Resolver, SystemResolver};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tuic_protocol::Address;
+
+    /// Fails the first `failures` lookups with the error `fail` returns
+    struct StubResolver {
+        failures: usize,
+        fail: fn() -> ResolveError,
+        lookups: AtomicUsize,
+    }
+
+    impl StubResolver {
+        fn new(failures: usize, fail: fn() -> ResolveError) -> Self {
+            Self {
+                failures,
+                fail,
+                lookups: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Resolver for StubResolver {
+        async fn lookup(&self, _: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+            if self.lookups.fetch_add(1, Ordering::Relaxed) < self.failures {
+                Err((self.fail)())
+            } else {
+                Ok(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))])
+            }
+        }
+    }
+
+    fn target() -> Address {
+        Address::DomainAddress("example.com".to_owned(), 80)
+    }
+
+    #[tokio::test]
+    async fn temporary_failures_are_retried() {
+        let resolver = StubResolver::new(1, || ResolveError::Temporary("EAI_AGAIN".to_owned()));
+
+        let addrs = resolve(&resolver, &target(), 3, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            addrs.collect::<Vec<_>>(),
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]
+        );
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn nonexistent_names_fail_immediately() {
+        let resolver = StubResolver::new(1, || ResolveError::Permanent("EAI_NONAME".to_owned()));
+
+        let err = resolve(&resolver, &target(), 3, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ResolveError::Permanent(_)));
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let resolver = StubResolver::new(5, || ResolveError::Temporary("EAI_AGAIN".to_owned()));
+
+        let err = resolve(&resolver, &target(), 1, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+
+        assert!(err.is_temporary());
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn socket_addresses_are_not_looked_up() {
+        let resolver = StubResolver::new(1, || ResolveError::Permanent("EAI_NONAME".to_owned()));
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+
+        let addrs = resolve(&resolver, &Address::SocketAddress(addr), 0, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(addrs.collect::<Vec<_>>(), [addr]);
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn system_resolver_resolves_numeric_hosts() {
+        assert_eq!(
+            SystemResolver.lookup("127.0.0.1", 80).await.unwrap(),
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]
+        );
+        assert_eq!(
+            SystemResolver.lookup("::1", 443).await.unwrap(),
+            [SocketAddr::from((Ipv6Addr::LOCALHOST, 443))]
+        );
+    }
+}
@@ -1,42 +1,221 @@
-use super::udp::UdpSessionMap;
+use super::{
+    quota::ByteQuota,
+    rate_limit::RateLimiter,
+    resolve::{self, SystemResolver},
+    throughput::Throughput,
+    udp::UdpSessionMap,
+    ConnectionConfig, ConnectionContext,
+};
+use crate::buffer::BufferProvider;
 use bytes::{Bytes, BytesMut};
 use quinn::{
     Connection as QuinnConnection, ConnectionError, ReadExactError, RecvStream, SendDatagramError,
-    SendStream, WriteError,
+    SendStream, VarInt, WriteError,
+};
+use socket2::SockRef;
+use std::{
+    future,
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-use std::{io::Error as IoError, net::SocketAddr, sync::Arc};
 use thiserror::Error;
-use tokio::{io, net::TcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+    time,
+};
 use tuic_protocol::{Address, Command};
 
+/// What the relayed bytes of a connection are accounted against
+#[derive(Clone, Copy)]
+pub struct ByteAccounting<'a> {
+    pub rate_limiter: Option<&'a RateLimiter>,
+    pub quota: Option<&'a ByteQuota>,
+    pub throughput: &'a Throughput,
+}
+
 pub async fn connect(
     mut send: SendStream,
     mut recv: RecvStream,
-    addr: Address,
-) -> Result<(), TaskError> {
-    let mut stream = None;
-    let addrs = addr.to_socket_addrs().await?;
-
-    for addr in addrs {
-        if let Ok(tcp_stream) = TcpStream::connect(addr).await {
-            stream = Some(tcp_stream);
-            break;
-        }
-    }
-
+    stream: Option<TcpStream>,
+    accounting: ByteAccounting<'_>,
+    config: &ConnectionConfig,
+    trace_ctx: Option<ConnectionContext>,
+) -> Result<ConnectOutcome, TaskError> {
     if let Some(mut stream) = stream {
         let resp = Command::new_response(true);
+        trace_response(trace_ctx, &resp);
         resp.write_to(&mut send).await?;
 
-        let (mut target_recv, mut target_send) = stream.split();
-        let target_to_tunnel = io::copy(&mut target_recv, &mut send);
-        let tunnel_to_target = io::copy(&mut recv, &mut target_send);
-        let _ = tokio::try_join!(target_to_tunnel, tunnel_to_target);
+        let end = relay(
+            &mut stream,
+            &mut recv,
+            &mut send,
+            accounting,
+            config.buffer_provider.as_ref(),
+            config.first_byte_timeout,
+        )
+        .await;
+
+        match end {
+            RelayEnd::Finished { has_data } => Ok(ConnectOutcome::Relayed { has_data }),
+            RelayEnd::FirstByteTimeout => {
+                let _ = send.reset(VarInt::from_u32(0));
+                let _ = recv.stop(VarInt::from_u32(0));
+                Err(TaskError::FirstByteTimeout)
+            }
+            RelayEnd::TargetReset => {
+                if config.reset_on_target_reset {
+                    let _ = send.reset(VarInt::from_u32(0));
+                    let _ = recv.stop(VarInt::from_u32(0));
+                }
+
+                Err(TaskError::TargetReset)
+            }
+        }
     } else {
         let resp = Command::new_response(false);
+        trace_response(trace_ctx, &resp);
         resp.write_to(&mut send).await?;
+
+        Ok(ConnectOutcome::Unreachable)
+    }
+}
+
+pub enum ConnectOutcome {
+    Relayed { has_data: bool },
+    Unreachable,
+}
+
+enum RelayEnd {
+    Finished { has_data: bool },
+    FirstByteTimeout,
+    TargetReset,
+}
+
+/// Relays between the target and the tunnel in both directions at once, so whichever side sends first is relayed without waiting for the other
+async fn relay<R, W>(
+    stream: &mut TcpStream,
+    tunnel_recv: &mut R,
+    tunnel_send: &mut W,
+    accounting: ByteAccounting<'_>,
+    buffer_provider: &dyn BufferProvider,
+    first_byte_timeout: Option<Duration>,
+) -> RelayEnd
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let has_data = AtomicBool::new(false);
+    let (mut target_recv, mut target_send) = stream.split();
+    let is_target_reset = AtomicBool::new(false);
+    let target_to_tunnel = async {
+        let res = copy(
+            &mut target_recv,
+            tunnel_send,
+            accounting,
+            &has_data,
+            buffer_provider,
+        )
+        .await;
+
+        // errors from writing to the stream are not OS errors
+        if let Err(err) = &res {
+            if err.kind() == ErrorKind::ConnectionReset && err.raw_os_error().is_some() {
+                is_target_reset.store(true, Ordering::Release);
+            }
+        }
+
+        res
+    };
+    let tunnel_to_target = copy(
+        tunnel_recv,
+        &mut target_send,
+        accounting,
+        &has_data,
+        buffer_provider,
+    );
+    let relay = async {
+        let _ = tokio::try_join!(target_to_tunnel, tunnel_to_target);
     };
 
+    let is_timed_out = match first_byte_timeout {
+        Some(timeout) => tokio::select! {
+            () = relay => false,
+            () = wait_first_byte(timeout, &has_data) => true,
+        },
+        None => {
+            relay.await;
+            false
+        }
+    };
+
+    if is_timed_out {
+        RelayEnd::FirstByteTimeout
+    } else if is_target_reset.load(Ordering::Acquire) {
+        RelayEnd::TargetReset
+    } else {
+        RelayEnd::Finished {
+            has_data: has_data.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Connects to the first reachable address the target resolves to, trying at most the configured number of them. A failed resolution is treated like a failed connection, and addresses matching the listening address of the server are skipped
+pub async fn connect_target(addr: &Address, config: &ConnectionConfig) -> Option<TcpStream> {
+    let resolved = resolve::resolve(
+        &SystemResolver,
+        addr,
+        config.resolve_retries,
+        config.resolve_retry_time,
+    )
+    .await
+    .ok()?;
+
+    for resolved in resolved.take(config.max_resolved_addresses.unwrap_or(usize::MAX)) {
+        if config
+            .listen_address
+            .as_ref()
+            .is_some_and(|listen_addr| listen_addr.matches(resolved))
+        {
+            log::warn!(
+                "[resolve] [{addr}] {resolved} is the listening address of the server, skipped"
+            );
+            continue;
+        }
+
+        if let Ok(stream) = connect_tcp(resolved, config.ipv6_flow_label, config.tcp_nodelay).await
+        {
+            return Some(stream);
+        }
+    }
+
+    None
+}
+
+/// Resolves once `timeout` has passed without any data relayed
+async fn wait_first_byte(timeout: Duration, has_data: &AtomicBool) {
+    time::sleep(timeout).await;
+
+    if has_data.load(Ordering::Acquire) {
+        future::pending().await
+    }
+}
+
+fn trace_response(trace_ctx: Option<ConnectionContext>, resp: &Command) {
+    if let Some(ctx) = trace_ctx {
+        log::trace!("[{ctx}] [trace] [send] {resp}");
+    }
+}
+
+pub async fn refuse_connect(mut send: SendStream) -> Result<(), TaskError> {
+    let resp = Command::new_response(false);
+    resp.write_to(&mut send).await?;
     Ok(())
 }
 
@@ -47,15 +226,12 @@ pub async fn packet_from_uni_stream(
     len: u16,
     addr: Address,
     src_addr: SocketAddr,
-    max_udp_pkt_size: usize,
 ) -> Result<(), TaskError> {
     let mut buf = vec![0; len as usize];
     stream.read_exact(&mut buf).await?;
 
     let pkt = Bytes::from(buf);
-    udp_sessions
-        .send(assoc_id, pkt, addr, src_addr, max_udp_pkt_size)
-        .await?;
+    udp_sessions.send(assoc_id, pkt, addr, src_addr).await;
 
     Ok(())
 }
@@ -66,11 +242,8 @@ pub async fn packet_from_datagram(
     assoc_id: u32,
     addr: Address,
     src_addr: SocketAddr,
-    max_udp_pkt_size: usize,
 ) -> Result<(), TaskError> {
-    udp_sessions
-        .send(assoc_id, pkt, addr, src_addr, max_udp_pkt_size)
-        .await?;
+    udp_sessions.send(assoc_id, pkt, addr, src_addr).await;
     Ok(())
 }
 
@@ -108,15 +281,153 @@ pub async fn packet_to_datagram(
     Ok(())
 }
 
+pub async fn packets_to_datagram(
+    conn: QuinnConnection,
+    pkts: Vec<(u32, Bytes, Address)>,
+) -> Result<(), TaskError> {
+    let mut buf = BytesMut::new();
+
+    for (assoc_id, pkt, addr) in pkts {
+        let cmd = Command::new_packet(assoc_id, pkt.len() as u16, addr);
+
+        buf.reserve(cmd.serialized_len() + pkt.len());
+        cmd.write_to_buf(&mut buf);
+        buf.extend_from_slice(&pkt);
+    }
+
+    conn.send_datagram(buf.freeze())?;
+
+    Ok(())
+}
+
 pub async fn dissociate(
     udp_sessions: Arc<UdpSessionMap>,
     assoc_id: u32,
     src_addr: SocketAddr,
 ) -> Result<(), TaskError> {
-    udp_sessions.dissociate(assoc_id, src_addr);
+    udp_sessions.dissociate(assoc_id, src_addr).await;
+    Ok(())
+}
+
+pub async fn send_dissociate(conn: QuinnConnection, assoc_id: u32) -> Result<(), TaskError> {
+    let mut stream = conn.open_uni().await?;
+    let cmd = Command::new_dissociate(assoc_id);
+    cmd.write_to(&mut stream).await?;
     Ok(())
 }
 
+async fn connect_tcp(
+    addr: SocketAddr,
+    ipv6_flow_label: Option<u32>,
+    tcp_nodelay: bool,
+) -> Result<TcpStream, IoError> {
+    let stream = match (addr, ipv6_flow_label) {
+        (SocketAddr::V6(mut addr), Some(label)) => {
+            let socket = TcpSocket::new_v6()?;
+            set_flow_info_send(&socket)?;
+
+            // `sin6_flowinfo` is handed to the kernel as is, which expects network byte order
+            addr.set_flowinfo(label.to_be());
+            socket.connect(SocketAddr::V6(addr)).await?
+        }
+        (addr, _) => TcpStream::connect(addr).await?,
+    };
+
+    if tcp_nodelay {
+        stream.set_nodelay(true)?;
+    }
+
+    // the stream has no way to mark urgent data, so it is relayed in-band instead of being left out of the stream by the kernel
+    SockRef::from(&stream).set_out_of_band_inline(true)?;
+
+    Ok(stream)
+}
+
+#[cfg(target_os = "linux")]
+fn set_flow_info_send(socket: &TcpSocket) -> Result<(), IoError> {
+    use std::{mem, os::unix::io::AsRawFd};
+
+    let enable: libc::c_int = 1;
+
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWINFO_SEND,
+            &enable as *const _ as *const libc::c_void,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_flow_info_send(_socket: &TcpSocket) -> Result<(), IoError> {
+    Ok(())
+}
+
+const COPY_BUFFER_SIZE: usize = 8192;
+
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    accounting: ByteAccounting<'_>,
+    has_data: &AtomicBool,
+    buffer_provider: &dyn BufferProvider,
+) -> Result<(), IoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = buffer_provider.acquire(COPY_BUFFER_SIZE);
+    let res = copy_with_buffer(reader, writer, accounting, has_data, &mut buf).await;
+    buffer_provider.release(buf);
+    res
+}
+
+async fn copy_with_buffer<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    accounting: ByteAccounting<'_>,
+    has_data: &AtomicBool,
+    buf: &mut [u8],
+) -> Result<(), IoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let len = reader.read(buf).await?;
+
+        if len == 0 {
+            break;
+        }
+
+        accounting.throughput.record(len as u64);
+        has_data.store(true, Ordering::Release);
+
+        if let Some(quota) = accounting.quota {
+            if !quota.consume(len as u64) {
+                return Err(IoError::other("byte quota exceeded"));
+            }
+        }
+
+        if let Some(rate_limiter) = accounting.rate_limiter {
+            rate_limiter.acquire(len as u64).await;
+        }
+
+        writer.write_all(&buf[..len]).await?;
+    }
+
+    // passes the end of the data on, so the peer does not wait for the other direction to finish too
+    writer.shutdown().await
+}
+
 #[derive(Error, Debug)]
 pub enum TaskError {
     #[error(transparent)]
@@ -129,4 +440,348 @@ pub enum TaskError {
     WriteStream(#[from] WriteError),
     #[error(transparent)]
     SendDatagram(#[from] SendDatagramError),
+    #[error("no data transferred within the first-byte timeout")]
+    FirstByteTimeout,
+    #[error("connection reset by the target")]
+    TargetReset,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, relay, ByteAccounting, RelayEnd, COPY_BUFFER_SIZE};
+    use crate::{
+        buffer::{BufferProvider, HeapBufferProvider, PooledBufferProvider},
+        connection::{throughput::Throughput, ConnectionConfig},
+        testing::TestClient,
+    };
+    use socket2::SockRef;
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+        net::{TcpListener, TcpStream, UdpSocket},
+        time,
+    };
+
+    const BANNER: &[u8] = b"220 smtp.example.com ESMTP ready\r\n";
+
+    /// An endless stream of bytes, counting how many have been read from it
+    struct CountingSource(Arc<AtomicUsize>);
+
+    impl AsyncRead for CountingSource {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let len = buf.remaining();
+            buf.put_slice(&vec![0x61; len]);
+            self.0.fetch_add(len, Ordering::AcqRel);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A TCP target echoing back what it receives, on every connection
+    async fn echo_target() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Sends `count` packets to `dst` through the association 0 of `client`, returning how many arrive within `timeout`
+    async fn relay_packets(
+        client: &TestClient,
+        dst: &UdpSocket,
+        count: usize,
+        timeout: Duration,
+    ) -> usize {
+        let dst_addr = dst.local_addr().unwrap();
+
+        for _ in 0..count {
+            client.send_packet(0, dst_addr, b"packet").await;
+        }
+
+        let mut buf = [0; 16];
+        let mut received = 0;
+
+        while time::timeout(timeout, dst.recv_from(&mut buf))
+            .await
+            .is_ok()
+        {
+            received += 1;
+        }
+
+        received
+    }
+
+    fn accounting(throughput: &Throughput) -> ByteAccounting<'_> {
+        ByteAccounting {
+            rate_limiter: None,
+            quota: None,
+            throughput,
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_stops_reading_while_the_writer_is_stalled() {
+        const PIPE_CAPACITY: usize = 16 * 1024;
+
+        let read = Arc::new(AtomicUsize::new(0));
+        let mut source = CountingSource(read.clone());
+        let (mut writer, _stalled_reader) = io::duplex(PIPE_CAPACITY);
+        let throughput = Throughput::new(Duration::from_secs(1));
+        let has_data = AtomicBool::new(false);
+
+        let copying = copy(
+            &mut source,
+            &mut writer,
+            accounting(&throughput),
+            &has_data,
+            &HeapBufferProvider,
+        );
+
+        assert!(time::timeout(Duration::from_millis(200), copying)
+            .await
+            .is_err());
+
+        let read = read.load(Ordering::Acquire);
+        assert!(read >= PIPE_CAPACITY);
+        assert!(
+            read <= PIPE_CAPACITY + COPY_BUFFER_SIZE,
+            "{read} bytes read"
+        );
+    }
+
+    /// Counts the buffers handed out by a pool that are not released yet
+    struct CountingBuffers {
+        pool: PooledBufferProvider,
+        outstanding: AtomicUsize,
+    }
+
+    impl BufferProvider for CountingBuffers {
+        fn acquire(&self, size: usize) -> Vec<u8> {
+            self.outstanding.fetch_add(1, Ordering::AcqRel);
+            self.pool.acquire(size)
+        }
+
+        fn release(&self, buf: Vec<u8>) {
+            self.outstanding.fetch_sub(1, Ordering::AcqRel);
+            self.pool.release(buf);
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_returns_its_buffer_to_the_pool() {
+        let buffers = CountingBuffers {
+            pool: PooledBufferProvider::new(1),
+            outstanding: AtomicUsize::new(0),
+        };
+        let throughput = Throughput::new(Duration::from_secs(1));
+        let has_data = AtomicBool::new(false);
+
+        let mut source: &[u8] = &[0; 3 * COPY_BUFFER_SIZE];
+        let mut sink = Vec::new();
+        copy(
+            &mut source,
+            &mut sink,
+            accounting(&throughput),
+            &has_data,
+            &buffers,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sink.len(), 3 * COPY_BUFFER_SIZE);
+        assert_eq!(buffers.outstanding.load(Ordering::Acquire), 0);
+
+        // the buffer is released when the copy fails as well
+        let (mut writer, reader) = io::duplex(COPY_BUFFER_SIZE);
+        drop(reader);
+        let mut source: &[u8] = b"data";
+        assert!(copy(
+            &mut source,
+            &mut writer,
+            accounting(&throughput),
+            &has_data,
+            &buffers,
+        )
+        .await
+        .is_err());
+
+        assert_eq!(buffers.outstanding.load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn udp_is_relayed_while_tcp_waits_for_its_rate_limit() {
+        let client = TestClient::connect(ConnectionConfig {
+            max_tcp_bytes_per_second: Some(1024),
+            ..ConnectionConfig::default()
+        })
+        .await;
+        client.authenticate([0; 32]).await;
+
+        let (mut send, mut recv) = client.relay_to(echo_target().await).await.unwrap();
+        send.write_all(&[0; 64 * 1024]).await.unwrap();
+
+        let dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let received = relay_packets(&client, &dst, 5, Duration::from_millis(500)).await;
+        assert_eq!(received, 5);
+
+        // the echo is still held back by the TCP rate limit
+        let mut echo = vec![0; 64 * 1024];
+        assert!(
+            time::timeout(Duration::from_millis(200), recv.read_exact(&mut echo))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_is_relayed_while_udp_is_over_its_rate_limit() {
+        let client = TestClient::connect(ConnectionConfig {
+            max_udp_packets_per_second: Some(1),
+            ..ConnectionConfig::default()
+        })
+        .await;
+        client.authenticate([0; 32]).await;
+
+        let dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let received = relay_packets(&client, &dst, 5, Duration::from_millis(200)).await;
+        assert_eq!(received, 1);
+
+        let (mut send, mut recv) = client.relay_to(echo_target().await).await.unwrap();
+        send.write_all(&[0x61; 64 * 1024]).await.unwrap();
+
+        let mut echo = vec![0; 64 * 1024];
+        time::timeout(Duration::from_secs(5), recv.read_exact(&mut echo))
+            .await
+            .expect("the TCP relay is throttled")
+            .unwrap();
+        assert!(echo.iter().all(|byte| *byte == 0x61));
+    }
+
+    #[tokio::test]
+    async fn slow_target_flow_controls_the_client() {
+        const SOCKET_BUFFER_SIZE: usize = 4096;
+        // the kernel doubles the requested socket buffer sizes and may keep a few more segments in flight
+        const MAX_BUFFERED: usize = 1024 * 1024;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        SockRef::from(&listener)
+            .set_recv_buffer_size(SOCKET_BUFFER_SIZE)
+            .unwrap();
+        let target_addr = listener.local_addr().unwrap();
+
+        // the target accepts but never reads
+        let target = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(10)).await;
+            drop(conn);
+        });
+
+        let mut stream = TcpStream::connect(target_addr).await.unwrap();
+        SockRef::from(&stream)
+            .set_send_buffer_size(SOCKET_BUFFER_SIZE)
+            .unwrap();
+
+        let read = Arc::new(AtomicUsize::new(0));
+        let mut tunnel_recv = CountingSource(read.clone());
+        let (mut tunnel_send, _client) = io::duplex(1024);
+        let throughput = Throughput::new(Duration::from_secs(1));
+
+        let relaying = relay(
+            &mut stream,
+            &mut tunnel_recv,
+            &mut tunnel_send,
+            accounting(&throughput),
+            &HeapBufferProvider,
+            None,
+        );
+        tokio::pin!(relaying);
+
+        assert!(time::timeout(Duration::from_millis(300), &mut relaying)
+            .await
+            .is_err());
+        let stalled_at = read.load(Ordering::Acquire);
+
+        assert!(time::timeout(Duration::from_millis(200), &mut relaying)
+            .await
+            .is_err());
+        let read = read.load(Ordering::Acquire);
+
+        assert_eq!(read, stalled_at, "the tunnel is still read from");
+        assert!(read <= MAX_BUFFERED, "{read} bytes read");
+
+        target.abort();
+    }
+
+    #[tokio::test]
+    async fn target_sending_first_is_relayed_before_the_client_sends() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+
+        let target = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            conn.write_all(BANNER).await.unwrap();
+
+            let mut req = Vec::new();
+            conn.read_to_end(&mut req).await.unwrap();
+            req
+        });
+
+        let mut stream = TcpStream::connect(target_addr).await.unwrap();
+        let (mut client, tunnel) = io::duplex(1024);
+        let (mut tunnel_recv, mut tunnel_send) = io::split(tunnel);
+        let throughput = Throughput::new(Duration::from_secs(1));
+        let accounting = ByteAccounting {
+            rate_limiter: None,
+            quota: None,
+            throughput: &throughput,
+        };
+
+        let relaying = relay(
+            &mut stream,
+            &mut tunnel_recv,
+            &mut tunnel_send,
+            accounting,
+            &HeapBufferProvider,
+            None,
+        );
+
+        let session = async {
+            let mut banner = vec![0; BANNER.len()];
+            time::timeout(Duration::from_secs(5), client.read_exact(&mut banner))
+                .await
+                .expect("banner not relayed without client data")
+                .unwrap();
+            assert_eq!(banner, BANNER);
+
+            client.write_all(b"QUIT\r\n").await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+
+        let (end, ()) = tokio::join!(relaying, session);
+        assert!(matches!(end, RelayEnd::Finished { has_data: true }));
+        assert_eq!(target.await.unwrap(), b"QUIT\r\n");
+    }
 }
@@ -0,0 +1,71 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// An exponentially decaying moving average of the bytes relayed per second
+pub struct Throughput {
+    time_constant: f64,
+    state: Mutex<ThroughputState>,
+}
+
+struct ThroughputState {
+    rate: f64,
+    last_update: Instant,
+}
+
+impl Throughput {
+    pub fn new(time_constant: Duration) -> Self {
+        Self {
+            time_constant: time_constant.as_secs_f64(),
+            state: Mutex::new(ThroughputState {
+                rate: 0.0,
+                last_update: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn record(&self, amount: u64) {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+
+        state.rate = state.decayed(self.time_constant, now) + amount as f64 / self.time_constant;
+        state.last_update = now;
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.state
+            .lock()
+            .decayed(self.time_constant, Instant::now())
+    }
+}
+
+impl ThroughputState {
+    fn decayed(&self, time_constant: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.rate * (-elapsed / time_constant).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throughput;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn recorded_bytes_decay_with_the_time_constant() {
+        let throughput = Throughput::new(Duration::from_secs(1));
+        assert_eq!(throughput.rate(), 0.0);
+
+        throughput.record(1000);
+        let rate = throughput.rate();
+        assert!(rate > 990.0 && rate <= 1000.0, "{rate}");
+
+        // e^-0.1 of the rate is left after a tenth of the time constant
+        thread::sleep(Duration::from_millis(100));
+        let rate = throughput.rate();
+        assert!(rate > 850.0 && rate < 910.0, "{rate}");
+
+        throughput.record(1000);
+        let rate = throughput.rate();
+        assert!(rate > 1850.0 && rate < 1910.0, "{rate}");
+    }
+}
@@ -1,12 +1,25 @@
+use super::{pacer::UdpPacer, rate_limit::RateLimiter, recent_ids::RecentIds, ConnectionConfig};
+use crate::{metrics::RejectionReason, snapshot::AssociationSnapshot};
 use bytes::Bytes;
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
-use std::{collections::HashMap, io::Error as IoError, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{Error as IoError, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
-    net::UdpSocket,
-    sync::mpsc::{self, Receiver, Sender},
+    net::{self, UdpSocket},
+    sync::{
+        mpsc::{self, error::TryRecvError, Receiver, Sender},
+        oneshot,
+    },
+    task::{self, JoinHandle},
+    time,
 };
-use tuic_protocol::Address;
+use tuic_protocol::{Address, Command};
 
 #[derive(Clone)]
 pub struct UdpPacketFrom(Arc<AtomicCell<Option<UdpPacketSource>>>);
@@ -33,118 +46,425 @@ impl UdpPacketFrom {
     }
 }
 
+/// Which address family UDP associations use when their destination is given as a domain
+///
+/// The socket of an association is bound when its first packet is sent, with the family of that packet's destination. Later destinations are only resolved to addresses of the same family
+#[derive(Clone, Copy)]
+pub enum AddressPreference {
+    Ipv4Only,
+    Ipv6Only,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl AddressPreference {
+    fn select(self, addrs: &[SocketAddr], bound_ipv6: Option<bool>) -> Option<SocketAddr> {
+        let family = |ipv6: bool| addrs.iter().copied().find(|addr| addr.is_ipv6() == ipv6);
+
+        match (bound_ipv6, self) {
+            (Some(ipv6), _) => family(ipv6),
+            (None, Self::Ipv4Only) => family(false),
+            (None, Self::Ipv6Only) => family(true),
+            (None, Self::PreferIpv4) => family(false).or_else(|| family(true)),
+            (None, Self::PreferIpv6) => family(true).or_else(|| family(false)),
+        }
+    }
+}
+
+/// The associations refused on a connection while UDP relaying is disabled
+pub struct RefusedAssociations {
+    ids: Mutex<RecentIds>,
+    replies: RateLimiter,
+}
+
+impl RefusedAssociations {
+    pub fn new() -> Self {
+        Self {
+            ids: Mutex::new(RecentIds::new(MAX_REFUSED_ASSOCIATIONS)),
+            replies: RateLimiter::new(REFUSAL_REPLIES_PER_SECOND),
+        }
+    }
+
+    /// Returns `false` if the association was already refused
+    pub fn insert(&self, assoc_id: u32) -> bool {
+        self.ids.lock().insert(assoc_id)
+    }
+
+    /// Whether a refusal can still be answered, so a peer cycling through association IDs can not make the server open a stream for each
+    pub fn may_reply(&self) -> bool {
+        self.replies.try_acquire(1)
+    }
+}
+
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum UdpPacketSource {
     UniStream,
     Datagram,
 }
 
-pub type SendPacketSender = Sender<(Bytes, Address)>;
-pub type SendPacketReceiver = Receiver<(Bytes, Address)>;
-pub type RecvPacketSender = Sender<(u32, Bytes, Address)>;
-pub type RecvPacketReceiver = Receiver<(u32, Bytes, Address)>;
+/// How many dissociated association IDs are remembered to drop their late packets
+const MAX_DISSOCIATED_ASSOCIATIONS: usize = 1024;
+
+/// How many association IDs refused while UDP relaying is disabled are remembered, so each is only answered once
+const MAX_REFUSED_ASSOCIATIONS: usize = 1024;
+
+/// The number of `Dissociate` commands sent each second at most in answer to refused associations
+const REFUSAL_REPLIES_PER_SECOND: u64 = 16;
+
+/// Resolved domain destinations are cached for each association, and the cache is cleared when it grows beyond this
+const MAX_RESOLVED_DESTINATIONS: usize = 64;
+
+/// The cached destinations of an association, keyed by hostname first so they are looked up without copying it
+#[derive(Default)]
+struct ResolvedDestinations {
+    addrs: HashMap<String, HashMap<u16, SocketAddr>>,
+    len: usize,
+}
+
+impl ResolvedDestinations {
+    fn get(&self, hostname: &str, port: u16) -> Option<SocketAddr> {
+        self.addrs.get(hostname)?.get(&port).copied()
+    }
+
+    fn insert(&mut self, hostname: String, port: u16, addr: SocketAddr) {
+        if self.len >= MAX_RESOLVED_DESTINATIONS {
+            self.addrs.clear();
+            self.len = 0;
+        }
+
+        if self
+            .addrs
+            .entry(hostname)
+            .or_default()
+            .insert(port, addr)
+            .is_none()
+        {
+            self.len += 1;
+        }
+    }
+}
+
+/// Packets are timestamped on the clock of tokio, which the pacer waits on
+pub type SendPacket = (Bytes, Address, time::Instant);
+pub type SendPacketSender = Sender<SendPacket>;
+pub type SendPacketReceiver = Receiver<SendPacket>;
+pub type RecvPacket = (u32, Bytes, Address);
+pub type RecvPacketSender = Sender<RecvPacket>;
+pub type RecvPacketReceiver = Receiver<RecvPacket>;
 
 pub struct UdpSessionMap {
     map: Mutex<HashMap<u32, UdpSession>>,
+    dissociated: Option<Mutex<RecentIds>>,
+    association_rate_limiter: Option<RateLimiter>,
+    config: Arc<ConnectionConfig>,
     recv_pkt_tx_for_clone: RecvPacketSender,
 }
 
 impl UdpSessionMap {
-    pub fn new() -> (Self, RecvPacketReceiver) {
+    pub fn new(config: Arc<ConnectionConfig>) -> (Self, RecvPacketReceiver) {
         let (recv_pkt_tx, recv_pkt_rx) = mpsc::channel(1);
 
         (
             Self {
                 map: Mutex::new(HashMap::new()),
+                dissociated: config
+                    .reject_dissociated_packets
+                    .then(|| Mutex::new(RecentIds::new(MAX_DISSOCIATED_ASSOCIATIONS))),
+                association_rate_limiter: config
+                    .max_udp_associations_per_second
+                    .map(RateLimiter::new),
+                config,
                 recv_pkt_tx_for_clone: recv_pkt_tx,
             },
             recv_pkt_rx,
         )
     }
 
-    #[allow(clippy::await_holding_lock)]
-    pub async fn send(
-        &self,
-        assoc_id: u32,
-        pkt: Bytes,
-        addr: Address,
-        src_addr: SocketAddr,
-        max_udp_pkt_size: usize,
-    ) -> Result<(), IoError> {
-        let map = self.map.lock();
+    pub async fn send(&self, assoc_id: u32, pkt: Bytes, addr: Address, src_addr: SocketAddr) {
+        let send_pkt_tx = match self.session_sender(assoc_id, src_addr) {
+            Some(send_pkt_tx) => send_pkt_tx,
+            None => return,
+        };
 
-        let send_pkt_tx = if let Some(session) = map.get(&assoc_id) {
-            let send_pkt_tx = session.0.clone();
-            drop(map);
-            send_pkt_tx
-        } else {
-            log::info!("[{src_addr}] [associate] [{assoc_id}]");
-            drop(map);
+        let _ = send_pkt_tx.send((pkt, addr, time::Instant::now())).await;
+    }
 
-            let assoc = UdpSession::new(
-                assoc_id,
-                self.recv_pkt_tx_for_clone.clone(),
-                src_addr,
-                max_udp_pkt_size,
-            )
-            .await?;
+    /// Creates the association on its first packet. The limit is checked under the same lock as the insertion, so concurrent first packets can not overshoot it
+    fn session_sender(&self, assoc_id: u32, src_addr: SocketAddr) -> Option<SendPacketSender> {
+        let mut map = self.map.lock();
+        let count = map.len();
 
-            let send_pkt_tx = assoc.0.clone();
+        let entry = match map.entry(assoc_id) {
+            Entry::Occupied(entry) => return Some(entry.get().send_pkt_tx.clone()),
+            Entry::Vacant(entry) => entry,
+        };
 
-            let mut map = self.map.lock();
-            map.insert(assoc_id, assoc);
+        if self
+            .config
+            .max_udp_associations
+            .is_some_and(|max| count >= max)
+        {
+            log::debug!(
+                "[{src_addr}] [associate] [{assoc_id}] too many associations, packet dropped"
+            );
+            self.config.metrics.on_rejection(RejectionReason::OverLimit);
+            return None;
+        }
 
-            send_pkt_tx
-        };
+        if let Some(dissociated) = &self.dissociated {
+            if dissociated.lock().contains(assoc_id) {
+                log::debug!(
+                    "[{src_addr}] [associate] [{assoc_id}] packet after dissociate dropped"
+                );
+                return None;
+            }
+        }
 
-        let _ = send_pkt_tx.send((pkt, addr)).await;
+        if let Some(limiter) = &self.association_rate_limiter {
+            if !limiter.try_acquire(1) {
+                log::debug!(
+                    "[{src_addr}] [associate] [{assoc_id}] associations created too fast, packet dropped"
+                );
+                self.config.metrics.on_rejection(RejectionReason::OverLimit);
+                return None;
+            }
+        }
 
-        Ok(())
+        log::info!("[{src_addr}] [associate] [{assoc_id}]");
+
+        let assoc = UdpSession::new(
+            assoc_id,
+            self.recv_pkt_tx_for_clone.clone(),
+            src_addr,
+            self.config.clone(),
+        );
+
+        Some(entry.insert(assoc).send_pkt_tx.clone())
     }
 
-    pub fn dissociate(&self, assoc_id: u32, src_addr: SocketAddr) {
-        log::info!("[{src_addr}] [dissociate] [{assoc_id}]");
-        self.map.lock().remove(&assoc_id);
+    pub async fn dissociate(&self, assoc_id: u32, src_addr: SocketAddr) {
+        let session = self.map.lock().remove(&assoc_id);
+
+        if let Some(dissociated) = &self.dissociated {
+            dissociated.lock().insert(assoc_id);
+        }
+
+        if let Some(UdpSession {
+            send_pkt_tx, task, ..
+        }) = session
+        {
+            log::info!("[{src_addr}] [dissociate] [{assoc_id}]");
+            drop(send_pkt_tx);
+            let _ = task.await;
+        } else {
+            log::debug!("[{src_addr}] [dissociate] [{assoc_id}] [unknown]");
+        }
+    }
+
+    pub fn association_count(&self) -> usize {
+        self.map.lock().len()
     }
+
+    pub fn snapshot(&self) -> Vec<AssociationSnapshot> {
+        self.map
+            .lock()
+            .iter()
+            .map(|(assoc_id, session)| AssociationSnapshot {
+                assoc_id: *assoc_id,
+                last_destination: session.state.last_destination.load(),
+                idle_ms: session.state.last_activity.load().elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+
+    pub fn reap(&self, idle_threshold: Duration, src_addr: SocketAddr) -> usize {
+        let mut map = self.map.lock();
+        let len = map.len();
+
+        map.retain(|assoc_id, session| {
+            if session.state.last_activity.load().elapsed() < idle_threshold {
+                true
+            } else {
+                log::info!("[{src_addr}] [dissociate] [{assoc_id}] [idle]");
+                false
+            }
+        });
+
+        len - map.len()
+    }
+}
+
+struct UdpSession {
+    send_pkt_tx: SendPacketSender,
+    state: Arc<SessionState>,
+    task: JoinHandle<()>,
 }
 
-struct UdpSession(SendPacketSender);
+/// What the server looks into about an association while its task runs
+struct SessionState {
+    last_activity: AtomicCell<Instant>,
+    last_destination: AtomicCell<Option<SocketAddr>>,
+}
 
 impl UdpSession {
-    async fn new(
+    fn new(
         assoc_id: u32,
         recv_pkt_tx: RecvPacketSender,
         src_addr: SocketAddr,
-        max_udp_pkt_size: usize,
-    ) -> Result<Self, IoError> {
-        let socket = Arc::new(UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?);
-        let (send_pkt_tx, send_pkt_rx) = mpsc::channel(1);
+        config: Arc<ConnectionConfig>,
+    ) -> Self {
+        let pacer = config
+            .udp_pacing_rate
+            .map(|rate| UdpPacer::new(rate, config.udp_pacing_latency));
+        let max_udp_pkt_size = config.max_udp_packet_size;
+        let (socket_tx, socket_rx) = oneshot::channel();
+        let (send_pkt_tx, send_pkt_rx) =
+            mpsc::channel(pacer.as_ref().map_or(1, UdpPacer::queue_capacity));
+        let state = Arc::new(SessionState {
+            last_activity: AtomicCell::new(Instant::now()),
+            last_destination: AtomicCell::new(None),
+        });
+        let state_cloned = state.clone();
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             match tokio::select!(
-                res = Self::listen_send_packet(socket.clone(), send_pkt_rx) => res,
-                res = Self::listen_receive_packet(socket, assoc_id, recv_pkt_tx, max_udp_pkt_size) => res,
+                res = Self::listen_send_packet(socket_tx, assoc_id, src_addr, send_pkt_rx, pacer, config, &state_cloned) => res,
+                res = Self::listen_receive_packet(socket_rx, assoc_id, src_addr, recv_pkt_tx, max_udp_pkt_size, &state_cloned) => res,
             ) {
                 Ok(()) => (),
                 Err(err) => log::warn!("[{src_addr}] [udp-session] [{assoc_id}] {err}"),
             }
         });
 
-        Ok(Self(send_pkt_tx))
+        Self {
+            send_pkt_tx,
+            state,
+            task,
+        }
     }
 
     async fn listen_send_packet(
-        socket: Arc<UdpSocket>,
+        socket_tx: oneshot::Sender<Arc<UdpSocket>>,
+        assoc_id: u32,
+        src_addr: SocketAddr,
         mut send_pkt_rx: SendPacketReceiver,
+        mut pacer: Option<UdpPacer>,
+        config: Arc<ConnectionConfig>,
+        state: &SessionState,
     ) -> Result<(), IoError> {
-        while let Some((pkt, addr)) = send_pkt_rx.recv().await {
-            match addr {
-                Address::DomainAddress(hostname, port) => {
-                    socket.send_to(&pkt, (hostname, port)).await?;
+        let addr_pref = config.udp_address_preference;
+        let mut socket_tx = Some(socket_tx);
+        let mut socket: Option<Arc<UdpSocket>> = None;
+        let mut bound_ipv6 = None;
+        let mut resolved = ResolvedDestinations::default();
+
+        // the socket stays connected to the destination of the first packet, so ICMP errors are reported, until a packet to another destination arrives
+        let mut connected_addr = None;
+        let mut is_connectable = config.connect_udp_sockets && cfg!(target_os = "linux");
+
+        while let Some((pkt, addr, queued_at)) = send_pkt_rx.recv().await {
+            state.last_activity.store(Instant::now());
+
+            if let Some(pacer) = &mut pacer {
+                if !pacer.wait(queued_at).await {
+                    log::debug!("[{src_addr}] [udp-session] [{assoc_id}] [{addr}] over the pacing latency budget, packet dropped");
+                    continue;
+                }
+            }
+
+            let selected = match addr {
+                Address::DomainAddress(hostname, port) => match resolved.get(&hostname, port) {
+                    Some(addr) => Some(addr),
+                    None => {
+                        // a destination that does not resolve only loses its own packets
+                        let addrs = match net::lookup_host((hostname.as_str(), port)).await {
+                            Ok(addrs) => addrs.collect::<Vec<_>>(),
+                            Err(err) => {
+                                log::debug!("[{src_addr}] [udp-session] [{assoc_id}] [{hostname}:{port}] {err}, packet dropped");
+                                continue;
+                            }
+                        };
+
+                        let addr = addr_pref.select(&addrs, bound_ipv6);
+
+                        if let Some(addr) = addr {
+                            resolved.insert(hostname, port, addr);
+                        }
+
+                        addr
+                    }
+                },
+                Address::SocketAddress(addr) => addr_pref.select(&[addr], bound_ipv6),
+            };
+
+            let addr = match selected {
+                Some(addr) => addr,
+                None => {
+                    log::debug!("[{src_addr}] [udp-session] [{assoc_id}] no destination address of the association's address family, packet dropped");
+                    continue;
+                }
+            };
+
+            if config
+                .listen_address
+                .as_ref()
+                .is_some_and(|listen_addr| listen_addr.matches(addr))
+            {
+                log::debug!("[{src_addr}] [udp-session] [{assoc_id}] [{addr}] the listening address of the server, packet dropped");
+                continue;
+            }
+
+            state.last_destination.store(Some(addr));
+
+            let socket = match &socket {
+                Some(socket) => socket.clone(),
+                None => {
+                    let bind_addr = if addr.is_ipv6() {
+                        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+                    } else {
+                        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+                    };
+
+                    let bound = Arc::new(UdpSocket::bind(bind_addr).await?);
+                    bound_ipv6 = Some(addr.is_ipv6());
+
+                    if let Some(socket_tx) = socket_tx.take() {
+                        let _ = socket_tx.send(bound.clone());
+                    }
+
+                    socket.insert(bound).clone()
+                }
+            };
+
+            if is_connectable {
+                match connected_addr {
+                    None => {
+                        socket.connect(addr).await?;
+                        connected_addr = Some(addr);
+                    }
+                    Some(connected) if connected == addr => {}
+                    Some(_) => {
+                        log::debug!("[{src_addr}] [udp-session] [{assoc_id}] multiple destinations, socket disconnected");
+                        disconnect(&socket)?;
+                        connected_addr = None;
+                        is_connectable = false;
+                    }
                 }
-                Address::SocketAddress(addr) => {
-                    socket.send_to(&pkt, addr).await?;
+            }
+
+            let res = match connected_addr {
+                Some(_) => socket.send(&pkt).await,
+                None => socket.send_to(&pkt, addr).await,
+            };
+
+            match res {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                    log::debug!(
+                        "[{src_addr}] [udp-session] [{assoc_id}] [{addr}] destination unreachable"
+                    );
                 }
+                Err(err) => return Err(err),
             }
         }
 
@@ -152,16 +472,41 @@ impl UdpSession {
     }
 
     async fn listen_receive_packet(
-        socket: Arc<UdpSocket>,
+        socket_rx: oneshot::Receiver<Arc<UdpSocket>>,
         assoc_id: u32,
+        src_addr: SocketAddr,
         recv_pkt_tx: RecvPacketSender,
         max_udp_pkt_size: usize,
+        state: &SessionState,
     ) -> Result<(), IoError> {
+        // nothing can be received before the first packet is sent
+        let socket = match socket_rx.await {
+            Ok(socket) => socket,
+            Err(_) => return Ok(()),
+        };
+
         loop {
-            let mut buf = vec![0; max_udp_pkt_size];
-            let (len, addr) = socket.recv_from(&mut buf).await?;
+            // one extra byte tells oversized packets apart from the ones that fit exactly
+            let mut buf = vec![0; max_udp_pkt_size + 1];
+
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                    log::debug!("[{src_addr}] [udp-session] [{assoc_id}] destination unreachable");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            if len > max_udp_pkt_size {
+                log::warn!("[{src_addr}] [udp-session] [{assoc_id}] packet from {addr} exceeds the maximum UDP packet size of {max_udp_pkt_size} bytes, dropped");
+                continue;
+            }
+
             buf.truncate(len);
 
+            state.last_activity.store(Instant::now());
+
             let pkt = Bytes::from(buf);
             let _ = recv_pkt_tx
                 .send((assoc_id, pkt, Address::SocketAddress(addr)))
@@ -169,3 +514,378 @@ impl UdpSession {
         }
     }
 }
+
+#[cfg(target_os = "linux")]
+fn disconnect(socket: &UdpSocket) -> Result<(), IoError> {
+    use socket2::{SockAddr, SockRef};
+    use std::{mem, os::unix::io::AsRawFd};
+
+    let port = socket.local_addr()?.port();
+
+    // connecting to an `AF_UNSPEC` address dissolves the association
+    let mut addr: libc::sockaddr = unsafe { mem::zeroed() };
+    addr.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+
+    let res = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr,
+            mem::size_of_val(&addr) as libc::socklen_t,
+        )
+    };
+
+    if res != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    // the system releases a port it picked for the socket once it is disconnected, so the same port is bound again to keep the source address of the association
+    let bind_addr = match socket.local_addr()? {
+        SocketAddr::V4(addr) if addr.port() == 0 => SocketAddr::from((*addr.ip(), port)),
+        SocketAddr::V6(addr) if addr.port() == 0 => SocketAddr::from((*addr.ip(), port)),
+        _ => return Ok(()),
+    };
+
+    SockRef::from(socket).bind(&SockAddr::from(bind_addr))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disconnect(_socket: &UdpSocket) -> Result<(), IoError> {
+    Ok(())
+}
+
+/// Takes the packets already waiting in `recv_pkt_rx` after `first` as long as they fit in a single datagram of `max_size` bytes, returning them with the first packet that does not fit
+pub async fn coalesce_udp_packets(
+    first: RecvPacket,
+    recv_pkt_rx: &mut RecvPacketReceiver,
+    max_size: usize,
+) -> (Vec<RecvPacket>, Option<RecvPacket>) {
+    fn frame_len((assoc_id, pkt, addr): &RecvPacket) -> usize {
+        Command::new_packet(*assoc_id, pkt.len() as u16, addr.clone()).serialized_len() + pkt.len()
+    }
+
+    let mut size = frame_len(&first);
+    let mut pkts = vec![first];
+    let mut is_yielded = false;
+
+    loop {
+        match recv_pkt_rx.try_recv() {
+            Ok(pkt) => {
+                let len = frame_len(&pkt);
+
+                if size + len > max_size {
+                    return (pkts, Some(pkt));
+                }
+
+                size += len;
+                pkts.push(pkt);
+            }
+            Err(TryRecvError::Empty) if !is_yielded => {
+                is_yielded = true;
+                task::yield_now().await;
+            }
+            Err(_) => return (pkts, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        coalesce_udp_packets, AddressPreference, ConnectionConfig, RecvPacket, RecvPacketReceiver,
+        RefusedAssociations, ResolvedDestinations, UdpSessionMap, MAX_DISSOCIATED_ASSOCIATIONS,
+        MAX_REFUSED_ASSOCIATIONS, MAX_RESOLVED_DESTINATIONS, REFUSAL_REPLIES_PER_SECOND,
+    };
+    use bytes::Bytes;
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::{net::UdpSocket, sync::mpsc, time};
+    use tuic_protocol::{Address, Command};
+
+    const SRC_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+    async fn send(sessions: &UdpSessionMap, assoc_id: u32) {
+        // the discard port, so relayed packets are not answered
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 9)));
+        sessions
+            .send(assoc_id, Bytes::from_static(b"packet"), addr, SRC_ADDR)
+            .await;
+    }
+
+    /// Sends a packet through the association to `dst`, and answers it from there, returning the address of the association's socket
+    async fn round_trip(
+        sessions: &UdpSessionMap,
+        recv_pkt_rx: &mut RecvPacketReceiver,
+        dst: &UdpSocket,
+    ) -> SocketAddr {
+        let dst_addr = dst.local_addr().unwrap();
+        let pkt = Bytes::from_static(b"request");
+        sessions
+            .send(0, pkt, Address::SocketAddress(dst_addr), SRC_ADDR)
+            .await;
+
+        let mut buf = [0; 16];
+        let (_, assoc_addr) = dst.recv_from(&mut buf).await.unwrap();
+        dst.send_to(b"reply", assoc_addr).await.unwrap();
+
+        let (_, pkt, addr) = recv_pkt_rx.recv().await.unwrap();
+        assert_eq!(&pkt[..], b"reply");
+        assert!(addr == Address::SocketAddress(dst_addr));
+
+        assoc_addr
+    }
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn unconnected_sockets_receive_from_any_source() {
+        let (sessions, mut recv_pkt_rx) = UdpSessionMap::new(Arc::new(ConnectionConfig::default()));
+        let (first, other) = (bind().await, bind().await);
+
+        let assoc_addr = round_trip(&sessions, &mut recv_pkt_rx, &first).await;
+
+        // full-cone traversal: the reply comes from an address no packet was sent to
+        other.send_to(b"reply", assoc_addr).await.unwrap();
+        let (_, pkt, addr) = recv_pkt_rx.recv().await.unwrap();
+        assert_eq!(&pkt[..], b"reply");
+        assert!(addr == Address::SocketAddress(other.local_addr().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn connected_sockets_disconnect_on_a_second_destination() {
+        let config = ConnectionConfig {
+            connect_udp_sockets: true,
+            ..ConnectionConfig::default()
+        };
+        let (sessions, mut recv_pkt_rx) = UdpSessionMap::new(Arc::new(config));
+        let (first, second) = (bind().await, bind().await);
+
+        let assoc_addr = round_trip(&sessions, &mut recv_pkt_rx, &first).await;
+        assert_eq!(
+            round_trip(&sessions, &mut recv_pkt_rx, &first).await,
+            assoc_addr
+        );
+
+        // while connected to the first destination, the system drops packets from any other
+        second.send_to(b"stray", assoc_addr).await.unwrap();
+        let stray = time::timeout(Duration::from_millis(100), recv_pkt_rx.recv()).await;
+        assert!(stray.is_err());
+
+        assert_eq!(
+            round_trip(&sessions, &mut recv_pkt_rx, &second).await,
+            assoc_addr
+        );
+        assert_eq!(
+            round_trip(&sessions, &mut recv_pkt_rx, &first).await,
+            assoc_addr
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_first_packets_respect_the_association_limit() {
+        let config = ConnectionConfig {
+            max_udp_associations: Some(4),
+            ..ConnectionConfig::default()
+        };
+        let (sessions, _recv_pkt_rx) = UdpSessionMap::new(Arc::new(config));
+        let sessions = Arc::new(sessions);
+
+        let tasks = (0..64)
+            .map(|assoc_id| {
+                let sessions = sessions.clone();
+                tokio::spawn(async move { send(&sessions, assoc_id).await })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(sessions.association_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn dissociated_ids_are_remembered_up_to_the_cap() {
+        let config = ConnectionConfig {
+            reject_dissociated_packets: true,
+            ..ConnectionConfig::default()
+        };
+        let (sessions, _recv_pkt_rx) = UdpSessionMap::new(Arc::new(config));
+
+        send(&sessions, 0).await;
+        assert_eq!(sessions.association_count(), 1);
+
+        sessions.dissociate(0, SRC_ADDR).await;
+        send(&sessions, 0).await;
+        assert_eq!(sessions.association_count(), 0);
+
+        for assoc_id in 1..=MAX_DISSOCIATED_ASSOCIATIONS as u32 {
+            sessions.dissociate(assoc_id, SRC_ADDR).await;
+        }
+
+        send(&sessions, 0).await;
+        assert_eq!(sessions.association_count(), 1);
+    }
+
+    #[test]
+    fn address_preference_selects_the_family() {
+        let v4 = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 53));
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 53));
+        let both = [v4, v6];
+
+        assert_eq!(AddressPreference::Ipv4Only.select(&both, None), Some(v4));
+        assert_eq!(AddressPreference::Ipv6Only.select(&both, None), Some(v6));
+        assert_eq!(AddressPreference::PreferIpv4.select(&both, None), Some(v4));
+        assert_eq!(AddressPreference::PreferIpv6.select(&both, None), Some(v6));
+
+        assert_eq!(AddressPreference::Ipv4Only.select(&[v6], None), None);
+        assert_eq!(AddressPreference::PreferIpv4.select(&[v6], None), Some(v6));
+        assert_eq!(AddressPreference::PreferIpv6.select(&[v4], None), Some(v4));
+
+        // once the socket is bound, the family of the socket wins over the preference
+        assert_eq!(
+            AddressPreference::PreferIpv6.select(&both, Some(false)),
+            Some(v4)
+        );
+        assert_eq!(
+            AddressPreference::Ipv4Only.select(&both, Some(true)),
+            Some(v6)
+        );
+        assert_eq!(
+            AddressPreference::PreferIpv4.select(&[v6], Some(false)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesced_packets_fit_in_the_datagram() {
+        fn pkt(assoc_id: u32) -> RecvPacket {
+            let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+            (assoc_id, Bytes::from_static(b"0123456789"), addr)
+        }
+
+        let (_, _, addr) = pkt(0);
+        let frame_len = Command::new_packet(0, 10, addr).serialized_len() + 10;
+
+        let (tx, mut rx) = mpsc::channel(8);
+        for assoc_id in 1..4 {
+            tx.send(pkt(assoc_id)).await.unwrap();
+        }
+
+        let (pkts, rest) = coalesce_udp_packets(pkt(0), &mut rx, frame_len * 2).await;
+        assert_eq!(pkts.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), [0, 1]);
+        assert!(matches!(rest, Some((2, ..))));
+
+        let (pkts, rest) = coalesce_udp_packets(pkt(4), &mut rx, frame_len * 3).await;
+        assert_eq!(pkts.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), [4, 3]);
+        assert!(rest.is_none());
+
+        // a packet larger than the datagram is still passed on alone
+        let (pkts, rest) = coalesce_udp_packets(pkt(5), &mut rx, 1).await;
+        assert_eq!(pkts.len(), 1);
+        assert!(rest.is_none());
+    }
+
+    #[tokio::test]
+    async fn unresolvable_destinations_only_drop_their_packets() {
+        let (sessions, _recv_pkt_rx) = UdpSessionMap::new(Arc::new(ConnectionConfig::default()));
+        let dst = bind().await;
+
+        // a nul byte fails the lookup right away
+        let unresolvable = Address::DomainAddress("bad\0host".to_owned(), 80);
+        sessions
+            .send(0, Bytes::from_static(b"lost"), unresolvable, SRC_ADDR)
+            .await;
+
+        let dst_addr = Address::SocketAddress(dst.local_addr().unwrap());
+        sessions
+            .send(0, Bytes::from_static(b"relayed"), dst_addr, SRC_ADDR)
+            .await;
+
+        let mut buf = [0; 16];
+        let (len, _) = time::timeout(Duration::from_secs(5), dst.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&buf[..len], b"relayed");
+        assert_eq!(sessions.association_count(), 1);
+    }
+
+    #[test]
+    fn resolved_destinations_are_cached_per_port_up_to_the_cap() {
+        let mut resolved = ResolvedDestinations::default();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+
+        resolved.insert("example.com".to_owned(), 80, addr);
+        assert_eq!(resolved.get("example.com", 80), Some(addr));
+        assert_eq!(resolved.get("example.com", 443), None);
+        assert_eq!(resolved.get("example.org", 80), None);
+
+        for port in 1..MAX_RESOLVED_DESTINATIONS as u16 {
+            resolved.insert("example.org".to_owned(), port, addr);
+        }
+
+        assert_eq!(resolved.get("example.com", 80), Some(addr));
+
+        // one more destination clears the cache first
+        resolved.insert("example.net".to_owned(), 80, addr);
+        assert_eq!(resolved.get("example.com", 80), None);
+        assert_eq!(resolved.get("example.net", 80), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn only_idle_associations_are_reaped() {
+        let (sessions, _recv_pkt_rx) = UdpSessionMap::new(Arc::new(ConnectionConfig::default()));
+        let dst = bind().await;
+        let dst_addr = Address::SocketAddress(dst.local_addr().unwrap());
+
+        for assoc_id in 0..5 {
+            let pkt = Bytes::from_static(b"packet");
+            sessions
+                .send(assoc_id, pkt, dst_addr.clone(), SRC_ADDR)
+                .await;
+            // once the packet arrives, the association has recorded its activity
+            dst.recv_from(&mut [0; 16]).await.unwrap();
+        }
+
+        let stale = Instant::now() - Duration::from_secs(60);
+        for assoc_id in [1, 3] {
+            sessions.map.lock()[&assoc_id]
+                .state
+                .last_activity
+                .store(stale);
+        }
+
+        assert_eq!(sessions.reap(Duration::from_secs(30), SRC_ADDR), 2);
+
+        let mut remaining = sessions.map.lock().keys().copied().collect::<Vec<_>>();
+        remaining.sort_unstable();
+        assert_eq!(remaining, [0, 2, 4]);
+
+        assert_eq!(sessions.reap(Duration::from_secs(30), SRC_ADDR), 0);
+        assert_eq!(sessions.association_count(), 3);
+    }
+
+    #[test]
+    fn refused_associations_are_answered_once_at_a_bounded_rate() {
+        let refused = RefusedAssociations::new();
+
+        assert!(refused.insert(0));
+        assert!(!refused.insert(0));
+
+        for assoc_id in 1..=MAX_REFUSED_ASSOCIATIONS as u32 {
+            assert!(refused.insert(assoc_id));
+        }
+
+        assert!(refused.insert(0));
+
+        assert!((0..REFUSAL_REPLIES_PER_SECOND).all(|_| refused.may_reply()));
+        assert!(!refused.may_reply());
+    }
+}
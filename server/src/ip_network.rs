@@ -0,0 +1,113 @@
+use std::{net::IpAddr, str::FromStr};
+use thiserror::Error;
+
+/// An IP address range in CIDR notation. A bare address matches only itself
+#[derive(Clone, Copy, Debug)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ParseIpNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| ParseIpNetworkError(s.to_owned()))?
+            .to_canonical();
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.parse() {
+                Ok(prefix_len) if prefix_len <= max_prefix_len => prefix_len,
+                _ => return Err(ParseIpNetworkError(s.to_owned())),
+            },
+            None => max_prefix_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid IP network: {0}")]
+pub struct ParseIpNetworkError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::IpNetwork;
+    use std::net::IpAddr;
+
+    fn contains(net: &str, addr: &str) -> bool {
+        let net = net.parse::<IpNetwork>().unwrap();
+        net.contains(addr.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn addresses_in_the_prefix_match() {
+        assert!(contains("192.0.2.0/24", "192.0.2.255"));
+        assert!(!contains("192.0.2.0/24", "192.0.3.0"));
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+    }
+
+    #[test]
+    fn zero_and_full_prefixes() {
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("192.0.2.1/32", "192.0.2.1"));
+        assert!(!contains("192.0.2.1/32", "192.0.2.2"));
+        assert!(contains("192.0.2.1", "192.0.2.1"));
+        assert!(!contains("2001:db8::1", "2001:db8::2"));
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_networks() {
+        assert!(contains("192.0.2.0/24", "::ffff:192.0.2.1"));
+        assert!(contains("::ffff:192.0.2.0/24", "192.0.2.1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "192.0.2.1"));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for net in [
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "192.0.2/24",
+            "example.com/8",
+            "192.0.2.0/-1",
+        ] {
+            assert!(net.parse::<IpNetwork>().is_err(), "{net}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Limits how often identical error events are logged. Within each window, the first `burst` occurrences of an event are logged, and the rest are counted and summarized once the window ends
+pub struct LogThrottle {
+    burst: u64,
+    events: Mutex<HashMap<String, EventCount>>,
+}
+
+struct EventCount {
+    window_start: Instant,
+    count: u64,
+}
+
+impl LogThrottle {
+    pub const WINDOW: Duration = Duration::from_secs(60);
+
+    pub fn new(burst: u64) -> Self {
+        Self {
+            burst,
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an occurrence of `event`, returning whether it should be logged
+    pub fn check(&self, event: &str) -> bool {
+        let mut events = self.events.lock();
+        let now = Instant::now();
+
+        match events.get_mut(event) {
+            Some(count) => {
+                if now.duration_since(count.window_start) >= Self::WINDOW {
+                    self.summarize(event, count);
+                    count.window_start = now;
+                    count.count = 0;
+                }
+
+                count.count += 1;
+                count.count <= self.burst
+            }
+            None => {
+                events.insert(
+                    event.to_owned(),
+                    EventCount {
+                        window_start: now,
+                        count: 1,
+                    },
+                );
+
+                true
+            }
+        }
+    }
+
+    /// Logs the summaries of the windows that have ended and forgets their events
+    pub fn flush(&self) {
+        let now = Instant::now();
+
+        self.events.lock().retain(|event, count| {
+            if now.duration_since(count.window_start) < Self::WINDOW {
+                true
+            } else {
+                self.summarize(event, count);
+                false
+            }
+        });
+    }
+
+    fn summarize(&self, event: &str, count: &EventCount) {
+        if count.count > self.burst {
+            log::warn!(
+                "{} occurrences of \"{event}\" in the last minute, {} of them not logged",
+                count.count,
+                count.count - self.burst,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogThrottle;
+
+    #[test]
+    fn events_beyond_the_burst_are_suppressed() {
+        let throttle = LogThrottle::new(2);
+
+        assert!(throttle.check("connection lost"));
+        assert!(throttle.check("connection lost"));
+        assert!(!throttle.check("connection lost"));
+
+        // other events have their own count
+        assert!(throttle.check("timed out"));
+    }
+
+    #[test]
+    fn flushing_keeps_events_whose_window_has_not_ended() {
+        let throttle = LogThrottle::new(1);
+
+        assert!(throttle.check("connection lost"));
+        throttle.flush();
+        assert!(!throttle.check("connection lost"));
+    }
+}
@@ -1,13 +1,24 @@
 use crate::{
+    buffer::{BufferProvider, HeapBufferProvider, PooledBufferProvider},
     config::{Config, ConfigError},
-    server::Server,
+    metrics::{CounterMetrics, Metrics, NoopMetrics},
+    server::{Server, ShutdownState},
 };
-use std::{env, process};
+use std::{env, process, sync::Arc};
 
+mod buffer;
+mod catch_panic;
 mod certificate;
 mod config;
 mod connection;
+mod ip_network;
+mod log_throttle;
+mod metrics;
 mod server;
+mod snapshot;
+
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() {
@@ -19,7 +30,10 @@ async fn main() {
             match err {
                 ConfigError::Help(help) => println!("{help}"),
                 ConfigError::Version(version) => println!("{version}"),
-                err => eprintln!("{err}"),
+                err => {
+                    eprintln!("{err}");
+                    process::exit(1);
+                }
             }
             return;
         }
@@ -32,21 +46,57 @@ async fn main() {
         .format_module_path(false)
         .init();
 
-    let server = match Server::init(
-        config.server_config,
-        config.port,
-        config.token_digest,
-        config.authentication_timeout,
-        config.max_udp_packet_size,
-        config.enable_ipv6,
-    ) {
+    let check = config.check;
+
+    let counters = config
+        .metrics_log_interval
+        .map(|interval| (Arc::new(CounterMetrics::default()), interval));
+
+    let metrics: Arc<dyn Metrics> = match &counters {
+        Some((counters, _)) => counters.clone(),
+        None => Arc::new(NoopMetrics),
+    };
+
+    let buffer_provider: Arc<dyn BufferProvider> = match config.buffer_pool_size {
+        Some(size) => Arc::new(PooledBufferProvider::new(size)),
+        None => Arc::new(HeapBufferProvider),
+    };
+
+    let server = match Server::init(config, buffer_provider, metrics) {
         Ok(server) => server,
         Err(err) => {
             eprintln!("{err}");
-            return;
+            process::exit(1);
         }
     };
 
-    server.run().await;
-    process::exit(1);
+    if check {
+        println!("Configuration OK");
+        return;
+    }
+
+    if let Some((counters, interval)) = counters {
+        tokio::spawn(counters.log_every(interval));
+    }
+
+    #[cfg(feature = "signals")]
+    let res = server.run_with_signals().await;
+
+    #[cfg(not(feature = "signals"))]
+    let res = Ok::<_, std::io::Error>(
+        server
+            .run(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await,
+    );
+
+    match res {
+        Ok(ShutdownState::Closed) => {}
+        Ok(_) => process::exit(1),
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,233 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time;
+
+/// Hooks for observing server events. All methods do nothing by default
+pub trait Metrics: Send + Sync {
+    fn on_connection_panic(&self, _rmt_addr: SocketAddr) {}
+
+    fn on_rejection(&self, _reason: RejectionReason) {}
+
+    /// Called once per connection, when the client sends the expected token
+    fn on_authenticated(&self, _rmt_addr: SocketAddr) {}
+
+    fn on_connection_closed(&self, _rmt_addr: SocketAddr, _info: &ConnectionInfo) {}
+
+    fn on_path_stats(&self, _rmt_addr: SocketAddr, _delta: &PathStatsDelta) {}
+
+    /// Called when a `Connect` had to wait for `--max-outbound-connects`, with the time it waited
+    fn on_outbound_saturated(&self, _waited: Duration) {}
+
+    /// Called when a graceful shutdown starts, with the number of connections left to drain
+    fn on_drain_started(&self, _remaining: usize) {}
+
+    /// Called periodically while connections are being drained
+    fn on_drain_progress(&self, _remaining: usize, _elapsed: Duration) {}
+
+    /// Called once all connections are closed at the end of a graceful shutdown
+    fn on_drain_finished(&self, _info: &DrainInfo) {}
+}
+
+/// A snapshot of the statistics of a connection
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    /// Moving average of the bytes relayed per second in both directions
+    pub throughput: f64,
+    pub rtt: Duration,
+    pub tcp_streams: usize,
+    pub udp_associations: usize,
+    /// The address the most recent `Connect` was relayed to, after resolving the domain
+    pub last_upstream_addr: Option<SocketAddr>,
+}
+
+/// Changes of the QUIC path statistics of a connection between two samples, taken at the stats interval
+#[derive(Clone, Copy, Debug)]
+pub struct PathStatsDelta {
+    pub interval: Duration,
+    pub sent_datagrams: u64,
+    /// Congestion events, each caused by the loss of packets or an ECN congestion mark
+    pub congestion_events: u64,
+}
+
+/// The outcome of draining the connections during a graceful shutdown
+#[derive(Clone, Copy, Debug)]
+pub struct DrainInfo {
+    /// The time from the start of the shutdown until all connections were closed
+    pub elapsed: Duration,
+    /// The connections still open when the grace period elapsed, which were then closed by the server
+    pub forced_closes: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectionReason {
+    AuthenticationFailure,
+    AuthenticationTimeout,
+    IdleTimeout,
+    OverLimit,
+    Policy,
+    UpstreamFailure,
+    ProtocolError,
+    /// The command at the start of a stream was not received within `--command-read-timeout`
+    CommandTimeout,
+}
+
+impl RejectionReason {
+    pub const ALL: [Self; 8] = [
+        Self::AuthenticationFailure,
+        Self::AuthenticationTimeout,
+        Self::IdleTimeout,
+        Self::OverLimit,
+        Self::Policy,
+        Self::UpstreamFailure,
+        Self::ProtocolError,
+        Self::CommandTimeout,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthenticationFailure => "authentication failure",
+            Self::AuthenticationTimeout => "authentication timeout",
+            Self::IdleTimeout => "idle timeout",
+            Self::OverLimit => "over limit",
+            Self::Policy => "policy",
+            Self::UpstreamFailure => "upstream failure",
+            Self::ProtocolError => "protocol error",
+            Self::CommandTimeout => "command timeout",
+        }
+    }
+}
+
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Counts server events, logged at `--metrics-log-interval`
+#[derive(Default)]
+pub struct CounterMetrics {
+    authenticated: AtomicU64,
+    closed: AtomicU64,
+    panics: AtomicU64,
+    outbound_saturated: AtomicU64,
+    rejections: [AtomicU64; RejectionReason::ALL.len()],
+}
+
+impl CounterMetrics {
+    pub fn authenticated(&self) -> u64 {
+        self.authenticated.load(Ordering::Relaxed)
+    }
+
+    pub fn closed(&self) -> u64 {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn rejections(&self, reason: RejectionReason) -> u64 {
+        self.rejections[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Logs the totals every `interval`, until the task is dropped
+    pub async fn log_every(self: Arc<Self>, interval: Duration) {
+        let mut interval = time::interval(interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            log::info!("[metrics] {self}");
+        }
+    }
+}
+
+impl Metrics for CounterMetrics {
+    fn on_connection_panic(&self, _rmt_addr: SocketAddr) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_rejection(&self, reason: RejectionReason) {
+        self.rejections[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_authenticated(&self, _rmt_addr: SocketAddr) {
+        self.authenticated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_connection_closed(&self, _rmt_addr: SocketAddr, _info: &ConnectionInfo) {
+        self.closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_outbound_saturated(&self, _waited: Duration) {
+        self.outbound_saturated.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for CounterMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "authenticated: {}, closed: {}, panics: {}, saturated outbound connects: {}, rejections:",
+            self.authenticated(),
+            self.closed(),
+            self.panics.load(Ordering::Relaxed),
+            self.outbound_saturated.load(Ordering::Relaxed),
+        )?;
+
+        for reason in RejectionReason::ALL {
+            write!(f, " {} {}", reason.as_str(), self.rejections(reason))?;
+
+            if reason != RejectionReason::CommandTimeout {
+                write!(f, ",")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ConnectionInfo {
+        ConnectionInfo {
+            throughput: 0.0,
+            rtt: Duration::ZERO,
+            tcp_streams: 0,
+            udp_associations: 0,
+            last_upstream_addr: None,
+        }
+    }
+
+    #[test]
+    fn counters_are_kept_per_event_and_reason() {
+        let metrics = CounterMetrics::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 443));
+
+        metrics.on_authenticated(addr);
+        metrics.on_connection_closed(addr, &info());
+        metrics.on_connection_closed(addr, &info());
+        metrics.on_rejection(RejectionReason::CommandTimeout);
+        metrics.on_rejection(RejectionReason::CommandTimeout);
+        metrics.on_rejection(RejectionReason::Policy);
+
+        assert_eq!(metrics.authenticated(), 1);
+        assert_eq!(metrics.closed(), 2);
+        assert_eq!(metrics.rejections(RejectionReason::CommandTimeout), 2);
+        assert_eq!(metrics.rejections(RejectionReason::Policy), 1);
+        assert_eq!(metrics.rejections(RejectionReason::IdleTimeout), 0);
+    }
+
+    #[test]
+    fn every_reason_has_its_own_counter() {
+        for (i, reason) in RejectionReason::ALL.into_iter().enumerate() {
+            assert_eq!(reason as usize, i);
+        }
+
+        let line = CounterMetrics::default().to_string();
+        assert!(line.ends_with("protocol error 0, command timeout 0"));
+    }
+}
@@ -1,30 +1,63 @@
-use crate::connection::Connection;
+use crate::{
+    buffer::BufferProvider,
+    catch_panic::spawn_catching_panic,
+    config::Config,
+    connection::{
+        Connection, ConnectionConfig, ConnectionRegistry, DestinationLimiter, ListenAddress,
+        RegisteredConnection,
+    },
+    ip_network::IpNetwork,
+    log_throttle::LogThrottle,
+    metrics::{DrainInfo, Metrics, RejectionReason},
+    snapshot::ServerStateSnapshot,
+};
 use futures_util::StreamExt;
-use quinn::{Endpoint, EndpointConfig, Incoming, ServerConfig};
+use parking_lot::Mutex;
+use quinn::{Endpoint, Incoming, ServerConfig, VarInt};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    future::{self, Future},
     io::Error as IoError,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Semaphore,
+    },
+    time,
 };
 
 pub struct Server {
+    endpoint: Endpoint,
     incoming: Incoming,
+    server_config: ServerConfig,
+    shutdown_state: ShutdownState,
+    shutdown_grace_period: Duration,
+    connection_tracker: Option<Sender<()>>,
+    connection_tracker_rx: Receiver<()>,
     port: u16,
-    expected_token_digest: [u8; 32],
-    authentication_timeout: Duration,
-    max_udp_packet_size: usize,
+    udp_association_reap_threshold: Duration,
+    connection_registry: Arc<ConnectionRegistry>,
+    connection_config: Arc<ConnectionConfig>,
+    reap_signal: ReapSignal,
+    dump_signal: DumpSignal,
+    trace_peers: Vec<IpAddr>,
+    allowed_clients: Vec<IpNetwork>,
 }
 
 impl Server {
     pub fn init(
-        config: ServerConfig,
-        port: u16,
-        exp_tkn_dgst: [u8; 32],
-        auth_timeout: Duration,
-        max_udp_pkt_size: usize,
-        enable_ipv6: bool,
+        config: Config,
+        buffer_provider: Arc<dyn BufferProvider>,
+        metrics: Arc<dyn Metrics>,
     ) -> Result<Self, IoError> {
+        let enable_ipv6 = config.enable_ipv6;
+        let port = config.port;
+
         let (addr, domain) = if enable_ipv6 {
             (
                 SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
@@ -43,30 +76,581 @@ impl Server {
             socket.set_only_v6(false)?;
         }
 
-        socket.bind(&SockAddr::from(addr))?;
+        if let Some(size) = config.udp_receive_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+            let actual = socket.recv_buffer_size()?;
+            log::info!("UDP socket receive buffer size: {actual} bytes (requested: {size})");
+        }
+
+        if let Some(size) = config.udp_send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+            let actual = socket.send_buffer_size()?;
+            log::info!("UDP socket send buffer size: {actual} bytes (requested: {size})");
+        }
+
+        socket
+            .bind(&SockAddr::from(addr))
+            .map_err(|err| IoError::new(err.kind(), format!("Failed to bind {addr}: {err}")))?;
         let socket = UdpSocket::from(socket);
 
-        let (_, incoming) = Endpoint::new(EndpointConfig::default(), Some(config), socket)?;
+        let (endpoint, incoming) = Endpoint::new(
+            config.endpoint_config,
+            Some(config.server_config.clone()),
+            socket,
+        )?;
+        let (conn_tracker, conn_tracker_rx) = mpsc::channel(1);
+
+        let connection_config = ConnectionConfig {
+            expected_token_digest: config.token_digest,
+            authentication_timeout: config.authentication_timeout,
+            max_udp_packet_size: config.max_udp_packet_size,
+            max_udp_packets_per_second: config.max_udp_packets_per_second,
+            max_tcp_bytes_per_second: config.max_tcp_bytes_per_second,
+            strict_authentication: config.strict_authentication,
+            acknowledge_dissociate: config.acknowledge_dissociate,
+            destination_limiter: config
+                .max_connections_per_destination
+                .map(|max| Arc::new(DestinationLimiter::new(max, config.destination_limit_key))),
+            max_bytes_per_connection: config.max_bytes_per_connection,
+            reject_dissociated_packets: config.reject_dissociated_packets,
+            max_concurrent_connects: config.max_concurrent_connects,
+            max_udp_associations: config.max_udp_associations,
+            max_udp_associations_per_second: config.max_udp_associations_per_second,
+            ipv6_flow_label: config.ipv6_flow_label,
+            disable_udp: config.disable_udp,
+            connect_udp_sockets: config.connect_udp_sockets,
+            listen_address: (!config.allow_self_relay).then(|| ListenAddress::new(addr)),
+            max_migrations: config.max_migrations,
+            max_connection_lifetime: config.max_connection_lifetime,
+            pending_connections: config
+                .max_pending_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            outbound_connects: config.max_outbound_connects.map(Semaphore::new),
+            coalesce_udp_replies: config.coalesce_udp_replies,
+            throughput_time_constant: config.throughput_time_constant,
+            stats_interval: config.stats_interval,
+            first_byte_timeout: config.first_byte_timeout,
+            command_read_timeout: config.command_read_timeout,
+            close_on_truncated_command: config.close_on_truncated_command,
+            max_domain_length: config.max_domain_length,
+            max_resolved_addresses: config.max_resolved_addresses,
+            resolve_retries: config.resolve_retries,
+            resolve_retry_time: config.resolve_retry_time,
+            log_throttle: config.error_log_burst.map(LogThrottle::new),
+            udp_pacing_rate: config.udp_pacing_rate,
+            udp_pacing_latency: config.udp_pacing_latency,
+            udp_address_preference: config.udp_address_preference,
+            tcp_nodelay: !config.disable_tcp_nodelay,
+            reset_on_target_reset: config.reset_on_target_reset,
+            buffer_provider,
+            metrics,
+        };
 
         Ok(Self {
+            endpoint,
             incoming,
+            server_config: config.server_config,
+            shutdown_state: ShutdownState::Running,
+            shutdown_grace_period: config.shutdown_grace_period,
+            connection_tracker: Some(conn_tracker),
+            connection_tracker_rx: conn_tracker_rx,
             port,
-            expected_token_digest: exp_tkn_dgst,
-            authentication_timeout: auth_timeout,
-            max_udp_packet_size: max_udp_pkt_size,
+            udp_association_reap_threshold: config.udp_association_reap_threshold,
+            connection_registry: Arc::new(Mutex::new(Vec::new())),
+            connection_config: Arc::new(connection_config),
+            reap_signal: ReapSignal::new()?,
+            dump_signal: DumpSignal::new()?,
+            trace_peers: config.trace_peers,
+            allowed_clients: config.allowed_clients,
         })
     }
 
-    pub async fn run(mut self) {
+    /// Runs the server until `shutdown` resolves, then performs the ordered shutdown
+    pub async fn run<F>(mut self, shutdown: F) -> ShutdownState
+    where
+        F: Future<Output = ()>,
+    {
         log::info!("Server started. Listening port: {}", self.port);
 
-        while let Some(conn) = self.incoming.next().await {
-            tokio::spawn(Connection::handle(
-                conn,
-                self.expected_token_digest,
-                self.authentication_timeout,
-                self.max_udp_packet_size,
-            ));
+        tokio::pin!(shutdown);
+
+        let mut log_throttle_timer = time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                conn = self.incoming.next() => match conn {
+                    Some(conn) => {
+                        let rmt_addr = conn.remote_address();
+
+                        if !self.is_allowed_client(rmt_addr) {
+                            log::debug!("[{rmt_addr}] not in the client allowlist, connection dropped");
+                            self.connection_config.metrics.on_rejection(RejectionReason::Policy);
+                            continue;
+                        }
+
+                        let tracker = self.connection_tracker.clone();
+                        let metrics = self.connection_config.metrics.clone();
+                        let trace_commands = self
+                            .trace_peers
+                            .iter()
+                            .any(|ip| ip.to_canonical() == rmt_addr.ip().to_canonical());
+
+                        let handle = Connection::handle(
+                            conn,
+                            self.connection_config.clone(),
+                            self.connection_registry.clone(),
+                            trace_commands,
+                        );
+
+                        spawn_catching_panic(
+                            async move {
+                                handle.await;
+                                drop(tracker);
+                            },
+                            move || {
+                                log::error!("[{rmt_addr}] connection handler panicked");
+                                metrics.on_connection_panic(rmt_addr);
+                            },
+                        );
+                    }
+                    None => break,
+                },
+                _ = log_throttle_timer.tick(), if self.connection_config.log_throttle.is_some() => {
+                    if let Some(log_throttle) = &self.connection_config.log_throttle {
+                        log_throttle.flush();
+                    }
+                }
+                () = self.reap_signal.recv() => {
+                    let count = self.reap_associations(self.udp_association_reap_threshold);
+                    log::info!("Reclaimed {count} idle UDP associations");
+                }
+                () = self.dump_signal.recv() => match dump_state(&self.connection_registry) {
+                    Ok(state) => log::info!("State: {state}"),
+                    Err(err) => log::error!("Failed to serialize the server state: {err}"),
+                },
+                () = &mut shutdown => {
+                    self.shutdown().await;
+                    break;
+                }
+            }
+        }
+
+        self.shutdown_state
+    }
+
+    /// Runs the server, triggering the ordered shutdown on SIGTERM / SIGINT (Ctrl-C on non-Unix platforms)
+    #[cfg(feature = "signals")]
+    pub async fn run_with_signals(self) -> Result<ShutdownState, IoError> {
+        let mut signal = ShutdownSignal::new()?;
+        Ok(self.run(async move { signal.recv().await }).await)
+    }
+
+    async fn shutdown(&mut self) {
+        self.set_shutdown_state(ShutdownState::Draining);
+
+        let mut config = self.server_config.clone();
+        config.concurrent_connections(0);
+        self.endpoint.set_server_config(Some(config));
+
+        self.connection_tracker = None;
+
+        let started = Instant::now();
+        let remaining = open_connections(&self.connection_registry);
+        log::info!("[shutdown] draining {remaining} connections");
+        self.connection_config.metrics.on_drain_started(remaining);
+
+        let forced_closes = {
+            let drained = self.connection_tracker_rx.recv();
+            let grace_period = time::sleep(self.shutdown_grace_period);
+            let mut progress_timer = time::interval_at(
+                time::Instant::now() + DRAIN_PROGRESS_INTERVAL,
+                DRAIN_PROGRESS_INTERVAL,
+            );
+
+            tokio::pin!(drained, grace_period);
+
+            loop {
+                tokio::select! {
+                    _ = &mut drained => break 0,
+                    () = &mut grace_period => {
+                        let remaining = open_connections(&self.connection_registry);
+                        log::warn!("[shutdown] grace period elapsed, closing {remaining} remaining connections");
+                        break remaining;
+                    }
+                    _ = progress_timer.tick() => {
+                        let remaining = open_connections(&self.connection_registry);
+                        log::info!("[shutdown] {remaining} connections remaining");
+                        self.connection_config.metrics.on_drain_progress(remaining, started.elapsed());
+                    }
+                }
+            }
+        };
+
+        self.set_shutdown_state(ShutdownState::Closing);
+
+        self.endpoint
+            .close(VarInt::from_u32(0), b"server shutting down");
+        self.endpoint.wait_idle().await;
+
+        let info = DrainInfo {
+            elapsed: started.elapsed(),
+            forced_closes,
+        };
+
+        log::info!(
+            "[shutdown] drained in {:?}, {} connections closed by the server",
+            info.elapsed,
+            info.forced_closes
+        );
+        self.connection_config.metrics.on_drain_finished(&info);
+
+        self.set_shutdown_state(ShutdownState::Closed);
+    }
+
+    fn is_allowed_client(&self, rmt_addr: SocketAddr) -> bool {
+        self.allowed_clients.is_empty()
+            || self
+                .allowed_clients
+                .iter()
+                .any(|net| net.contains(rmt_addr.ip()))
+    }
+
+    fn set_shutdown_state(&mut self, state: ShutdownState) {
+        log::info!("[shutdown] {} -> {state}", self.shutdown_state);
+        self.shutdown_state = state;
+    }
+
+    /// Expires UDP associations across all connections that have been idle for at least `idle_threshold`, returning the number reclaimed
+    pub fn reap_associations(&self, idle_threshold: Duration) -> usize {
+        let mut registry = self.connection_registry.lock();
+        registry.retain(RegisteredConnection::is_alive);
+
+        registry.iter().map(|conn| conn.reap(idle_threshold)).sum()
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ShutdownState {
+    Running,
+    Draining,
+    Closing,
+    Closed,
+}
+
+impl Display for ShutdownState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Running => write!(f, "running"),
+            Self::Draining => write!(f, "draining"),
+            Self::Closing => write!(f, "closing"),
+            Self::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "signals"))]
+struct ShutdownSignal {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(all(unix, feature = "signals"))]
+impl ShutdownSignal {
+    fn new() -> Result<Self, IoError> {
+        use tokio::signal::unix::{self, SignalKind};
+
+        Ok(Self {
+            terminate: unix::signal(SignalKind::terminate())?,
+            interrupt: unix::signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        let res = tokio::select! {
+            res = self.terminate.recv() => res,
+            res = self.interrupt.recv() => res,
+        };
+
+        if res.is_none() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(all(not(unix), feature = "signals"))]
+struct ShutdownSignal;
+
+#[cfg(all(not(unix), feature = "signals"))]
+impl ShutdownSignal {
+    fn new() -> Result<Self, IoError> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        if tokio::signal::ctrl_c().await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+struct ReapSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl ReapSignal {
+    fn new() -> Result<Self, IoError> {
+        use tokio::signal::unix::{self, SignalKind};
+        Ok(Self(unix::signal(SignalKind::user_defined1())?))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct ReapSignal;
+
+#[cfg(not(unix))]
+impl ReapSignal {
+    fn new() -> Result<Self, IoError> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        future::pending::<()>().await;
+    }
+}
+
+#[cfg(unix)]
+struct DumpSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl DumpSignal {
+    fn new() -> Result<Self, IoError> {
+        use tokio::signal::unix::{self, SignalKind};
+        Ok(Self(unix::signal(SignalKind::user_defined2())?))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct DumpSignal;
+
+#[cfg(not(unix))]
+impl DumpSignal {
+    fn new() -> Result<Self, IoError> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        future::pending::<()>().await;
+    }
+}
+
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serializes a snapshot of the open connections and their UDP associations, as logged on SIGUSR2. Each association map is read under its lock, so no association is seen half-created, but connections opening or closing meanwhile may or may not be included
+fn dump_state(registry: &ConnectionRegistry) -> Result<String, serde_json::Error> {
+    let mut registry = registry.lock();
+    registry.retain(RegisteredConnection::is_alive);
+
+    let state = ServerStateSnapshot {
+        connections: registry
+            .iter()
+            .filter_map(RegisteredConnection::snapshot)
+            .collect(),
+    };
+
+    drop(registry);
+    serde_json::to_string(&state)
+}
+
+fn open_connections(registry: &ConnectionRegistry) -> usize {
+    let mut registry = registry.lock();
+    registry.retain(RegisteredConnection::is_alive);
+    registry.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump_state, Server, ShutdownState};
+    use crate::{
+        buffer::HeapBufferProvider,
+        config::Config,
+        connection::ConnectionConfig,
+        metrics::NoopMetrics,
+        testing::{self, TestClient},
+    };
+    use futures_util::StreamExt;
+    use quinn::{ConnectionError, Endpoint, NewConnection, VarInt};
+    use serde_json::{json, Value};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+    use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle, time};
+    use tuic_protocol::testing as protocol_testing;
+
+    /// Runs a server on a free port until the returned sender is used or dropped
+    fn spawn_server(
+        extra_args: &[&str],
+    ) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<ShutdownState>) {
+        let args = [
+            "tuic-server",
+            "--port",
+            "0",
+            "--token",
+            "token",
+            "--certificate",
+            protocol_testing::CERTIFICATE_PATH,
+            "--private-key",
+            protocol_testing::PRIVATE_KEY_PATH,
+        ];
+
+        let config = Config::parse(args.iter().chain(extra_args)).unwrap();
+        let server =
+            Server::init(config, Arc::new(HeapBufferProvider), Arc::new(NoopMetrics)).unwrap();
+        let port = server.endpoint.local_addr().unwrap().port();
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let run = tokio::spawn(server.run(async move {
+            let _ = shutdown_rx.await;
+        }));
+
+        (
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            shutdown_tx,
+            run,
+        )
+    }
+
+    async fn connect(addr: SocketAddr) -> (Endpoint, Result<NewConnection, ConnectionError>) {
+        let mut endpoint = Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        endpoint.set_default_client_config(testing::client_config());
+        let conn = endpoint.connect(addr, "localhost").unwrap().await;
+        (endpoint, conn)
+    }
+
+    #[tokio::test]
+    async fn connections_opened_while_draining_are_refused() {
+        let (addr, shutdown_tx, run) = spawn_server(&["--authentication-timeout", "10000"]);
+        let (_endpoint, conn) = connect(addr).await;
+        let conn = conn.unwrap();
+
+        shutdown_tx.send(()).unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+
+        // still draining the open connection
+        assert!(!run.is_finished());
+
+        let (_endpoint, refused) = connect(addr).await;
+        assert!(refused.is_err());
+
+        conn.connection.close(VarInt::from_u32(0), b"");
+
+        let state = time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the server is still draining")
+            .unwrap();
+
+        assert!(state == ShutdownState::Closed);
+    }
+
+    #[tokio::test]
+    async fn connections_left_after_the_grace_period_are_closed() {
+        let (addr, shutdown_tx, run) = spawn_server(&[
+            "--authentication-timeout",
+            "10000",
+            "--shutdown-grace-period",
+            "200",
+        ]);
+        let (_endpoint, conn) = connect(addr).await;
+        let mut conn = conn.unwrap();
+
+        shutdown_tx.send(()).unwrap();
+
+        let state = time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the server is still draining")
+            .unwrap();
+
+        assert!(state == ShutdownState::Closed);
+
+        let err = match conn.uni_streams.next().await {
+            Some(Err(err)) => err,
+            _ => panic!("the connection is not closed"),
+        };
+
+        assert!(matches!(
+            err,
+            ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(0)
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_server_stops_when_the_shutdown_future_resolves() {
+        let (_, shutdown_tx, run) = spawn_server(&[]);
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!run.is_finished());
+
+        shutdown_tx.send(()).unwrap();
+
+        let state = time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the server is still running")
+            .unwrap();
+
+        assert!(state == ShutdownState::Closed);
+    }
+
+    #[tokio::test]
+    async fn the_state_dump_lists_connections_and_associations() {
+        let client = TestClient::connect(ConnectionConfig::default()).await;
+        client.authenticate([0; 32]).await;
+
+        let dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let dst_addr = dst.local_addr().unwrap();
+        let mut buf = [0; 16];
+
+        for assoc_id in [3, 7] {
+            client.send_packet(assoc_id, dst_addr, b"ping").await;
+            time::timeout(Duration::from_secs(5), dst.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let state: Value = serde_json::from_str(&dump_state(&client.registry).unwrap()).unwrap();
+        let conns = state["connections"].as_array().unwrap();
+
+        assert_eq!(conns.len(), 1);
+        assert_eq!(conns[0]["remote_addr"], json!(client.local_addr()));
+        assert_eq!(conns[0]["tcp_streams"], json!(0));
+
+        let mut assocs = conns[0]["udp_associations"].as_array().unwrap().clone();
+        assocs.sort_by_key(|assoc| assoc["assoc_id"].as_u64());
+
+        // the idle time depends on how long the test took
+        for assoc in &mut assocs {
+            let idle_ms = assoc.as_object_mut().unwrap().remove("idle_ms").unwrap();
+            assert!(idle_ms.as_u64().unwrap() < 5000);
         }
+
+        assert_eq!(
+            assocs,
+            [
+                json!({ "assoc_id": 3, "last_destination": dst_addr }),
+                json!({ "assoc_id": 7, "last_destination": dst_addr }),
+            ]
+        );
     }
 }
@@ -0,0 +1,25 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// The connections of the server and their relay tasks at one point in time
+#[derive(Debug, Serialize)]
+pub struct ServerStateSnapshot {
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionSnapshot {
+    pub remote_addr: SocketAddr,
+    pub tcp_streams: usize,
+    /// Moving average of the bytes relayed per second in both directions
+    pub throughput: f64,
+    pub udp_associations: Vec<AssociationSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AssociationSnapshot {
+    pub assoc_id: u32,
+    /// The destination of the most recent packet sent by the association, if any
+    pub last_destination: Option<SocketAddr>,
+    pub idle_ms: u64,
+}
@@ -0,0 +1,153 @@
+//! Connects a test client to `Connection::handle` over QUIC on the loopback interface
+
+use crate::{
+    certificate,
+    connection::{Connection, ConnectionConfig, ConnectionRegistry},
+};
+use futures_util::StreamExt;
+use quinn::{
+    ClientConfig, ConnectionError, Endpoint, NewConnection, RecvStream, SendStream, ServerConfig,
+    VarInt,
+};
+use rustls::RootCertStore;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time};
+use tuic_protocol::{testing, Address, Command};
+
+pub fn server_config() -> ServerConfig {
+    let certs = certificate::load_certificates(testing::CERTIFICATE_PATH).unwrap();
+    let priv_key = certificate::load_private_key(testing::PRIVATE_KEY_PATH).unwrap();
+    ServerConfig::with_single_cert(certs, priv_key).unwrap()
+}
+
+pub fn client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+
+    for cert in certificate::load_certificates(testing::CA_CERTIFICATE_PATH).unwrap() {
+        roots.add(&cert).unwrap();
+    }
+
+    ClientConfig::with_root_certificates(roots)
+}
+
+/// A client connected to a connection handler running in the background
+pub struct TestClient {
+    pub conn: NewConnection,
+    pub handler: JoinHandle<()>,
+    pub registry: Arc<ConnectionRegistry>,
+    endpoints: (Endpoint, Endpoint),
+}
+
+impl TestClient {
+    pub async fn connect(config: ConnectionConfig) -> Self {
+        Self::connect_with(config, server_config()).await
+    }
+
+    pub async fn connect_with(config: ConnectionConfig, server_config: ServerConfig) -> Self {
+        let (server, mut incoming) =
+            Endpoint::server(server_config, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let registry = Arc::new(ConnectionRegistry::default());
+        let handler_registry = registry.clone();
+        let config = Arc::new(config);
+
+        let handler = tokio::spawn(async move {
+            let conn = incoming.next().await.unwrap();
+            Connection::handle(conn, config, handler_registry, false).await;
+        });
+
+        let mut client = Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        client.set_default_client_config(client_config());
+        let conn = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        Self {
+            conn,
+            handler,
+            registry,
+            endpoints: (server, client),
+        }
+    }
+
+    /// The address the server sees the client connecting from
+    pub fn local_addr(&self) -> SocketAddr {
+        self.endpoints.1.local_addr().unwrap()
+    }
+
+    /// Sends a command on a new unidirectional stream
+    pub async fn send_uni(&self, cmd: Command) {
+        let mut send = self.conn.connection.open_uni().await.unwrap();
+        cmd.write_to(&mut send).await.unwrap();
+        send.finish().await.unwrap();
+    }
+
+    pub async fn authenticate(&self, digest: [u8; 32]) {
+        self.send_uni(Command::new_authenticate(digest)).await;
+    }
+
+    /// Relays `pkt` to `addr` in the association `assoc_id`, on a new unidirectional stream
+    pub async fn send_packet(&self, assoc_id: u32, addr: SocketAddr, pkt: &[u8]) {
+        let mut send = self.conn.connection.open_uni().await.unwrap();
+
+        Command::new_packet(assoc_id, pkt.len() as u16, Address::SocketAddress(addr))
+            .write_to(&mut send)
+            .await
+            .unwrap();
+
+        send.write_all(pkt).await.unwrap();
+        send.finish().await.unwrap();
+    }
+
+    /// Sends a `Connect` to `addr` on a new bidirectional stream, returning whether the server accepted it
+    pub async fn connect_to(&self, addr: SocketAddr) -> bool {
+        self.relay_to(addr).await.is_some()
+    }
+
+    /// Sends a `Connect` to `addr` on a new bidirectional stream, returning the stream to relay through if the server accepted it
+    pub async fn relay_to(&self, addr: SocketAddr) -> Option<(SendStream, RecvStream)> {
+        let (mut send, mut recv) = self.conn.connection.open_bi().await.unwrap();
+
+        Command::new_connect(Address::SocketAddress(addr))
+            .write_to(&mut send)
+            .await
+            .unwrap();
+
+        match Command::read_from(&mut recv).await {
+            Ok(Command::Response(true)) => Some((send, recv)),
+            _ => None,
+        }
+    }
+
+    /// Waits for the server to close the connection, returning the error it was closed with
+    pub async fn closed(&mut self) -> ConnectionError {
+        let closed = async {
+            loop {
+                match self.conn.uni_streams.next().await {
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => break err,
+                    None => break ConnectionError::LocallyClosed,
+                }
+            }
+        };
+
+        time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("the connection is not closed")
+    }
+
+    /// The close code the server closed the connection with, if it was closed by the application
+    pub async fn close_code(&mut self) -> Option<VarInt> {
+        match self.closed().await {
+            ConnectionError::ApplicationClosed(close) => Some(close.error_code),
+            _ => None,
+        }
+    }
+}